@@ -0,0 +1,232 @@
+use std::{collections::HashSet, io::Write};
+
+use anyhow::{bail, Error, Result};
+
+use crate::format::{is_ptrmap_page, Cell, Page, PageType, SqliteFile, Table};
+
+/// Space used by one b-tree, the numbers sqlite3_analyzer reports
+#[derive(Debug, Default)]
+pub struct BTreeStats {
+    pub entries: u64,
+    pub leaf_pages: u64,
+    pub interior_pages: u64,
+    pub depth: u32,
+    /// Child pointers over all interior pages, for the average fanout
+    pub child_pointers: u64,
+    pub overflow_pages: u64,
+    pub payload_bytes: u64,
+    pub unused_bytes: u64,
+    /// Leaf pages in key order
+    leaves: Vec<u32>,
+}
+
+impl BTreeStats {
+    pub fn pages(&self) -> u64 {
+        self.leaf_pages + self.interior_pages + self.overflow_pages
+    }
+
+    pub fn average_fanout(&self) -> f64 {
+        match self.interior_pages {
+            0 => 0.0,
+            n => self.child_pointers as f64 / n as f64,
+        }
+    }
+
+    /// Percentage of leaves that don't directly follow the previous one in the file
+    pub fn fragmentation(&self) -> f64 {
+        if self.leaves.len() < 2 {
+            return 0.0;
+        }
+        let out_of_order = self
+            .leaves
+            .windows(2)
+            .filter(|it| it[1] != it[0] + 1)
+            .count();
+        out_of_order as f64 * 100.0 / (self.leaves.len() - 1) as f64
+    }
+}
+
+pub fn btree_stats(db: &mut SqliteFile, root_page: u32) -> Result<BTreeStats> {
+    let mut stats = BTreeStats::default();
+    let mut visited = HashSet::new();
+    walk(db, root_page, 1, &mut visited, &mut stats)?;
+    Ok(stats)
+}
+
+fn walk(
+    db: &mut SqliteFile,
+    page_number: u32,
+    depth: u32,
+    visited: &mut HashSet<u32>,
+    stats: &mut BTreeStats,
+) -> Result<()> {
+    if !visited.insert(page_number) {
+        bail!("page {} is referenced more than once", page_number);
+    }
+
+    let buf = db.read_raw_page(page_number as u64)?;
+    let page = db.parse_page(page_number as u64, &buf)?;
+    let padding = if page_number == 1 { 100 } else { 0 };
+    let usable_size = db.header.usable_size();
+
+    let freeblocks = Page::parse_freeblocks(&buf, &page.header).map_err(Error::msg)?;
+    let content_start = match page.header.first_cell_content {
+        0 => 65536,
+        n => n as usize,
+    };
+    let pointer_array_end = padding + page.header.size() + page.cells.len() * 2;
+    stats.unused_bytes += content_start.saturating_sub(pointer_array_end) as u64
+        + freeblocks.iter().map(|it| it.size as u64).sum::<u64>()
+        + page.header.fragmented_free_bytes as u64;
+
+    for cell in &page.cells {
+        let size = match cell {
+            Cell::LeafTable { size, .. }
+            | Cell::LeafIndex { size, .. }
+            | Cell::InteriorIndex { size, .. } => size.value as u64,
+            Cell::InteriorTable { .. } => continue,
+        };
+        stats.entries += 1;
+        stats.payload_bytes += size;
+
+        let local = Cell::local_payload_size(size as usize, usable_size, &page.header.kind) as u64;
+        let overflow_bytes = size - local;
+        if overflow_bytes > 0 {
+            let per_page = usable_size as u64 - 4;
+            let pages = overflow_bytes.div_ceil(per_page);
+            stats.overflow_pages += pages;
+            stats.unused_bytes += pages * per_page - overflow_bytes;
+        }
+    }
+
+    match page.header.kind {
+        PageType::LeafTable | PageType::LeafIndex => {
+            stats.leaf_pages += 1;
+            stats.depth = stats.depth.max(depth);
+            stats.leaves.push(page_number);
+        }
+        PageType::InteriorTable | PageType::InteriorIndex => {
+            stats.interior_pages += 1;
+            stats.child_pointers += page.cells.len() as u64 + 1;
+            for cell in &page.cells {
+                if let Some(left_child) = cell.left_child() {
+                    walk(db, left_child, depth + 1, visited, stats)?;
+                }
+            }
+            if let Some(right_most) = page.header.page_number {
+                walk(db, right_most, depth + 1, visited, stats)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Per b-tree space report, to see which tables bloat the file
+pub fn analyze(db: &mut SqliteFile, out: &mut impl Write) -> Result<()> {
+    let page_count = db.page_count()?;
+    let freelist = db.freelist()?;
+    let freelist_pages = freelist.len() as u64;
+
+    // sqlite_schema itself isn't listed in the schema
+    let mut objects = vec![Table {
+        kind: "table".to_string(),
+        name: "sqlite_schema".to_string(),
+        table_name: "sqlite_schema".to_string(),
+        root_page: 1,
+        sql: String::new(),
+    }];
+    objects.extend(db.tables.iter().filter(|it| it.root_page > 0).cloned());
+
+    let mut reports = vec![];
+    for object in objects {
+        let stats = btree_stats(db, object.root_page as u32)?;
+        reports.push((object, stats));
+    }
+    let used: u64 = reports.iter().map(|(_, stats)| stats.pages()).sum();
+    let percent = |pages: u64| pages as f64 * 100.0 / page_count.max(1) as f64;
+
+    writeln!(out, "/** Disk-Space Utilization Report */")?;
+    writeln!(out)?;
+    line(out, "Page size in bytes", db.header.page_size.to_string())?;
+    line(out, "Pages in the whole file", page_count.to_string())?;
+    line(
+        out,
+        "Pages in use by b-trees",
+        format!("{} {:.1}%", used, percent(used)),
+    )?;
+    line(
+        out,
+        "Pages on the freelist",
+        format!("{} {:.1}%", freelist_pages, percent(freelist_pages)),
+    )?;
+    line(
+        out,
+        "Freelist trunk pages",
+        freelist.trunks.len().to_string(),
+    )?;
+    line(
+        out,
+        "Freelist leaf pages",
+        freelist.leaves.len().to_string(),
+    )?;
+    let ptrmap_pages = match db.header.is_auto_vacuum() {
+        true => (2..=page_count as u32)
+            .filter(|it| is_ptrmap_page(&db.header, *it))
+            .count() as u64,
+        false => 0,
+    };
+    line(
+        out,
+        "Pages of auto-vacuum overhead",
+        format!("{} {:.1}%", ptrmap_pages, percent(ptrmap_pages)),
+    )?;
+    let other = page_count.saturating_sub(used + freelist_pages + ptrmap_pages);
+    line(
+        out,
+        "Pages used for other things",
+        format!("{} {:.1}%", other, percent(other)),
+    )?;
+
+    for (object, stats) in reports {
+        writeln!(out)?;
+        writeln!(out, "*** {} {} ***", object.kind, object.name)?;
+        writeln!(out)?;
+        line(out, "Number of entries", stats.entries.to_string())?;
+        line(
+            out,
+            "Total pages used",
+            format!("{} {:.1}%", stats.pages(), percent(stats.pages())),
+        )?;
+        line(out, "B-tree depth", stats.depth.to_string())?;
+        line(out, "Leaf pages", stats.leaf_pages.to_string())?;
+        line(out, "Interior pages", stats.interior_pages.to_string())?;
+        line(
+            out,
+            "Average fanout",
+            format!("{:.1}", stats.average_fanout()),
+        )?;
+        line(out, "Overflow pages", stats.overflow_pages.to_string())?;
+        line(out, "Bytes of payload", stats.payload_bytes.to_string())?;
+        let total_bytes = stats.pages() * db.header.page_size as u64;
+        line(
+            out,
+            "Unused bytes",
+            format!(
+                "{} {:.1}%",
+                stats.unused_bytes,
+                stats.unused_bytes as f64 * 100.0 / total_bytes.max(1) as f64
+            ),
+        )?;
+        line(
+            out,
+            "Fragmentation",
+            format!("{:.1}%", stats.fragmentation()),
+        )?;
+    }
+    Ok(())
+}
+
+fn line(out: &mut impl Write, label: &str, value: String) -> Result<()> {
+    writeln!(out, "{:.<40} {}", label, value)?;
+    Ok(())
+}
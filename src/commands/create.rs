@@ -0,0 +1,256 @@
+use anyhow::{anyhow, bail, Error, Result};
+
+use super::TableWriter;
+use crate::{
+    format::{PageType, RecordSerial, SqliteFile, Table},
+    utils::create_btree,
+};
+
+/// Adds a table with an empty b-tree, one for each of its automatic indexes,
+/// and sqlite_sequence on the first AUTOINCREMENT. `sql` goes to sqlite_schema as is.
+pub fn create_table(
+    db: &mut SqliteFile,
+    table_name: &str,
+    sql: &str,
+    if_not_exists: bool,
+) -> Result<usize> {
+    if !check_name(db, "table", table_name, if_not_exists)? {
+        return Ok(0);
+    }
+    let table = Table {
+        kind: "table".to_string(),
+        name: table_name.to_string(),
+        table_name: table_name.to_string(),
+        root_page: 0,
+        sql: sql.to_string(),
+    };
+    let definition = table.definition().map_err(Error::msg)?;
+    if definition.columns.is_empty() {
+        bail!("table {} has no columns", table_name);
+    }
+
+    // WITHOUT ROWID tables are stored like an index on their primary key
+    let kind = if definition.without_rowid {
+        PageType::LeafIndex
+    } else {
+        PageType::LeafTable
+    };
+    let root_page = create_btree(db, kind)?;
+    add_to_schema(db, "table", table_name, table_name, root_page, Some(sql))?;
+    for n in 1..=definition.autoindexes.len() {
+        let root_page = create_btree(db, PageType::LeafIndex)?;
+        let name = format!("sqlite_autoindex_{table_name}_{n}");
+        add_to_schema(db, "index", &name, table_name, root_page, None)?;
+    }
+    let has_sequence = db.tables.iter().any(|it| it.name == "sqlite_sequence");
+    if definition.autoincrement && !has_sequence {
+        let root_page = create_btree(db, PageType::LeafTable)?;
+        let sql = "CREATE TABLE sqlite_sequence(name,seq)";
+        add_to_schema(
+            db,
+            "table",
+            "sqlite_sequence",
+            "sqlite_sequence",
+            root_page,
+            Some(sql),
+        )?;
+    }
+    db.schema_changed()?;
+    Ok(1)
+}
+
+/// Adds an index and fills it from the rows already in the table
+pub fn create_index(
+    db: &mut SqliteFile,
+    index_name: &str,
+    table_name: &str,
+    sql: &str,
+    if_not_exists: bool,
+) -> Result<usize> {
+    if !check_name(db, "index", index_name, if_not_exists)? {
+        return Ok(0);
+    }
+    let table = TableWriter::open(db, table_name)?;
+    let index = Table {
+        kind: "index".to_string(),
+        name: index_name.to_string(),
+        table_name: table.table.name.clone(),
+        root_page: 0,
+        sql: sql.to_string(),
+    };
+    let definition = index.index_definition().map_err(Error::msg)?;
+    for column in &definition.columns {
+        let name = column
+            .column
+            .as_ref()
+            .ok_or_else(|| anyhow!("indexes on expressions are not supported"))?;
+        if table.definition.column_index(name).is_none() {
+            bail!("no such column: {}", name);
+        }
+    }
+
+    let root_page = create_btree(db, PageType::LeafIndex)?;
+    add_to_schema(
+        db,
+        "index",
+        index_name,
+        &index.table_name,
+        root_page,
+        Some(sql),
+    )?;
+    db.schema_changed()?;
+    TableWriter::open(db, table_name)?.build_index(db, index_name)?;
+    Ok(1)
+}
+
+/// Whether the name is free. With IF NOT EXISTS an object of the same kind
+/// is fine and nothing gets created
+fn check_name(db: &SqliteFile, kind: &str, name: &str, if_not_exists: bool) -> Result<bool> {
+    if name.len() >= 7 && name[..7].eq_ignore_ascii_case("sqlite_") {
+        bail!("object name reserved for internal use: {}", name);
+    }
+    match db
+        .tables
+        .iter()
+        .find(|it| it.name.eq_ignore_ascii_case(name))
+    {
+        Some(existing) if existing.kind == kind && if_not_exists => Ok(false),
+        Some(existing) if existing.kind == kind => bail!("{} {} already exists", kind, name),
+        Some(existing) => bail!(
+            "there is already {} named {}",
+            article(&existing.kind),
+            name
+        ),
+        None => Ok(true),
+    }
+}
+
+fn article(kind: &str) -> String {
+    match kind {
+        "index" => "an index".to_string(),
+        _ => format!("a {kind}"),
+    }
+}
+
+/// A row of sqlite_schema, automatic indexes have no `sql`
+pub fn add_to_schema(
+    db: &mut SqliteFile,
+    kind: &str,
+    name: &str,
+    table_name: &str,
+    root_page: u32,
+    sql: Option<&str>,
+) -> Result<()> {
+    let values = vec![
+        RecordSerial::String(kind.to_string()),
+        RecordSerial::String(name.to_string()),
+        RecordSerial::String(table_name.to_string()),
+        RecordSerial::I64(root_page as i64),
+        sql.map_or(RecordSerial::Null, |it| {
+            RecordSerial::String(it.to_string())
+        }),
+    ];
+    TableWriter::schema()?.insert_row(db, None, values)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        format::{CreateOptions, MemoryVfs, SqliteFile},
+        testing::{create, execute, integrity_check, query_one},
+    };
+
+    fn database() -> SqliteFile {
+        let mut db = create(&MemoryVfs::default(), &CreateOptions::default());
+        execute(&mut db, "CREATE TABLE t(id INTEGER PRIMARY KEY, a TEXT)").unwrap();
+        for id in 1..=100 {
+            let sql = format!("INSERT INTO t VALUES ({id}, 'a{}')", id % 10);
+            execute(&mut db, &sql).unwrap();
+        }
+        db
+    }
+
+    fn names(db: &SqliteFile) -> Vec<(String, String)> {
+        db.tables
+            .iter()
+            .map(|it| (it.name.clone(), it.sql.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn indexes_are_built_from_the_rows_already_there() {
+        let mut db = database();
+        execute(&mut db, "CREATE INDEX t_a ON t(a)").unwrap();
+        assert_eq!(
+            query_one(&mut db, "SELECT COUNT(*) FROM t WHERE a = 'a3'"),
+            "10"
+        );
+        assert_eq!(integrity_check(&mut db), "ok");
+
+        execute(&mut db, "CREATE UNIQUE INDEX t_id ON t(id, a)").unwrap();
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    #[test]
+    fn unique_indexes_fail_on_duplicate_rows() {
+        let mut db = database();
+        let error = execute(&mut db, "CREATE UNIQUE INDEX t_a ON t(a)").unwrap_err();
+        assert_eq!(error.to_string(), "UNIQUE constraint failed: t.a");
+        assert!(db.tables.iter().all(|it| it.name != "t_a"));
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    #[test]
+    fn unique_and_primary_keys_get_automatic_indexes() {
+        let mut db = create(&MemoryVfs::default(), &CreateOptions::default());
+        execute(&mut db, "CREATE TABLE u(a UNIQUE, b TEXT PRIMARY KEY)").unwrap();
+        let expected = [
+            ("u", "CREATE TABLE u(a UNIQUE, b TEXT PRIMARY KEY)"),
+            ("sqlite_autoindex_u_1", ""),
+            ("sqlite_autoindex_u_2", ""),
+        ];
+        assert_eq!(names(&db), expected.map(|(a, b)| (a.into(), b.into())));
+
+        execute(&mut db, "INSERT INTO u VALUES (1, 'x')").unwrap();
+        let error = execute(&mut db, "INSERT INTO u VALUES (1, 'y')").unwrap_err();
+        assert_eq!(error.to_string(), "UNIQUE constraint failed: u.a");
+        let error = execute(&mut db, "INSERT INTO u VALUES (2, 'x')").unwrap_err();
+        assert_eq!(error.to_string(), "UNIQUE constraint failed: u.b");
+        let error = execute(&mut db, "DROP INDEX sqlite_autoindex_u_1").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped"
+        );
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    #[test]
+    fn the_first_autoincrement_table_creates_sqlite_sequence() {
+        let mut db = database();
+        assert!(db.tables.iter().all(|it| it.name != "sqlite_sequence"));
+
+        execute(
+            &mut db,
+            "CREATE TABLE s(id INTEGER PRIMARY KEY AUTOINCREMENT, a)",
+        )
+        .unwrap();
+        execute(
+            &mut db,
+            "CREATE TABLE r(id INTEGER PRIMARY KEY AUTOINCREMENT, a)",
+        )
+        .unwrap();
+        let sequences = db.tables.iter().filter(|it| it.name == "sqlite_sequence");
+        assert_eq!(sequences.count(), 1);
+
+        execute(&mut db, "INSERT INTO s (a) VALUES (1)").unwrap();
+        execute(&mut db, "INSERT INTO s (a) VALUES (2)").unwrap();
+        execute(&mut db, "INSERT INTO r (a) VALUES (3)").unwrap();
+        let rows = execute(&mut db, "SELECT name, seq FROM sqlite_sequence").unwrap();
+        let rows = rows
+            .iter()
+            .map(|row| format!("{}|{}", row[0], row[1]))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, ["s|2", "r|1"]);
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+}
@@ -0,0 +1,55 @@
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::format::SqliteFile;
+
+/// Header fields and schema counts, laid out like sqlite3's `.dbinfo`
+pub fn dbinfo(db: &SqliteFile, out: &mut impl Write) -> Result<()> {
+    let header = &db.header;
+    let count = |kind: &str| db.tables.iter().filter(|it| it.kind == kind).count();
+    let schema_size: usize = db.tables.iter().map(|it| it.sql.len()).sum();
+
+    writeln!(out, "database page size:  {}", header.page_size)?;
+    writeln!(out, "write format:        {}", header.write_version)?;
+    writeln!(out, "read format:         {}", header.read_version)?;
+    writeln!(out, "reserved bytes:      {}", header.page_reserved_bytes)?;
+    writeln!(out, "file change counter: {}", header.file_change_counter)?;
+    writeln!(out, "database page count: {}", db.page_count()?)?;
+    writeln!(out, "freelist page count: {}", header.free_list_count)?;
+    writeln!(out, "schema cookie:       {}", header.schema_cookie)?;
+    writeln!(out, "schema format:       {}", header.schema_format_number)?;
+    writeln!(
+        out,
+        "default cache size:  {}",
+        header.default_page_cache_size
+    )?;
+    writeln!(
+        out,
+        "autovacuum top root: {}",
+        header.largest_root_btree_page
+    )?;
+    writeln!(
+        out,
+        "incremental vacuum:  {}",
+        header.incremental_vacuum_mode as u32
+    )?;
+    writeln!(
+        out,
+        "text encoding:       {} ({})",
+        header.text_encoding as u32, header.text_encoding
+    )?;
+    writeln!(out, "user version:        {}", header.user_version)?;
+    writeln!(out, "application id:      {}", header.application_id)?;
+    writeln!(
+        out,
+        "software version:    {}",
+        header.sqlite_version.number()
+    )?;
+    writeln!(out, "number of tables:    {}", count("table"))?;
+    writeln!(out, "number of indexes:   {}", count("index"))?;
+    writeln!(out, "number of triggers:  {}", count("trigger"))?;
+    writeln!(out, "number of views:     {}", count("view"))?;
+    writeln!(out, "schema size:         {}", schema_size)?;
+    Ok(())
+}
@@ -0,0 +1,20 @@
+use anyhow::Result;
+
+use super::TableWriter;
+use crate::{expr::Expr, format::SqliteFile};
+
+/// Removes the rows matching `condition`, or all of them, with their index entries
+pub fn delete(db: &mut SqliteFile, table_name: &str, condition: Option<&Expr>) -> Result<usize> {
+    let writer = TableWriter::open(db, table_name)?;
+    let mut count = 0;
+    for row in writer.rows(db)? {
+        if let Some(condition) = condition {
+            if !condition.matches(&writer.definition, &row.1)? {
+                continue;
+            }
+        }
+        writer.delete_row(db, &row)?;
+        count += 1;
+    }
+    Ok(count)
+}
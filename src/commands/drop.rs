@@ -0,0 +1,192 @@
+use anyhow::{bail, Result};
+use itertools::Itertools;
+
+use super::TableWriter;
+use crate::{
+    format::{RecordSerial, SqliteFile},
+    utils::free_btree,
+};
+
+/// Removes a table with its indexes and triggers, their pages go to the freelist
+pub fn drop_table(db: &mut SqliteFile, table_name: &str, if_exists: bool) -> Result<usize> {
+    let Some(table) = db
+        .tables
+        .iter()
+        .find(|it| it.kind == "table" && it.name.eq_ignore_ascii_case(table_name))
+        .cloned()
+    else {
+        if if_exists {
+            return Ok(0);
+        }
+        bail!("no such table: {}", table_name);
+    };
+    if table.name.len() >= 7 && table.name[..7].eq_ignore_ascii_case("sqlite_") {
+        bail!("table {} may not be dropped", table.name);
+    }
+
+    let objects = db
+        .tables
+        .iter()
+        .filter(|it| it.table_name.eq_ignore_ascii_case(&table.name))
+        .cloned()
+        .collect::<Vec<_>>();
+    remove_from_schema(db, |_, _, table_name| {
+        table_name.eq_ignore_ascii_case(&table.name)
+    })?;
+    // From the last root down, as auto-vacuum moves later roots into freed ones
+    let roots = objects
+        .iter()
+        .filter(|it| it.root_page > 0)
+        .map(|it| it.root_page as u32)
+        .sorted()
+        .rev();
+    for root_page in roots {
+        destroy_root(db, root_page)?;
+    }
+
+    // Like SQLite, AUTOINCREMENT starts over if the table comes back
+    if db.tables.iter().any(|it| it.name == "sqlite_sequence") {
+        let sequence = TableWriter::open(db, "sqlite_sequence")?;
+        for row in sequence.rows(db)? {
+            if matches!(&row.1[0], RecordSerial::String(it) if *it == table.name) {
+                sequence.delete_row(db, &row)?;
+            }
+        }
+    }
+    db.schema_changed()?;
+    Ok(1)
+}
+
+/// Removes an index created with CREATE INDEX, its pages go to the freelist
+pub fn drop_index(db: &mut SqliteFile, index_name: &str, if_exists: bool) -> Result<usize> {
+    let Some(index) = db
+        .tables
+        .iter()
+        .find(|it| it.kind == "index" && it.name.eq_ignore_ascii_case(index_name))
+        .cloned()
+    else {
+        if if_exists {
+            return Ok(0);
+        }
+        bail!("no such index: {}", index_name);
+    };
+    if index.sql.is_empty() {
+        bail!("index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped");
+    }
+
+    remove_from_schema(db, |kind, name, _| kind == "index" && name == index.name)?;
+    destroy_root(db, index.root_page as u32)?;
+    db.schema_changed()?;
+    Ok(1)
+}
+
+/// Frees a b-tree whose schema row is gone, and points the schema row of the
+/// root auto-vacuum moved into its place there
+fn destroy_root(db: &mut SqliteFile, root_page: u32) -> Result<()> {
+    let Some(moved) = free_btree(db, root_page)? else {
+        return Ok(());
+    };
+    let schema = TableWriter::schema()?;
+    for row in schema.rows(db)? {
+        if row.1[3].as_i64() == Some(moved as i64) {
+            schema.delete_row(db, &row)?;
+            let mut values = row.1.clone();
+            values[3] = RecordSerial::I64(root_page as i64);
+            schema.insert_row(db, row.0, values)?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the sqlite_schema rows for which `filter(type, name, tbl_name)` is true
+fn remove_from_schema(
+    db: &mut SqliteFile,
+    filter: impl Fn(&str, &str, &str) -> bool,
+) -> Result<()> {
+    let schema = TableWriter::schema()?;
+    for row in schema.rows(db)? {
+        let text = |i: usize| match &row.1[i] {
+            RecordSerial::String(it) => it.as_str(),
+            _ => "",
+        };
+        if filter(text(0), text(1), text(2)) {
+            schema.delete_row(db, &row)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        format::{CreateOptions, MemoryVfs, SqliteFile},
+        testing::{create, execute, integrity_check, query_one},
+    };
+
+    /// 200 rows in 1024 byte pages, with an index
+    fn database() -> SqliteFile {
+        let options = CreateOptions {
+            page_size: 1024,
+            ..CreateOptions::default()
+        };
+        let mut db = create(&MemoryVfs::default(), &options);
+        execute(
+            &mut db,
+            "CREATE TABLE t(id INTEGER PRIMARY KEY AUTOINCREMENT, a TEXT)",
+        )
+        .unwrap();
+        execute(&mut db, "CREATE INDEX t_a ON t(a)").unwrap();
+        for id in 1..=200 {
+            let sql = format!("INSERT INTO t (a) VALUES ('{}')", "x".repeat(id));
+            execute(&mut db, &sql).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn dropped_pages_go_to_the_freelist() {
+        let mut db = database();
+        execute(&mut db, "CREATE TABLE kept(a)").unwrap();
+        let page_count = db.page_count().unwrap();
+
+        execute(&mut db, "DROP INDEX t_a").unwrap();
+        let index_pages = db.header.free_list_count;
+        assert!(index_pages > 1);
+        execute(&mut db, "DROP TABLE t").unwrap();
+        assert!(db.header.free_list_count > index_pages);
+
+        // Page 1, sqlite_sequence and kept are all that's left
+        assert_eq!(db.page_count().unwrap(), page_count);
+        assert_eq!(db.header.free_list_count as u64, page_count - 3);
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    #[test]
+    fn dropping_a_table_clears_its_sequence() {
+        let mut db = database();
+        execute(
+            &mut db,
+            "CREATE TABLE u(id INTEGER PRIMARY KEY AUTOINCREMENT, a)",
+        )
+        .unwrap();
+        execute(&mut db, "INSERT INTO u (a) VALUES (1)").unwrap();
+
+        execute(&mut db, "DROP TABLE t").unwrap();
+        assert_eq!(
+            query_one(&mut db, "SELECT COUNT(*) FROM sqlite_sequence"),
+            "1"
+        );
+        assert_eq!(query_one(&mut db, "SELECT name FROM sqlite_sequence"), "u");
+        assert!(db.tables.iter().all(|it| it.table_name != "t"));
+
+        // AUTOINCREMENT starts over
+        execute(
+            &mut db,
+            "CREATE TABLE t(id INTEGER PRIMARY KEY AUTOINCREMENT, a)",
+        )
+        .unwrap();
+        execute(&mut db, "INSERT INTO t (a) VALUES (1)").unwrap();
+        assert_eq!(query_one(&mut db, "SELECT id FROM t"), "1");
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+}
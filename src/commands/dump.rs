@@ -0,0 +1,208 @@
+use std::io::Write;
+
+use anyhow::{Error, Result};
+use itertools::Itertools;
+
+use crate::{
+    format::{quote_identifier, SqliteFile, Table},
+    utils::{scan_index, scan_table},
+};
+
+/// Writes the database (or only `table_name` and its indexes and triggers)
+/// as SQL text, in the same shape as sqlite3's `.dump`
+pub fn dump(db: &mut SqliteFile, table_name: Option<&str>, out: &mut impl Write) -> Result<()> {
+    let selected =
+        |it: &&Table| table_name.is_none_or(|name| it.table_name.eq_ignore_ascii_case(name));
+    // Borrowing db.tables while reading pages needs its own copy
+    let schema = db.tables.iter().filter(selected).cloned().collect_vec();
+
+    writeln!(out, "PRAGMA foreign_keys=OFF;")?;
+    writeln!(out, "BEGIN TRANSACTION;")?;
+
+    for table in schema.iter().filter(|it| it.kind == "table") {
+        if table.name.starts_with("sqlite_") {
+            continue;
+        }
+        writeln!(out, "{};", table.sql)?;
+        // Virtual tables have no b-tree of their own
+        if table.root_page == 0 {
+            continue;
+        }
+        dump_rows(db, table, out)?;
+    }
+
+    // Created on its own by the first AUTOINCREMENT table, sqlite3 restores
+    // it last through the writable schema
+    let sequence = schema.iter().find(|it| it.name == "sqlite_sequence");
+    if let Some(sequence) = sequence {
+        writeln!(out, "PRAGMA writable_schema=ON;")?;
+        let columns = sequence.sql.trim_start_matches("CREATE TABLE ");
+        writeln!(out, "CREATE TABLE IF NOT EXISTS {};", columns)?;
+        writeln!(out, "DELETE FROM sqlite_sequence;")?;
+        dump_rows(db, sequence, out)?;
+    }
+
+    for other in schema.iter().filter(|it| it.kind != "table") {
+        // Automatic indexes for UNIQUE and PRIMARY KEY have no sql
+        if !other.sql.is_empty() {
+            writeln!(out, "{};", other.sql)?;
+        }
+    }
+
+    if sequence.is_some() {
+        writeln!(out, "PRAGMA writable_schema=OFF;")?;
+    }
+    writeln!(out, "COMMIT;")?;
+    Ok(())
+}
+
+fn dump_rows(db: &mut SqliteFile, table: &Table, out: &mut impl Write) -> Result<()> {
+    let definition = table.definition().map_err(Error::msg)?;
+    let rows = if definition.without_rowid {
+        scan_index(db, table.root_page as u32)?
+            .into_iter()
+            .map(|record| definition.row_values(None, record))
+            .collect_vec()
+    } else {
+        scan_table(db, table.root_page as u32)?
+            .into_iter()
+            .map(|row| definition.row_values(Some(row.row_id), row.record))
+            .collect_vec()
+    };
+
+    let name = quote_identifier(&table.name);
+    for values in rows {
+        writeln!(
+            out,
+            "INSERT INTO {} VALUES({});",
+            name,
+            values.iter().map(|it| it.to_sql_literal()).join(",")
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::{CreateOptions, MemoryVfs, RecordSerial},
+        testing::{create, execute},
+    };
+
+    fn database(sql: &str) -> SqliteFile {
+        let mut db = create(&MemoryVfs::default(), &CreateOptions::default());
+        execute(&mut db, sql).unwrap();
+        db
+    }
+
+    fn dump_text(db: &mut SqliteFile) -> String {
+        let mut out = vec![];
+        dump(db, None, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Runs the dump again in a new database, leaving out what it can't parse
+    fn reload(text: &str) -> SqliteFile {
+        let statements = text
+            .lines()
+            .filter(|it| it.starts_with("CREATE") || it.starts_with("INSERT"))
+            .join("\n");
+        database(&statements)
+    }
+
+    #[test]
+    fn names_and_text_are_quoted() {
+        let mut db = database(
+            "CREATE TABLE \"order\"(a TEXT);\
+             INSERT INTO \"order\" VALUES ('it''s');\
+             INSERT INTO \"order\" VALUES (NULL)",
+        );
+        assert_eq!(
+            dump_text(&mut db),
+            "PRAGMA foreign_keys=OFF;\n\
+             BEGIN TRANSACTION;\n\
+             CREATE TABLE \"order\"(a TEXT);\n\
+             INSERT INTO \"order\" VALUES('it''s');\n\
+             INSERT INTO \"order\" VALUES(NULL);\n\
+             COMMIT;\n"
+        );
+    }
+
+    #[test]
+    fn blobs_and_reals_round_trip() {
+        let mut db = database(
+            "CREATE TABLE t(a);\
+             INSERT INTO t VALUES (X'');\
+             INSERT INTO t VALUES (X'00ff');\
+             INSERT INTO t VALUES (0.1);\
+             INSERT INTO t VALUES (3.0);\
+             INSERT INTO t VALUES (1.7976931348623157e308);\
+             INSERT INTO t VALUES (-2.5e-300)",
+        );
+        let text = dump_text(&mut db);
+        assert!(text.contains("INSERT INTO t VALUES(X'');\n"));
+        assert!(text.contains("INSERT INTO t VALUES(X'00ff');\n"));
+        assert!(text.contains("INSERT INTO t VALUES(3.0);\n"));
+
+        let before = execute(&mut db, "SELECT a FROM t").unwrap();
+        let after = execute(&mut reload(&text), "SELECT a FROM t").unwrap();
+        let bits = |rows: Vec<Vec<RecordSerial>>| {
+            rows.into_iter()
+                .map(|row| match &row[0] {
+                    RecordSerial::F64(f) => f.to_bits().to_string(),
+                    other => other.to_sql_literal(),
+                })
+                .collect_vec()
+        };
+        assert_eq!(bits(after), bits(before));
+    }
+
+    #[test]
+    fn without_rowid_tables_dump_their_rows_in_key_order() {
+        let mut db = database(
+            "CREATE TABLE w(k TEXT PRIMARY KEY, v) WITHOUT ROWID;\
+             INSERT INTO w VALUES ('b', 2);\
+             INSERT INTO w VALUES ('a', 1)",
+        );
+        let text = dump_text(&mut db);
+        assert_eq!(
+            text,
+            "PRAGMA foreign_keys=OFF;\n\
+             BEGIN TRANSACTION;\n\
+             CREATE TABLE w(k TEXT PRIMARY KEY, v) WITHOUT ROWID;\n\
+             INSERT INTO w VALUES('a',1);\n\
+             INSERT INTO w VALUES('b',2);\n\
+             COMMIT;\n"
+        );
+        assert_eq!(dump_text(&mut reload(&text)), text);
+    }
+
+    #[test]
+    fn sqlite_sequence_is_restored_after_the_tables() {
+        let mut db = database(
+            "CREATE TABLE t(id INTEGER PRIMARY KEY AUTOINCREMENT, a);\
+             CREATE TABLE z(b);\
+             INSERT INTO t (a) VALUES ('x');\
+             INSERT INTO z VALUES (1);\
+             CREATE INDEX i ON t(a)",
+        );
+        // The same as sqlite3 prints
+        assert_eq!(
+            dump_text(&mut db),
+            "PRAGMA foreign_keys=OFF;\n\
+             BEGIN TRANSACTION;\n\
+             CREATE TABLE t(id INTEGER PRIMARY KEY AUTOINCREMENT, a);\n\
+             INSERT INTO t VALUES(1,'x');\n\
+             CREATE TABLE z(b);\n\
+             INSERT INTO z VALUES(1);\n\
+             PRAGMA writable_schema=ON;\n\
+             CREATE TABLE IF NOT EXISTS sqlite_sequence(name,seq);\n\
+             DELETE FROM sqlite_sequence;\n\
+             INSERT INTO sqlite_sequence VALUES('t',1);\n\
+             CREATE INDEX i ON t(a);\n\
+             PRAGMA writable_schema=OFF;\n\
+             COMMIT;\n"
+        );
+    }
+}
@@ -0,0 +1,20 @@
+use std::io::Write;
+
+use anyhow::Result;
+use itertools::Itertools;
+
+use crate::format::SqliteFile;
+
+/// Lists the free pages, trunk by trunk
+pub fn freelist(db: &mut SqliteFile, out: &mut impl Write) -> Result<()> {
+    let freelist = db.freelist()?;
+    writeln!(out, "free pages:   {}", freelist.len())?;
+    writeln!(out, "header count: {}", db.header.free_list_count)?;
+    writeln!(out, "trunk pages:  {}", freelist.trunks.iter().join(" "))?;
+    writeln!(
+        out,
+        "leaf pages:   {}",
+        freelist.leaves.iter().sorted().join(" ")
+    )?;
+    Ok(())
+}
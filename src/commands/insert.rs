@@ -0,0 +1,52 @@
+use anyhow::{anyhow, bail, Result};
+use itertools::Itertools;
+
+use super::TableWriter;
+use crate::format::{RecordSerial, SqliteFile};
+
+/// Adds rows to a table and to every index on it, `column_names` empty means
+/// all columns in order. Pages stay dirty until the caller commits.
+pub fn insert(
+    db: &mut SqliteFile,
+    table_name: &str,
+    column_names: &[String],
+    rows: Vec<Vec<RecordSerial>>,
+) -> Result<usize> {
+    let writer = TableWriter::open(db, table_name)?;
+    let (table, definition) = (&writer.table, &writer.definition);
+
+    let positions: Vec<usize> = if column_names.is_empty() {
+        (0..definition.columns.len()).collect()
+    } else {
+        column_names
+            .iter()
+            .map(|name| {
+                definition
+                    .column_index(name)
+                    .ok_or_else(|| anyhow!("table {} has no column named {}", table.name, name))
+            })
+            .try_collect()?
+    };
+
+    let count = rows.len();
+    for row in rows {
+        if row.len() != positions.len() {
+            if column_names.is_empty() {
+                bail!(
+                    "table {} has {} columns but {} values were supplied",
+                    table.name,
+                    positions.len(),
+                    row.len()
+                );
+            }
+            bail!("{} values for {} columns", row.len(), positions.len());
+        }
+        let mut values: Vec<Option<RecordSerial>> = vec![None; definition.columns.len()];
+        for (i, value) in positions.iter().zip(row) {
+            values[*i] = Some(value);
+        }
+        let values = writer.prepare(values)?;
+        writer.insert_row(db, None, values)?;
+    }
+    Ok(count)
+}
@@ -0,0 +1,711 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use itertools::Itertools;
+
+use crate::{
+    format::{
+        is_ptrmap_page, u32_at, Cell, DatabaseHeader, IndexDefinition, Page, PageHeader, PageType,
+        PtrmapEntry, PtrmapType, Record, RecordSerial, SqliteFile, Table,
+    },
+    utils::Varint,
+};
+
+/// Walks every b-tree and the freelist and reports problems with the same
+/// wording as `PRAGMA integrity_check`. An empty list means the file is fine.
+/// `quick` skips comparing indexes against their tables, like `quick_check`.
+pub fn integrity_check(db: &mut SqliteFile, quick: bool, max_errors: usize) -> Result<Vec<String>> {
+    let page_count = db.page_count()? as u32;
+    let mut check = IntegrityCheck {
+        header: db.header.clone(),
+        db,
+        quick,
+        max_errors,
+        errors: vec![],
+        references: vec![false; page_count as usize + 1],
+        rows: HashMap::new(),
+    };
+
+    check.check_freelist();
+    check.mark_reserved_pages();
+
+    let mut trees = vec![(1, true)];
+    for table in check.db.tables.iter().filter(|it| it.root_page > 0) {
+        let is_table = table.kind == "table" && !is_without_rowid(table);
+        trees.push((table.root_page as u32, is_table));
+    }
+    for (root_page, is_table) in trees {
+        if check.is_full() {
+            break;
+        }
+        let context = format!("Tree {}: ", root_page);
+        let entry = PtrmapEntry::new(PtrmapType::RootPage, 0);
+        check.check_ptrmap(&context, root_page, entry);
+        let mut tree = Tree {
+            root: root_page,
+            is_table,
+            last_row_id: None,
+            entries: vec![],
+        };
+        check.check_page(&mut tree, root_page, None, None);
+        if !quick {
+            check.rows.insert(root_page, tree.entries);
+        }
+    }
+
+    for page_number in 1..=page_count {
+        if !check.references[page_number as usize] {
+            check.error(format!("Page {}: never used", page_number));
+        }
+    }
+
+    if !quick {
+        let tables = check.db.tables.clone();
+        for index in tables
+            .iter()
+            .filter(|it| it.kind == "index" && it.root_page > 0)
+        {
+            if check.is_full() {
+                break;
+            }
+            check.check_index(index, &tables);
+        }
+    }
+
+    check.errors.truncate(max_errors);
+    Ok(check.errors)
+}
+
+fn is_without_rowid(table: &Table) -> bool {
+    table.kind == "table" && table.definition().is_ok_and(|it| it.without_rowid)
+}
+
+struct IntegrityCheck<'a> {
+    db: &'a mut SqliteFile,
+    header: DatabaseHeader,
+    quick: bool,
+    max_errors: usize,
+    errors: Vec<String>,
+    references: Vec<bool>,
+    /// Entries of every b-tree by root page, to compare indexes with their tables
+    rows: HashMap<u32, Vec<(Option<i64>, Record)>>,
+}
+
+/// State carried through one b-tree
+struct Tree {
+    root: u32,
+    is_table: bool,
+    last_row_id: Option<i64>,
+    entries: Vec<(Option<i64>, Record)>,
+}
+
+impl IntegrityCheck<'_> {
+    fn error(&mut self, message: String) {
+        if !self.is_full() {
+            self.errors.push(message);
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.errors.len() >= self.max_errors
+    }
+
+    /// Records a use of the page, false if it can't or shouldn't be read
+    fn mark(&mut self, page_number: u32, context: &str) -> bool {
+        match self.references.get_mut(page_number as usize) {
+            Some(seen) if page_number > 0 && !*seen => {
+                *seen = true;
+                true
+            }
+            Some(_) if page_number > 0 => {
+                self.error(format!("{}2nd reference to page {}", context, page_number));
+                false
+            }
+            _ => {
+                self.error(format!("{}invalid page number {}", context, page_number));
+                false
+            }
+        }
+    }
+
+    fn check_freelist(&mut self) {
+        let expected = self.header.free_list_count;
+        let freelist = match self.db.freelist() {
+            Ok(freelist) => freelist,
+            Err(e) => {
+                self.error(format!("Freelist: {}", e));
+                return;
+            }
+        };
+        for page_number in freelist.trunks.iter().chain(freelist.leaves.iter()) {
+            if self.mark(*page_number, "Freelist: ") {
+                let entry = PtrmapEntry::new(PtrmapType::FreePage, 0);
+                self.check_ptrmap("Freelist: ", *page_number, entry);
+            }
+        }
+        if freelist.len() as u32 != expected {
+            self.error(format!(
+                "Freelist: size is {} but should be {}",
+                freelist.len(),
+                expected
+            ));
+        }
+    }
+
+    /// Pages that belong to no b-tree on purpose
+    fn mark_reserved_pages(&mut self) {
+        let page_count = self.references.len() as u32 - 1;
+
+        // The page holding the lock bytes at 1GB is never used
+        let pending_byte_page = self.header.pending_byte_page();
+        if pending_byte_page <= page_count {
+            self.references[pending_byte_page as usize] = true;
+        }
+
+        // Auto-vacuum files have pointer map pages from page 2 on
+        if self.header.is_auto_vacuum() {
+            for page_number in 2..=page_count {
+                if is_ptrmap_page(&self.header, page_number) {
+                    self.mark(page_number, "Pointer map: ");
+                }
+            }
+        }
+    }
+
+    /// In auto-vacuum files, the pointer map must name what points to a page
+    fn check_ptrmap(&mut self, context: &str, page_number: u32, expected: PtrmapEntry) {
+        if !self.header.is_auto_vacuum()
+            || page_number < 3
+            || page_number as usize >= self.references.len()
+        {
+            return;
+        }
+        let (kind, parent) = match self.db.ptrmap_entry(page_number) {
+            Ok(Some(entry)) if entry == expected => return,
+            Ok(entry) => entry.map_or((0, 0), |it| (it.kind as u8, it.parent)),
+            Err(_) => {
+                self.error(format!(
+                    "{}Failed to read ptrmap key={}",
+                    context, page_number
+                ));
+                return;
+            }
+        };
+        self.error(format!(
+            "{}Bad ptr map entry key={} expected=({},{}) got=({},{})",
+            context, page_number, expected.kind as u8, expected.parent, kind, parent
+        ));
+    }
+
+    /// Checks a page and its children, returns the depth of its leaves.
+    /// Rowids below it must be in `(lower, upper]`.
+    fn check_page(
+        &mut self,
+        tree: &mut Tree,
+        page_number: u32,
+        lower: Option<i64>,
+        upper: Option<i64>,
+    ) -> Option<u32> {
+        let context = format!("Tree {} page {}: ", tree.root, page_number);
+        if self.is_full() || !self.mark(page_number, &context) {
+            return None;
+        }
+        let buf = match self.db.read_raw_page(page_number as u64) {
+            Ok(buf) => buf,
+            Err(e) => {
+                self.error(format!("{}{}", context, e));
+                return None;
+            }
+        };
+        let padding = if page_number == 1 { 100 } else { 0 };
+        let header = match PageHeader::from_bytes(&buf[padding..]) {
+            Ok(header) => header,
+            Err(e) => {
+                self.error(format!("{}{}", context, e));
+                return None;
+            }
+        };
+        let is_table_page = matches!(header.kind, PageType::LeafTable | PageType::InteriorTable);
+        if is_table_page != tree.is_table {
+            self.error(format!("{}wrong page type {:?}", context, header.kind));
+            return None;
+        }
+        let pointers = match Page::parse_cell_pointer_array(&buf, &header, padding) {
+            Ok(pointers) => pointers,
+            Err(e) => {
+                self.error(format!("{}{}", context, e));
+                return None;
+            }
+        };
+
+        let cells = self.check_layout(tree.root, page_number, &buf, &header, &pointers);
+
+        let mut depth = None;
+        let mut lower_key = lower;
+
+        for (i, cell) in cells.into_iter().enumerate() {
+            let Some(cell) = cell else { continue };
+            let cell_context = format!("Tree {} page {} cell {}: ", tree.root, page_number, i);
+            match cell {
+                Cell::InteriorTable { left_child, key } => {
+                    self.check_key(&cell_context, key.value, lower_key, upper);
+                    self.check_child(
+                        tree,
+                        page_number,
+                        left_child,
+                        lower_key,
+                        Some(key.value),
+                        &mut depth,
+                    );
+                    lower_key = Some(key.value);
+                }
+                Cell::InteriorIndex {
+                    left_child,
+                    payload,
+                    ..
+                } => {
+                    self.check_child(tree, page_number, left_child, None, None, &mut depth);
+                    tree.entries.push((None, payload));
+                }
+                Cell::LeafTable {
+                    row_id, payload, ..
+                } => {
+                    self.check_key(&cell_context, row_id.value, lower, upper);
+                    if tree.last_row_id.is_some_and(|last| row_id.value <= last) {
+                        self.error(format!(
+                            "{}Rowid {} out of order",
+                            cell_context, row_id.value
+                        ));
+                    }
+                    tree.last_row_id = Some(row_id.value);
+                    if !self.quick {
+                        tree.entries.push((Some(row_id.value), payload));
+                    }
+                }
+                Cell::LeafIndex { payload, .. } => tree.entries.push((None, payload)),
+            }
+        }
+
+        match header.page_number {
+            Some(right_most) => {
+                self.check_child(tree, page_number, right_most, lower_key, upper, &mut depth);
+                depth.map(|it| it + 1)
+            }
+            None => Some(1),
+        }
+    }
+
+    /// Every child of a page must have its leaves at the same depth
+    fn check_child(
+        &mut self,
+        tree: &mut Tree,
+        parent: u32,
+        child: u32,
+        lower: Option<i64>,
+        upper: Option<i64>,
+        depth: &mut Option<u32>,
+    ) {
+        let root = tree.root;
+        let context = format!("Tree {} page {}: ", root, parent);
+        self.check_ptrmap(&context, child, PtrmapEntry::new(PtrmapType::BTree, parent));
+        match (*depth, self.check_page(tree, child, lower, upper)) {
+            (Some(a), Some(b)) if a != b => {
+                self.error(format!(
+                    "Tree {} page {}: Child page depth differs",
+                    root, child
+                ));
+            }
+            (None, Some(b)) => *depth = Some(b),
+            _ => {}
+        }
+    }
+
+    fn check_key(&mut self, context: &str, key: i64, lower: Option<i64>, upper: Option<i64>) {
+        if lower.is_some_and(|lower| key <= lower) || upper.is_some_and(|upper| key > upper) {
+            self.error(format!("{}Rowid {} out of order", context, key));
+        }
+    }
+
+    /// Cells and freeblocks must fit in the page without overlapping, and the
+    /// bytes left over must match the fragmented byte count. Returns the cells
+    /// that could be decoded, with their overflow chains checked.
+    fn check_layout(
+        &mut self,
+        root: u32,
+        page_number: u32,
+        buf: &[u8],
+        header: &PageHeader,
+        pointers: &[u16],
+    ) -> Vec<Option<Cell>> {
+        let context = format!("Tree {} page {}: ", root, page_number);
+        let padding = if page_number == 1 { 100 } else { 0 };
+        let usable_size = self.header.usable_size();
+        let content_start = header.first_cell_content as usize;
+        let pointer_array_end = padding + header.size() + pointers.len() * 2;
+        let mut used = vec![false; usable_size];
+        let mut overlap = None;
+        let mut claim = |start: usize, end: usize, used: &mut Vec<bool>| {
+            for (byte, it) in used.iter_mut().enumerate().take(end).skip(start) {
+                if *it && overlap.is_none() {
+                    overlap = Some(byte);
+                }
+                *it = true;
+            }
+        };
+        claim(0, pointer_array_end.min(usable_size), &mut used);
+
+        let mut cells = vec![];
+        for (i, pointer) in pointers.iter().enumerate() {
+            let cell_context = format!("Tree {} page {} cell {}: ", root, page_number, i);
+            let position = *pointer as usize;
+            if position < content_start.max(pointer_array_end) || position > usable_size - 4 {
+                self.error(format!(
+                    "{}Offset {} out of range {}..{}",
+                    cell_context,
+                    position,
+                    content_start.max(pointer_array_end),
+                    usable_size - 4
+                ));
+                cells.push(None);
+                continue;
+            }
+            let size = match Cell::size_on_page(buf, position, &header.kind, usable_size) {
+                Ok(size) if position + size <= usable_size => size,
+                _ => {
+                    self.error(format!("{}Extends off end of page", cell_context));
+                    cells.push(None);
+                    continue;
+                }
+            };
+            claim(position, position + size, &mut used);
+
+            let cell = Cell::from_bytes(buf, position as u64, header, &self.header, &mut |_| {
+                Err("Overflow chain checked separately")
+            });
+            let cell = match cell {
+                Ok(cell) => Some(cell),
+                Err(_) => self.check_overflow(&cell_context, page_number, buf, position, header),
+            };
+            cells.push(cell);
+        }
+
+        match Page::parse_freeblocks(buf, header) {
+            Ok(freeblocks) => {
+                for freeblock in freeblocks {
+                    let start = freeblock.offset as usize;
+                    let end = start + freeblock.size as usize;
+                    if start < content_start || end > usable_size {
+                        self.error(format!(
+                            "{}Freeblock at {} extends off end of page",
+                            context, start
+                        ));
+                        continue;
+                    }
+                    claim(start, end, &mut used);
+                }
+            }
+            Err(e) => self.error(format!("{}{}", context, e)),
+        }
+
+        if let Some(byte) = overlap {
+            self.error(format!(
+                "{}Multiple uses for byte {} of page {}",
+                context, byte, page_number
+            ));
+        } else if content_start >= pointer_array_end {
+            let fragmented = used[content_start.min(usable_size)..]
+                .iter()
+                .filter(|it| !**it)
+                .count();
+            if fragmented != header.fragmented_free_bytes as usize {
+                self.error(format!(
+                    "{}Fragmentation of {} bytes reported as {} on page {}",
+                    context, fragmented, header.fragmented_free_bytes, page_number
+                ));
+            }
+        }
+        cells
+    }
+
+    /// Follows the overflow chain of a cell that spills, then decodes it
+    fn check_overflow(
+        &mut self,
+        context: &str,
+        page_number: u32,
+        buf: &[u8],
+        position: usize,
+        header: &PageHeader,
+    ) -> Option<Cell> {
+        let usable_size = self.header.usable_size();
+        let mut chain = vec![];
+        let cell = Cell::from_bytes(buf, position as u64, header, &self.header, &mut |n| {
+            chain.push(n);
+            Err("")
+        });
+        // The loader stopped at the first overflow page, count what's expected from there
+        let Some(&first) = chain.first() else {
+            if let Err(e) = cell {
+                self.error(format!("{}{}", context, e));
+            }
+            return None;
+        };
+        let size_position = match header.kind {
+            PageType::InteriorIndex => position + 4,
+            _ => position,
+        };
+        let payload_size = Varint::from_bytes(&buf[size_position..]).value as usize;
+        let overflow_bytes =
+            payload_size - Cell::local_payload_size(payload_size, usable_size, &header.kind);
+        let expected = overflow_bytes.div_ceil(usable_size - 4);
+
+        let mut next = first;
+        let mut found = 0;
+        let mut entry = PtrmapEntry::new(PtrmapType::Overflow1, page_number);
+        while next != 0 && found < expected {
+            if !self.mark(next, context) {
+                break;
+            }
+            self.check_ptrmap(context, next, entry);
+            entry = PtrmapEntry::new(PtrmapType::Overflow2, next);
+            found += 1;
+            match self.db.read_page_ref(next as u64) {
+                Ok(page) => next = u32_at(&page, 0),
+                Err(_) => break,
+            }
+        }
+        if found < expected {
+            self.error(format!(
+                "{}{} of {} pages missing from overflow list starting at {}",
+                context,
+                expected - found,
+                expected,
+                first
+            ));
+            return None;
+        }
+
+        let db = &mut *self.db;
+        let cell = Cell::from_bytes(buf, position as u64, header, &self.header, &mut |n| {
+            db.read_raw_page(n as u64)
+                .map_err(|_| "Failed to read overflow page")
+        });
+        match cell {
+            Ok(cell) => Some(cell),
+            Err(e) => {
+                self.error(format!("{}{}", context, e));
+                None
+            }
+        }
+    }
+
+    /// Every row must have exactly one matching index entry
+    fn check_index(&mut self, index: &Table, tables: &[Table]) {
+        let Some(table) = tables
+            .iter()
+            .find(|it| it.kind == "table" && it.name.eq_ignore_ascii_case(&index.table_name))
+        else {
+            return;
+        };
+        let Ok(table_definition) = table.definition() else {
+            return;
+        };
+        // Expressions and partial indexes would need an expression evaluator
+        let Some((definition, columns)) = index.index_columns(&table_definition) else {
+            return;
+        };
+
+        let actual = self
+            .rows
+            .remove(&(index.root_page as u32))
+            .unwrap_or_default();
+        self.check_index_order(index, &definition, &actual);
+        let mut actual_keys: HashMap<Vec<String>, usize> = HashMap::new();
+        for (_, record) in &actual {
+            *actual_keys
+                .entry(record.content.iter().map(key_literal).collect())
+                .or_default() += 1;
+        }
+
+        let rows = self
+            .rows
+            .remove(&(table.root_page as u32))
+            .unwrap_or_default();
+        let mut unique_keys: HashMap<Vec<String>, usize> = HashMap::new();
+        let mut missing = vec![];
+        for (row_id, record) in &rows {
+            let values = table_definition.row_values(*row_id, record.clone());
+            let mut key = columns
+                .iter()
+                .map(|i| key_literal(&values[*i]))
+                .collect_vec();
+            if definition.unique
+                && columns
+                    .iter()
+                    .all(|i| !matches!(values[*i], RecordSerial::Null))
+            {
+                *unique_keys.entry(key.clone()).or_default() += 1;
+            }
+            match row_id {
+                Some(row_id) => key.push(row_id.to_string()),
+                None => key.extend(
+                    table_definition
+                        .primary_key
+                        .iter()
+                        .map(|i| key_literal(&values[*i])),
+                ),
+            }
+            match actual_keys.get_mut(&key) {
+                Some(count) if *count > 0 => *count -= 1,
+                _ => missing.push(*row_id),
+            }
+        }
+
+        for row_id in missing {
+            self.error(format!(
+                "row {} missing from index {}",
+                row_id.map_or("?".to_string(), |it| it.to_string()),
+                index.name
+            ));
+        }
+        if actual.len() != rows.len() {
+            self.error(format!("wrong # of entries in index {}", index.name));
+        }
+        if unique_keys.values().any(|it| *it > 1) {
+            self.error(format!("non-unique entry in index {}", index.name));
+        }
+        // Other indexes of the same table need them too
+        self.rows.insert(table.root_page as u32, rows);
+    }
+
+    /// Index entries must be sorted by their key, with the columns' collations
+    fn check_index_order(
+        &mut self,
+        index: &Table,
+        definition: &IndexDefinition,
+        entries: &[(Option<i64>, Record)],
+    ) {
+        for (i, pair) in entries.windows(2).enumerate() {
+            let (a, b) = (&pair[0].1.content, &pair[1].1.content);
+            let ordering = definition.compare(a, b).then_with(|| a.len().cmp(&b.len()));
+            if ordering.is_gt() {
+                self.error(format!("Index {} entry {} out of order", index.name, i + 1));
+                return;
+            }
+        }
+    }
+}
+
+/// Compares equal for the same value however it was stored
+fn key_literal(value: &RecordSerial) -> String {
+    match value {
+        RecordSerial::F64(f) if f.fract() == 0.0 && f.abs() < 9.0e15 => (*f as i64).to_string(),
+        other => other.to_sql_literal(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::{CreateOptions, MemoryVfs, Vfs},
+        testing::{content, create, execute, integrity_check, reopen, PATH},
+    };
+
+    /// Table `t` in page 2 and its index `t_a` in page 3, with 1024 byte pages
+    fn database(vfs: &MemoryVfs) {
+        let options = CreateOptions {
+            page_size: 1024,
+            ..CreateOptions::default()
+        };
+        let mut db = create(vfs, &options);
+        execute(&mut db, "CREATE TABLE t(id INTEGER PRIMARY KEY, a TEXT)").unwrap();
+        execute(&mut db, "CREATE INDEX t_a ON t(a)").unwrap();
+        execute(&mut db, "INSERT INTO t VALUES (1, 'apple')").unwrap();
+        execute(&mut db, "INSERT INTO t VALUES (2, 'banana')").unwrap();
+        execute(&mut db, "INSERT INTO t VALUES (3, 'cherry')").unwrap();
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    fn corrupt(vfs: &MemoryVfs, offset: usize, bytes: &[u8]) {
+        let mut file = vfs.open(PATH, false).unwrap();
+        file.write_at(offset as u64, bytes).unwrap();
+    }
+
+    fn errors(vfs: &MemoryVfs) -> Vec<String> {
+        super::integrity_check(&mut reopen(vfs), false, 100).unwrap()
+    }
+
+    /// Where the cell pointer `i` of page 2 points in the file
+    fn cell_offset(vfs: &MemoryVfs, i: usize) -> usize {
+        let file = content(vfs, PATH);
+        let at = 1024 + 8 + i * 2;
+        1024 + u16::from_be_bytes([file[at], file[at + 1]]) as usize
+    }
+
+    #[test]
+    fn rowids_must_increase() {
+        let vfs = MemoryVfs::default();
+        database(&vfs);
+        // The rowid follows a one byte payload size
+        corrupt(&vfs, cell_offset(&vfs, 1) + 1, &[1]);
+        assert_eq!(
+            errors(&vfs)[0],
+            "Tree 2 page 2 cell 1: Rowid 1 out of order"
+        );
+    }
+
+    #[test]
+    fn cells_must_not_overlap() {
+        let vfs = MemoryVfs::default();
+        database(&vfs);
+        let first = cell_offset(&vfs, 0) - 1024;
+        corrupt(&vfs, 1024 + 10, &(first as u16).to_be_bytes());
+        assert_eq!(
+            errors(&vfs)[0],
+            format!("Tree 2 page 2: Multiple uses for byte {} of page 2", first)
+        );
+    }
+
+    #[test]
+    fn freelist_pages_are_referenced_once() {
+        let vfs = MemoryVfs::default();
+        database(&vfs);
+        let mut db = reopen(&vfs);
+        execute(&mut db, "CREATE TABLE u(a)").unwrap();
+        execute(&mut db, "DROP TABLE u").unwrap();
+        let trunk = u32_at(&content(&vfs, PATH), 32);
+        assert_eq!(trunk, 4);
+
+        // The trunk lists itself as its only leaf
+        let at = (trunk as usize - 1) * 1024;
+        corrupt(&vfs, at + 4, &[0, 0, 0, 1, 0, 0, 0, trunk as u8]);
+        corrupt(&vfs, 36, &2u32.to_be_bytes());
+        // The freelist isn't walked past the loop, so its pages aren't marked
+        assert_eq!(
+            errors(&vfs),
+            ["Freelist: 2nd reference to page 4", "Page 4: never used"]
+        );
+    }
+
+    #[test]
+    fn pages_outside_every_tree_are_never_used() {
+        let vfs = MemoryVfs::default();
+        database(&vfs);
+        corrupt(&vfs, 3 * 1024, &[0; 1024]);
+        assert_eq!(errors(&vfs), ["Page 4: never used"]);
+    }
+
+    #[test]
+    fn rows_must_be_in_their_indexes() {
+        let vfs = MemoryVfs::default();
+        database(&vfs);
+        let file = content(&vfs, PATH);
+        let index_page = &file[2 * 1024..3 * 1024];
+        let at = index_page
+            .windows(6)
+            .position(|it| it == b"cherry")
+            .unwrap();
+        corrupt(&vfs, 2 * 1024 + at, b"cherrz");
+        assert_eq!(errors(&vfs), ["row 3 missing from index t_a"]);
+    }
+}
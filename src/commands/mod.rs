@@ -0,0 +1,27 @@
+mod analyze;
+mod create;
+mod dbinfo;
+mod delete;
+mod drop;
+mod dump;
+mod freelist;
+mod insert;
+mod integrity_check;
+mod page;
+mod table_writer;
+mod update;
+mod vacuum;
+
+pub use analyze::*;
+pub use create::*;
+pub use dbinfo::*;
+pub use delete::*;
+pub use drop::*;
+pub use dump::*;
+pub use freelist::*;
+pub use insert::*;
+pub use integrity_check::*;
+pub use page::*;
+pub use table_writer::*;
+pub use update::*;
+pub use vacuum::*;
@@ -0,0 +1,228 @@
+use std::io::Write;
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+
+use crate::format::{is_ptrmap_page, Cell, Page, PageHeader, PtrmapEntry, SqliteFile};
+
+/// Everything `Page::from_bytes_with_overflow` would look at, decoded one
+/// piece at a time so a broken cell doesn't hide the rest of the page
+pub fn page_dump(db: &mut SqliteFile, page_number: u64, out: &mut impl Write) -> Result<()> {
+    let page_count = db.page_count()?;
+    if page_number == 0 || page_number > page_count {
+        bail!(
+            "page {} out of range, the file has {} pages",
+            page_number,
+            page_count
+        );
+    }
+
+    let buf = db.read_raw_page(page_number)?;
+    let padding = if page_number == 1 { 100 } else { 0 };
+    let db_header = db.header.clone();
+    writeln!(
+        out,
+        "page {} of {}, file offset {}",
+        page_number,
+        page_count,
+        (page_number - 1) * db_header.page_size as u64
+    )?;
+
+    if db_header.is_auto_vacuum() && is_ptrmap_page(&db_header, page_number as u32) {
+        writeln!(out, "type:                  pointer map")?;
+        // Entries for the pages after this one, up to the end of the file
+        for (i, entry) in buf[..db_header.usable_size()].chunks_exact(5).enumerate() {
+            let mapped = page_number + 1 + i as u64;
+            if mapped > page_count {
+                break;
+            }
+            match PtrmapEntry::from_bytes(entry.try_into()?) {
+                Some(entry) => writeln!(
+                    out,
+                    "page {}: {:?}, parent {}",
+                    mapped, entry.kind, entry.parent
+                )?,
+                None => writeln!(out, "page {}: unmapped", mapped)?,
+            }
+        }
+        writeln!(out)?;
+        return hex_dump(&buf, out);
+    }
+
+    match PageHeader::from_bytes(&buf[padding..]) {
+        Ok(header) => {
+            writeln!(
+                out,
+                "type:                  {:?} (0x{:02x})",
+                header.kind, header.kind as u8
+            )?;
+            writeln!(out, "first freeblock:       {}", header.first_freeblock)?;
+            writeln!(out, "number of cells:       {}", header.number_of_cells)?;
+            writeln!(out, "cell content area:     {}", header.first_cell_content)?;
+            writeln!(
+                out,
+                "fragmented free bytes: {}",
+                header.fragmented_free_bytes
+            )?;
+            if let Some(right_most) = header.page_number {
+                writeln!(out, "right-most pointer:    {}", right_most)?;
+            }
+
+            match Page::parse_cell_pointer_array(&buf, &header, padding) {
+                Ok(pointers) => {
+                    writeln!(out, "cell pointers:         {}", pointers.iter().join(" "))?;
+                    let pointer_array_end = padding + header.size() + pointers.len() * 2;
+                    let content_start = match header.first_cell_content {
+                        0 => 65536,
+                        n => n as usize,
+                    };
+                    writeln!(
+                        out,
+                        "unallocated bytes:     {}",
+                        content_start.saturating_sub(pointer_array_end)
+                    )?;
+
+                    for (i, pointer) in pointers.iter().enumerate() {
+                        let cell = Cell::from_bytes(
+                            &buf,
+                            *pointer as u64,
+                            &header,
+                            &db_header,
+                            &mut |n| {
+                                db.read_raw_page(n as u64)
+                                    .map_err(|_| "Failed to read overflow page")
+                            },
+                        );
+                        write!(out, "cell {} at {}: ", i, pointer)?;
+                        match cell {
+                            Ok(cell) => write_cell(&cell, out)?,
+                            Err(e) => writeln!(out, "error: {}", e)?,
+                        }
+                    }
+                }
+                Err(e) => writeln!(out, "cell pointers:         error: {}", e)?,
+            }
+
+            match Page::parse_freeblocks(&buf, &header) {
+                Ok(freeblocks) => {
+                    for freeblock in freeblocks {
+                        writeln!(
+                            out,
+                            "freeblock at {}: {} bytes",
+                            freeblock.offset, freeblock.size
+                        )?;
+                    }
+                }
+                Err(e) => writeln!(out, "freeblocks: error: {}", e)?,
+            }
+        }
+        // Overflow and freelist pages have no b-tree header
+        Err(e) => writeln!(out, "page header: {} (type byte 0x{:02x})", e, buf[padding])?,
+    }
+
+    writeln!(out)?;
+    hex_dump(&buf, out)
+}
+
+fn write_cell(cell: &Cell, out: &mut impl Write) -> Result<()> {
+    match cell {
+        Cell::LeafTable {
+            size,
+            row_id,
+            overflow_page,
+            ..
+        } => writeln!(
+            out,
+            "rowid {}, payload {} bytes{}",
+            row_id.value,
+            size.value,
+            overflow(overflow_page)
+        )?,
+        Cell::InteriorTable { left_child, key } => {
+            writeln!(out, "left child {}, key {}", left_child, key.value)?
+        }
+        Cell::LeafIndex {
+            size,
+            overflow_page,
+            ..
+        } => writeln!(
+            out,
+            "payload {} bytes{}",
+            size.value,
+            overflow(overflow_page)
+        )?,
+        Cell::InteriorIndex {
+            left_child,
+            size,
+            overflow_page,
+            ..
+        } => writeln!(
+            out,
+            "left child {}, payload {} bytes{}",
+            left_child,
+            size.value,
+            overflow(overflow_page)
+        )?,
+    }
+    if let Some(payload) = cell.payload() {
+        writeln!(
+            out,
+            "  serial types: {}",
+            payload.serial_types.iter().join(" ")
+        )?;
+        writeln!(
+            out,
+            "  values:       {}",
+            payload
+                .content
+                .iter()
+                .map(|it| it.to_sql_literal())
+                .join("|")
+        )?;
+    }
+    Ok(())
+}
+
+fn overflow(overflow_page: &Option<u32>) -> String {
+    match overflow_page {
+        Some(page) => format!(", overflows to page {}", page),
+        None => String::new(),
+    }
+}
+
+/// 16 bytes per line, repeated lines collapse into `*` like `hexdump -C`
+fn hex_dump(buf: &[u8], out: &mut impl Write) -> Result<()> {
+    let mut previous: Option<&[u8]> = None;
+    let mut collapsed = false;
+    for (i, line) in buf.chunks(16).enumerate() {
+        if previous == Some(line) {
+            if !collapsed {
+                writeln!(out, "*")?;
+                collapsed = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        collapsed = false;
+
+        let ascii: String = line
+            .iter()
+            .map(|b| {
+                if b.is_ascii_graphic() || *b == b' ' {
+                    *b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(
+            out,
+            "{:06x}: {:<47}  |{}|",
+            i * 16,
+            line.iter().map(|b| format!("{b:02x}")).join(" "),
+            ascii
+        )?;
+    }
+    writeln!(out, "{:06x}", buf.len())?;
+    Ok(())
+}
@@ -0,0 +1,401 @@
+use anyhow::{anyhow, bail, Error, Result};
+use itertools::Itertools;
+
+use crate::{
+    format::{
+        IndexColumn, IndexDefinition, Record, RecordSerial, SqliteFile, Table, TableDefinition,
+    },
+    parser::parse_literal,
+    utils::{last_row_id, leaf_cell, scan_index, scan_table, Cursor},
+};
+
+/// An index of the table being written, with the table columns of its key
+struct Index {
+    table: Table,
+    definition: IndexDefinition,
+    columns: Vec<usize>,
+}
+
+/// A table with its indexes, keeping them in step when rows change.
+/// Pages stay dirty until the caller commits.
+pub struct TableWriter {
+    pub table: Table,
+    pub definition: TableDefinition,
+    indexes: Vec<Index>,
+}
+
+const SCHEMA_SQL: &str =
+    "CREATE TABLE sqlite_schema(type text, name text, tbl_name text, rootpage integer, sql text)";
+
+/// A row as stored: its rowid (None in WITHOUT ROWID tables) and values in column order
+pub type Row = (Option<i64>, Vec<RecordSerial>);
+
+impl TableWriter {
+    pub fn open(db: &SqliteFile, table_name: &str) -> Result<Self> {
+        let table = db
+            .tables
+            .iter()
+            .find(|it| it.kind == "table" && it.name.eq_ignore_ascii_case(table_name))
+            .cloned()
+            .ok_or_else(|| anyhow!("no such table: {}", table_name))?;
+        let definition = table.definition().map_err(Error::msg)?;
+
+        let indexes = db
+            .tables
+            .iter()
+            .filter(|it| it.kind == "index" && it.table_name.eq_ignore_ascii_case(&table.name))
+            .map(|index| {
+                let (index_definition, columns) =
+                    index.index_columns(&definition).ok_or_else(|| {
+                        anyhow!(
+                            "cannot write to {}, index {} is partial or on an expression",
+                            table.name,
+                            index.name
+                        )
+                    })?;
+                Ok(Index {
+                    table: index.clone(),
+                    definition: index_definition,
+                    columns,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(TableWriter {
+            table,
+            definition,
+            indexes,
+        })
+    }
+
+    /// sqlite_schema itself, which isn't one of `db.tables`
+    pub fn schema() -> Result<Self> {
+        let table = Table {
+            kind: "table".to_string(),
+            name: "sqlite_schema".to_string(),
+            table_name: "sqlite_schema".to_string(),
+            root_page: 1,
+            sql: SCHEMA_SQL.to_string(),
+        };
+        let definition = table.definition().map_err(Error::msg)?;
+        Ok(TableWriter {
+            table,
+            definition,
+            indexes: vec![],
+        })
+    }
+
+    pub fn rows(&self, db: &mut SqliteFile) -> Result<Vec<Row>> {
+        let root_page = self.table.root_page as u32;
+        Ok(if self.definition.without_rowid {
+            scan_index(db, root_page)?
+                .into_iter()
+                .map(|record| (None, self.definition.row_values(None, record)))
+                .collect()
+        } else {
+            scan_table(db, root_page)?
+                .into_iter()
+                .map(|row| {
+                    let values = self.definition.row_values(Some(row.row_id), row.record);
+                    (Some(row.row_id), values)
+                })
+                .collect()
+        })
+    }
+
+    /// Fills in DEFAULT values for missing columns and converts the rest to
+    /// their column's affinity
+    pub fn prepare(&self, values: Vec<Option<RecordSerial>>) -> Result<Vec<RecordSerial>> {
+        self.definition
+            .columns
+            .iter()
+            .zip(values)
+            .map(|(column, value)| {
+                let value = match (value, &column.default) {
+                    (Some(value), _) => value,
+                    (None, Some(default)) => parse_literal(default).map_err(|_| {
+                        anyhow!(
+                            "unsupported default for column {}: {}",
+                            column.name,
+                            default
+                        )
+                    })?,
+                    (None, None) => RecordSerial::Null,
+                };
+                Ok(column.affinity().apply(value))
+            })
+            .collect()
+    }
+
+    /// Adds a row from prepared values. The rowid comes from the rowid alias,
+    /// then `row_id`, or is the next free one.
+    pub fn insert_row(
+        &self,
+        db: &mut SqliteFile,
+        row_id: Option<i64>,
+        mut values: Vec<RecordSerial>,
+    ) -> Result<()> {
+        let (table, definition) = (&self.table, &self.definition);
+        let root_page = table.root_page as u32;
+        let encoding = db.header.text_encoding;
+        let alias = definition.rowid_alias();
+
+        for (i, column) in definition.columns.iter().enumerate() {
+            let required = column.not_null
+                || (definition.without_rowid && definition.primary_key.contains(&i));
+            if required && Some(i) != alias && matches!(values[i], RecordSerial::Null) {
+                bail!("NOT NULL constraint failed: {}.{}", table.name, column.name);
+            }
+        }
+
+        let row_id = if definition.without_rowid {
+            let key = definition
+                .primary_key
+                .iter()
+                .map(|i| values[*i].clone())
+                .collect_vec();
+            let primary_key = self.primary_key_definition();
+            if Cursor::seek_key(db, root_page, &|entry| primary_key.compare(&key, entry))?.found {
+                bail!(
+                    "UNIQUE constraint failed: {}",
+                    self.constraint_columns(&definition.primary_key)
+                );
+            }
+            None
+        } else {
+            let row_id = match (alias.map(|i| &values[i]), row_id) {
+                (Some(RecordSerial::Null) | None, Some(row_id)) => row_id,
+                (Some(RecordSerial::Null) | None, None) => self.next_row_id(db)?,
+                (Some(value), _) => value.as_i64().ok_or_else(|| anyhow!("datatype mismatch"))?,
+            };
+            if Cursor::seek_row_id(db, root_page, row_id)?.found {
+                let name = alias.map_or("rowid", |i| &definition.columns[i].name);
+                bail!("UNIQUE constraint failed: {}.{}", table.name, name);
+            }
+            if let Some(i) = alias {
+                values[i] = RecordSerial::I64(row_id);
+            }
+            Some(row_id)
+        };
+
+        // Every constraint is checked before anything is written
+        let keys = self
+            .indexes
+            .iter()
+            .map(|index| self.index_key(index, &values, row_id))
+            .collect_vec();
+        for (index, key) in self.indexes.iter().zip(&keys) {
+            self.check_unique(db, index, key)?;
+        }
+
+        let (cursor, cell) = match row_id {
+            Some(row_id) => {
+                // The rowid alias is stored as NULL, the rowid holds its value
+                if let Some(i) = alias {
+                    values[i] = RecordSerial::Null;
+                }
+                let cell = leaf_cell(db, Some(row_id), &Record::encode(&values, &encoding))?;
+                (Cursor::seek_row_id(db, root_page, row_id)?, cell)
+            }
+            None => {
+                let key = self.table_key(&values);
+                let primary_key = self.primary_key_definition();
+                let cell = leaf_cell(db, None, &Record::encode(&key, &encoding))?;
+                let compare = |entry: &[RecordSerial]| primary_key.compare(&key, entry);
+                (Cursor::seek_key(db, root_page, &compare)?, cell)
+            }
+        };
+        cursor.insert(db, cell)?;
+
+        for (index, key) in self.indexes.iter().zip(keys) {
+            insert_entry(db, index, key)?;
+        }
+
+        if let (true, Some(row_id)) = (definition.autoincrement, row_id) {
+            self.update_sequence(db, row_id)?;
+        }
+        Ok(())
+    }
+
+    /// Fills a new, still empty index from the rows already in the table
+    pub fn build_index(&self, db: &mut SqliteFile, index_name: &str) -> Result<()> {
+        let index = self
+            .indexes
+            .iter()
+            .find(|it| it.table.name.eq_ignore_ascii_case(index_name))
+            .ok_or_else(|| anyhow!("no such index: {}", index_name))?;
+        for (row_id, values) in self.rows(db)? {
+            let key = self.index_key(index, &values, row_id);
+            self.check_unique(db, index, &key)?;
+            insert_entry(db, index, key)?;
+        }
+        Ok(())
+    }
+
+    /// Removes a row read by `rows` and its index entries
+    pub fn delete_row(&self, db: &mut SqliteFile, (row_id, values): &Row) -> Result<()> {
+        let root_page = self.table.root_page as u32;
+        let cursor = match row_id {
+            Some(row_id) => Cursor::seek_row_id(db, root_page, *row_id)?,
+            None => {
+                let key = self.table_key(values);
+                let primary_key = self.primary_key_definition();
+                Cursor::seek_key(db, root_page, &|entry| primary_key.compare(&key, entry))?
+            }
+        };
+        if !cursor.found {
+            bail!("row missing from table {}", self.table.name);
+        }
+        cursor.delete(db)?;
+
+        for index in &self.indexes {
+            let key = self.index_key(index, values, *row_id);
+            let compare = |entry: &[RecordSerial]| {
+                index
+                    .definition
+                    .compare(&key, entry)
+                    .then(key.len().cmp(&entry.len()))
+            };
+            let cursor = Cursor::seek_key(db, index.table.root_page as u32, &compare)?;
+            if !cursor.found {
+                bail!("row missing from index {}", index.table.name);
+            }
+            cursor.delete(db)?;
+        }
+        Ok(())
+    }
+
+    /// One more than the largest rowid, or than the largest ever used with AUTOINCREMENT
+    fn next_row_id(&self, db: &mut SqliteFile) -> Result<i64> {
+        let mut last = last_row_id(db, self.table.root_page as u32)?.unwrap_or(0);
+        if self.definition.autoincrement {
+            last = last.max(self.sequence(db)?.map_or(0, |(_, seq)| seq));
+        }
+        // SQLite would look for an unused one at random
+        last.checked_add(1)
+            .ok_or_else(|| anyhow!("database or disk is full"))
+    }
+
+    /// The rowid and value of the table's row in sqlite_sequence
+    fn sequence(&self, db: &mut SqliteFile) -> Result<Option<(i64, i64)>> {
+        let root_page = sequence_table(db)?.root_page as u32;
+        Ok(scan_table(db, root_page)?
+            .into_iter()
+            .find_map(|row| match &row.record.content[..] {
+                [RecordSerial::String(name), seq, ..] if *name == self.table.name => {
+                    Some((row.row_id, seq.as_i64().unwrap_or_default()))
+                }
+                _ => None,
+            }))
+    }
+
+    fn update_sequence(&self, db: &mut SqliteFile, row_id: i64) -> Result<()> {
+        let root_page = sequence_table(db)?.root_page as u32;
+        let sequence_row_id = match self.sequence(db)? {
+            Some((_, seq)) if seq >= row_id => return Ok(()),
+            Some((sequence_row_id, _)) => sequence_row_id,
+            None => last_row_id(db, root_page)?.unwrap_or(0) + 1,
+        };
+        let record = Record::encode(
+            &[
+                RecordSerial::String(self.table.name.clone()),
+                RecordSerial::I64(row_id),
+            ],
+            &db.header.text_encoding,
+        );
+        let cell = leaf_cell(db, Some(sequence_row_id), &record)?;
+        Cursor::seek_row_id(db, root_page, sequence_row_id)?.insert(db, cell)
+    }
+
+    /// NULLs never conflict, they are all distinct
+    fn check_unique(&self, db: &mut SqliteFile, index: &Index, key: &[RecordSerial]) -> Result<()> {
+        let prefix = &key[..index.columns.len()];
+        if !index.definition.unique || prefix.iter().any(|it| matches!(it, RecordSerial::Null)) {
+            return Ok(());
+        }
+        let compare = |entry: &[RecordSerial]| index.definition.compare(prefix, entry);
+        if Cursor::seek_key(db, index.table.root_page as u32, &compare)?.found {
+            bail!(
+                "UNIQUE constraint failed: {}",
+                self.constraint_columns(&index.columns)
+            );
+        }
+        Ok(())
+    }
+
+    /// Index entries end with the rowid, or with the primary key of a WITHOUT ROWID table
+    fn index_key(
+        &self,
+        index: &Index,
+        values: &[RecordSerial],
+        row_id: Option<i64>,
+    ) -> Vec<RecordSerial> {
+        let mut key = index
+            .columns
+            .iter()
+            .map(|i| values[*i].clone())
+            .collect_vec();
+        match row_id {
+            Some(row_id) => key.push(RecordSerial::I64(row_id)),
+            None => key.extend(
+                self.definition
+                    .primary_key
+                    .iter()
+                    .filter(|i| !index.columns.contains(i))
+                    .map(|i| values[*i].clone()),
+            ),
+        }
+        key
+    }
+
+    /// WITHOUT ROWID records hold the primary key first, then the other columns
+    fn table_key(&self, values: &[RecordSerial]) -> Vec<RecordSerial> {
+        let primary_key = &self.definition.primary_key;
+        primary_key
+            .iter()
+            .copied()
+            .chain((0..values.len()).filter(|i| !primary_key.contains(i)))
+            .map(|i| values[i].clone())
+            .collect()
+    }
+
+    fn primary_key_definition(&self) -> IndexDefinition {
+        let columns = &self.definition.columns;
+        IndexDefinition {
+            unique: true,
+            columns: self
+                .definition
+                .primary_key
+                .iter()
+                .map(|i| IndexColumn {
+                    column: Some(columns[*i].name.clone()),
+                    collation: columns[*i].collation.clone(),
+                    descending: false,
+                })
+                .collect(),
+            partial: false,
+        }
+    }
+
+    /// `t.a, t.b` like SQLite names the columns of a failed constraint
+    fn constraint_columns(&self, columns: &[usize]) -> String {
+        columns
+            .iter()
+            .map(|i| format!("{}.{}", self.table.name, self.definition.columns[*i].name))
+            .join(", ")
+    }
+}
+
+fn insert_entry(db: &mut SqliteFile, index: &Index, key: Vec<RecordSerial>) -> Result<()> {
+    let cell = leaf_cell(db, None, &Record::encode(&key, &db.header.text_encoding))?;
+    let compare = |entry: &[RecordSerial]| index.definition.compare(&key, entry);
+    Cursor::seek_key(db, index.table.root_page as u32, &compare)?.insert(db, cell)
+}
+
+fn sequence_table(db: &SqliteFile) -> Result<Table> {
+    db.tables
+        .iter()
+        .find(|it| it.kind == "table" && it.name == "sqlite_sequence")
+        .cloned()
+        .ok_or_else(|| anyhow!("no such table: sqlite_sequence"))
+}
@@ -0,0 +1,45 @@
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+
+use super::TableWriter;
+use crate::{expr::Expr, format::SqliteFile};
+
+/// Sets columns of the rows matching `condition`. Each row is removed and
+/// written again, so its index entries and constraints are handled like an insert.
+pub fn update(
+    db: &mut SqliteFile,
+    table_name: &str,
+    assignments: &[(String, Expr)],
+    condition: Option<&Expr>,
+) -> Result<usize> {
+    let writer = TableWriter::open(db, table_name)?;
+    let definition = &writer.definition;
+    let columns: Vec<usize> = assignments
+        .iter()
+        .map(|(name, _)| {
+            definition
+                .column_index(name)
+                .ok_or_else(|| anyhow!("no such column: {}", name))
+        })
+        .try_collect()?;
+
+    let mut count = 0;
+    for row in writer.rows(db)? {
+        if let Some(condition) = condition {
+            if !condition.matches(definition, &row.1)? {
+                continue;
+            }
+        }
+        // Every expression sees the row as it was
+        let mut values = row.1.clone();
+        for (i, (_, expr)) in columns.iter().zip(assignments) {
+            values[*i] = definition.columns[*i]
+                .affinity()
+                .apply(expr.evaluate(definition, &row.1)?);
+        }
+        writer.delete_row(db, &row)?;
+        writer.insert_row(db, row.0, values)?;
+        count += 1;
+    }
+    Ok(count)
+}
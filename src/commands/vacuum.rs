@@ -0,0 +1,243 @@
+use std::{cmp::Ordering, rc::Rc};
+
+use anyhow::{bail, Result};
+
+use super::add_to_schema;
+use crate::{
+    format::{AutoVacuum, CreateOptions, FileSystem, PageType, Record, SqliteFile, Vfs},
+    utils::{create_btree, leaf_cell, random_u32, scan_index, scan_table, Cursor, Node},
+};
+
+/// Pages the copy keeps in memory before writing them to its file
+const COPY_DIRTY_PAGES: usize = 1000;
+
+/// Rebuilds the database from a compacted copy: no free pages, every b-tree
+/// packed and in order. The copy replaces all pages in one transaction
+pub fn vacuum(db: &mut SqliteFile) -> Result<()> {
+    if db.in_transaction() {
+        bail!("cannot VACUUM from within a transaction");
+    }
+    // Like SQLite, a WAL keeps its page size
+    if db.wal.is_some() {
+        db.pending_page_size = None;
+    }
+    // The copy is built in a file next to the database, so it isn't all in
+    // memory. The name is unique to this VACUUM
+    let vfs = db.vfs();
+    let copy_path = format!("{}-vacuum-{:08x}", db.path(), random_u32());
+    let result = compact_copy(db, vfs.clone(), &copy_path).and_then(|mut copy| {
+        let mut header = copy.header.clone();
+        // The file stays the same one to other connections, in the same journal mode
+        header.file_change_counter = db.header.file_change_counter;
+        header.version_valid_for = db.header.version_valid_for;
+        header.write_version = db.header.write_version;
+        header.read_version = db.header.read_version;
+        db.replace_content(header, &mut copy)
+    });
+    for path in [format!("{copy_path}-journal"), copy_path] {
+        if vfs.exists(&path)? {
+            vfs.delete(&path)?;
+        }
+    }
+    result?;
+    if let Err(e) = db.commit() {
+        db.discard()?;
+        return Err(e);
+    }
+    db.pending_page_size = None;
+    db.pending_auto_vacuum = None;
+    db.read_schema()
+}
+
+/// Switching between full and incremental auto-vacuum is immediate. Turning
+/// it on or off needs pointer map pages added or removed, so it only applies
+/// right away to a database with nothing in it yet, otherwise on the next VACUUM
+pub fn set_auto_vacuum(db: &mut SqliteFile, mode: AutoVacuum) -> Result<usize> {
+    let auto_vacuum = mode != AutoVacuum::None;
+    if auto_vacuum != db.header.is_auto_vacuum() && db.page_count()? > 1 {
+        db.pending_auto_vacuum = Some(mode);
+        return Ok(0);
+    }
+    if mode == db.header.auto_vacuum() {
+        return Ok(0);
+    }
+    if !db.header.is_auto_vacuum() {
+        db.header.largest_root_btree_page = 1;
+    } else if !auto_vacuum {
+        db.header.largest_root_btree_page = 0;
+    }
+    db.header.incremental_vacuum_mode = mode == AutoVacuum::Incremental;
+    // Page 1 carries the header
+    let page = db.read_raw_page(1)?;
+    db.write_page(1, page)?;
+    Ok(0)
+}
+
+/// Writes a compacted copy of the database to a new file
+pub fn vacuum_into(db: &mut SqliteFile, path: &str) -> Result<SqliteFile> {
+    if FileSystem.exists(path)? {
+        bail!("output file already exists");
+    }
+    compact_copy(db, Rc::new(FileSystem), path)
+}
+
+/// Writes a compacted copy of the database to `path` in `vfs`
+fn compact_copy(db: &mut SqliteFile, vfs: Rc<dyn Vfs>, path: &str) -> Result<SqliteFile> {
+    let options = CreateOptions {
+        page_size: db.pending_page_size.unwrap_or(db.header.page_size),
+        text_encoding: db.header.text_encoding,
+        user_version: db.header.user_version,
+        application_id: db.header.application_id,
+        auto_vacuum: db.pending_auto_vacuum.unwrap_or(db.header.auto_vacuum()),
+    };
+    let mut copy = SqliteFile::create_vfs(vfs, path, &options)?;
+    copy.header.default_page_cache_size = db.header.default_page_cache_size;
+    copy.header.schema_cookie = db.header.schema_cookie.wrapping_add(1);
+
+    for table in db.tables.clone() {
+        // Views and triggers have no b-tree
+        let root_page = match table.root_page {
+            0 => 0,
+            root_page => copy_btree(db, &mut copy, root_page as u32)?,
+        };
+        let sql = (!table.sql.is_empty()).then_some(table.sql.as_str());
+        add_to_schema(
+            &mut copy,
+            &table.kind,
+            &table.name,
+            &table.table_name,
+            root_page,
+            sql,
+        )?;
+    }
+    copy.commit()?;
+    copy.read_schema()?;
+    Ok(copy)
+}
+
+/// Entries go in key order, so every insert appends and fills pages up
+fn copy_btree(db: &mut SqliteFile, copy: &mut SqliteFile, source_root: u32) -> Result<u32> {
+    let encoding = db.header.text_encoding;
+    let kind = Node::read(db, source_root)?.kind;
+    if matches!(kind, PageType::LeafTable | PageType::InteriorTable) {
+        let root_page = create_btree(copy, PageType::LeafTable)?;
+        for row in scan_table(db, source_root)? {
+            let payload = Record::encode(&row.record.content, &encoding);
+            let cell = leaf_cell(copy, Some(row.row_id), &payload)?;
+            Cursor::seek_row_id(copy, root_page, row.row_id)?.insert(copy, cell)?;
+            write_some(copy)?;
+        }
+        return Ok(root_page);
+    }
+    let root_page = create_btree(copy, PageType::LeafIndex)?;
+    for record in scan_index(db, source_root)? {
+        let payload = Record::encode(&record.content, &encoding);
+        let cell = leaf_cell(copy, None, &payload)?;
+        Cursor::seek_key(copy, root_page, &|_| Ordering::Greater)?.insert(copy, cell)?;
+        write_some(copy)?;
+    }
+    Ok(root_page)
+}
+
+/// Commits the copy once it holds many pages, nothing else reads it
+fn write_some(copy: &mut SqliteFile) -> Result<()> {
+    if copy.dirty_page_count() >= COPY_DIRTY_PAGES {
+        copy.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::MemoryVfs,
+        testing::{create, execute, integrity_check, query_one, reopen},
+    };
+
+    /// 300 rows in 1024 byte pages, with an index, every other one deleted
+    fn database(vfs: &MemoryVfs) -> SqliteFile {
+        let options = CreateOptions {
+            page_size: 1024,
+            ..CreateOptions::default()
+        };
+        let mut db = create(vfs, &options);
+        execute(&mut db, "CREATE TABLE t(id INTEGER PRIMARY KEY, a TEXT)").unwrap();
+        execute(&mut db, "CREATE INDEX t_a ON t(a)").unwrap();
+        for id in 1..=300 {
+            let sql = format!("INSERT INTO t VALUES ({id}, '{id}{}')", "x".repeat(id * 5));
+            execute(&mut db, &sql).unwrap();
+        }
+        execute(&mut db, "DELETE FROM t WHERE id % 2 = 0").unwrap();
+        db
+    }
+
+    fn rows(db: &mut SqliteFile) -> Vec<String> {
+        let rows = execute(db, "SELECT id, a FROM t").unwrap();
+        rows.iter()
+            .map(|row| format!("{}|{}", row[0], row[1]))
+            .collect()
+    }
+
+    #[test]
+    fn vacuum_frees_every_page_it_can() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        let before = rows(&mut db);
+        let page_count = db.page_count().unwrap();
+        assert!(db.header.free_list_count > 0);
+
+        execute(&mut db, "VACUUM").unwrap();
+        assert_eq!(db.header.free_list_count, 0);
+        assert!(db.page_count().unwrap() < page_count);
+        // The copy is gone with its journal
+        assert_eq!(vfs.paths(), ["test.db"]);
+
+        let mut db = reopen(&vfs);
+        assert_eq!(rows(&mut db), before);
+        assert_eq!(
+            query_one(
+                &mut db,
+                "SELECT COUNT(*) FROM t WHERE a = '3xxxxxxxxxxxxxxx'"
+            ),
+            "1"
+        );
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    #[test]
+    fn vacuum_into_writes_a_new_file() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        let path = std::env::temp_dir().join(format!("into-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let sql = format!("VACUUM INTO '{path}'");
+        execute(&mut db, &sql).unwrap();
+        let again = execute(&mut db, &sql).err().map(|it| it.to_string());
+
+        let copy = SqliteFile::open(path).map(|mut copy| {
+            let free_pages = copy.header.free_list_count;
+            (rows(&mut copy), free_pages, integrity_check(&mut copy))
+        });
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(again.as_deref(), Some("output file already exists"));
+        assert_eq!(copy.unwrap(), (rows(&mut db), 0, "ok".to_string()));
+        assert!(db.header.free_list_count > 0);
+    }
+
+    #[test]
+    fn page_size_changes_at_the_next_vacuum() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        let before = rows(&mut db);
+        execute(&mut db, "PRAGMA page_size = 4096").unwrap();
+        assert_eq!(db.header.page_size, 1024);
+
+        execute(&mut db, "VACUUM").unwrap();
+        assert_eq!(query_one(&mut db, "PRAGMA page_size"), "4096");
+        let mut db = reopen(&vfs);
+        assert_eq!(db.header.page_size, 4096);
+        assert_eq!(rows(&mut db), before);
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+}
@@ -0,0 +1,345 @@
+use std::{cell::RefCell, rc::Rc};
+
+use anyhow::{anyhow, bail, Result};
+use itertools::Itertools;
+
+use crate::{
+    format::{
+        is_memory_path, CacheStats, CreateOptions, FileSystem, FromSql, FromSqlError, OpenOptions,
+        RecordSerial, SqliteFile, ToSql, Value, Vfs,
+    },
+    parser::{self, Command, Parameters, QueryResult},
+    FromRow, ToParams,
+};
+
+/// An open database file, to run SQL on
+pub struct Connection {
+    db: RefCell<SqliteFile>,
+    /// The path of a missing database, created by the first statement that
+    /// doesn't only read. Until then an empty one in memory is read
+    missing: RefCell<Option<String>>,
+}
+
+impl Connection {
+    /// A missing database is created once a statement writes, like sqlite3
+    /// does. `:memory:` and `file::memory:` are a new database that only
+    /// lives in memory
+    pub fn open(path: &str) -> Result<Self> {
+        Connection::open_with(path, &OpenOptions::default())
+    }
+
+    /// Like `open`, `options` say what to do with an existing database
+    pub fn open_with(path: &str, options: &OpenOptions) -> Result<Self> {
+        let missing = !is_memory_path(path) && !FileSystem.exists(path)?;
+        let db = if is_memory_path(path) || missing {
+            SqliteFile::memory(&CreateOptions::default())?
+        } else {
+            SqliteFile::open_with(path, options)?
+        };
+        Ok(Connection {
+            db: RefCell::new(db),
+            missing: RefCell::new(missing.then(|| path.to_string())),
+        })
+    }
+
+    /// Parses a single statement to run once or more
+    pub fn prepare(&self, sql: &str) -> Result<Statement<'_>> {
+        let mut statements = self.prepare_batch(sql)?;
+        if statements.len() != 1 {
+            bail!("expected one statement, got {}", statements.len());
+        }
+        Ok(statements.remove(0))
+    }
+
+    /// Parses `;` separated statements, to run in order
+    pub fn prepare_batch(&self, sql: &str) -> Result<Vec<Statement<'_>>> {
+        Ok(parser::parse(sql)?
+            .into_iter()
+            .map(|(command, parameters)| Statement {
+                connection: self,
+                command,
+                parameters,
+            })
+            .collect())
+    }
+
+    /// Runs a statement that writes, returns how many rows it changed
+    pub fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> Result<usize> {
+        self.prepare(sql)?.execute(params)
+    }
+
+    /// Inserts a struct into the columns named like its fields
+    pub fn insert<T: ToParams>(&self, table_name: &str, value: &T) -> Result<usize> {
+        let fields = value.to_params();
+        let sql = format!(
+            "INSERT INTO \"{}\" ({}) VALUES ({})",
+            table_name.replace('"', "\"\""),
+            fields.iter().map(|(name, _)| name).join(", "),
+            fields.iter().map(|(name, _)| format!(":{name}")).join(", ")
+        );
+        self.prepare(&sql)?.execute_struct(value)
+    }
+
+    /// Page cache hits and misses since the database was opened
+    pub fn cache_stats(&self) -> CacheStats {
+        self.db.borrow().cache_stats()
+    }
+}
+
+/// A parsed statement. Tables are looked up when it runs, so it sees schema
+/// changes made after it was prepared
+pub struct Statement<'conn> {
+    connection: &'conn Connection,
+    command: Command,
+    parameters: Parameters,
+}
+
+impl Statement<'_> {
+    /// How many values the statement takes, the largest placeholder index
+    pub fn parameter_count(&self) -> usize {
+        self.parameters.len()
+    }
+
+    /// The index from 1 of a placeholder like `:name` or `?2`
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.parameters.index(name)
+    }
+
+    /// Runs the statement with a value for every placeholder, in order, and
+    /// returns its rows. Statements that only write have none
+    pub fn query(&mut self, params: &[&dyn ToSql]) -> Result<Rows> {
+        let result = self.run(self.positional(params)?)?;
+        Ok(Rows {
+            columns: result.columns.into(),
+            rows: result.rows.into_iter(),
+        })
+    }
+
+    /// Like `query`, with values by placeholder name. Those left out are NULL
+    pub fn query_named(&mut self, params: &[(&str, &dyn ToSql)]) -> Result<Rows> {
+        let result = self.run(self.named(params)?)?;
+        Ok(Rows {
+            columns: result.columns.into(),
+            rows: result.rows.into_iter(),
+        })
+    }
+
+    /// Runs the statement, returns how many rows it inserted, updated or deleted
+    pub fn execute(&mut self, params: &[&dyn ToSql]) -> Result<usize> {
+        Ok(self.run(self.positional(params)?)?.changes)
+    }
+
+    pub fn execute_named(&mut self, params: &[(&str, &dyn ToSql)]) -> Result<usize> {
+        Ok(self.run(self.named(params)?)?.changes)
+    }
+
+    /// Like `execute`, with a struct's fields for the parameters named like
+    /// them. Fields without a parameter are left out
+    pub fn execute_struct<T: ToParams>(&mut self, value: &T) -> Result<usize> {
+        let mut values = vec![RecordSerial::Null; self.parameters.len()];
+        for (field, value) in value.to_params() {
+            let index = [":", "@", "$"]
+                .iter()
+                .find_map(|prefix| self.parameters.index(&format!("{prefix}{field}")));
+            if let Some(index) = index {
+                values[index - 1] = value.into();
+            }
+        }
+        Ok(self.run(values)?.changes)
+    }
+
+    fn positional(&self, params: &[&dyn ToSql]) -> Result<Vec<RecordSerial>> {
+        if params.len() != self.parameters.len() {
+            bail!(
+                "wrong number of parameters: expected {}, got {}",
+                self.parameters.len(),
+                params.len()
+            );
+        }
+        Ok(params.iter().map(|it| it.to_sql().into()).collect())
+    }
+
+    fn named(&self, params: &[(&str, &dyn ToSql)]) -> Result<Vec<RecordSerial>> {
+        let mut values = vec![RecordSerial::Null; self.parameters.len()];
+        for (name, value) in params {
+            let index = self
+                .parameters
+                .index(name)
+                .ok_or_else(|| anyhow!("no such parameter: {}", name))?;
+            values[index - 1] = value.to_sql().into();
+        }
+        Ok(values)
+    }
+
+    fn run(&self, values: Vec<RecordSerial>) -> Result<QueryResult> {
+        let mut db = self.connection.db.borrow_mut();
+        let mut missing = self.connection.missing.borrow_mut();
+        if let Some(path) = missing.as_ref().filter(|_| !self.command.is_read()) {
+            *db = SqliteFile::create(path, &CreateOptions::default())?;
+            *missing = None;
+        }
+        if values.is_empty() {
+            return parser::run(&self.command, &mut db);
+        }
+        parser::run(&self.command.bind(&values), &mut db)
+    }
+}
+
+/// The rows a statement returned, in order
+pub struct Rows {
+    columns: Rc<[String]>,
+    rows: std::vec::IntoIter<Vec<RecordSerial>>,
+}
+
+impl Rows {
+    pub fn column_names(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// Each row read into a struct, see `sql_struct!`
+    pub fn deserialize<T: FromRow>(self) -> impl Iterator<Item = Result<T>> {
+        self.map(|row| T::from_row(&row))
+    }
+}
+
+impl Iterator for Rows {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Row> {
+        self.rows.next().map(|values| Row {
+            columns: self.columns.clone(),
+            values: values.into_iter().map(Value::from).collect(),
+        })
+    }
+}
+
+/// One row, its values are read by column index
+#[derive(Debug, Clone)]
+pub struct Row {
+    columns: Rc<[String]>,
+    values: Vec<Value>,
+}
+
+impl Row {
+    pub fn column_names(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// The index of a column by name, in any case
+    pub fn column_index(&self, name: &str) -> Result<usize> {
+        self.columns
+            .iter()
+            .position(|it| it.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("no such column: {}", name))
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn value(&self, index: usize) -> Result<&Value> {
+        self.values.get(index).ok_or_else(|| {
+            anyhow!(
+                "column index {} out of range, the row has {} columns",
+                index,
+                self.values.len()
+            )
+        })
+    }
+
+    /// The value of a column as any type with a conversion, like
+    /// `row.get::<Option<String>>(1)`
+    pub fn get<T: FromSql>(&self, index: usize) -> Result<T> {
+        T::from_sql(self.value(index)?).map_err(|e| self.conversion_error(index, e))
+    }
+
+    pub fn is_null(&self, index: usize) -> Result<bool> {
+        Ok(matches!(self.value(index)?, Value::Null))
+    }
+
+    pub fn get_i64(&self, index: usize) -> Result<i64> {
+        self.get(index)
+    }
+
+    /// Integers are converted
+    pub fn get_f64(&self, index: usize) -> Result<f64> {
+        self.get(index)
+    }
+
+    /// Borrowed, where `get::<String>` copies
+    pub fn get_str(&self, index: usize) -> Result<&str> {
+        match self.value(index)? {
+            Value::Text(s) => Ok(s),
+            value => Err(self.wrong_type(index, "text", value)),
+        }
+    }
+
+    /// Borrowed, where `get::<Vec<u8>>` copies
+    pub fn get_blob(&self, index: usize) -> Result<&[u8]> {
+        match self.value(index)? {
+            Value::Blob(b) => Ok(b),
+            value => Err(self.wrong_type(index, "blob", value)),
+        }
+    }
+
+    fn wrong_type(&self, index: usize, expected: &'static str, value: &Value) -> anyhow::Error {
+        let error = FromSqlError::InvalidType {
+            expected,
+            actual: value.type_name(),
+        };
+        self.conversion_error(index, error)
+    }
+
+    fn conversion_error(&self, index: usize, error: FromSqlError) -> anyhow::Error {
+        anyhow!("column {} ({}): {}", index, self.columns[index], error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_databases_are_created_by_the_first_write() {
+        let path = std::env::temp_dir().join(format!("missing-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let connection = Connection::open(path).unwrap();
+        let error = connection
+            .prepare("SELECT a FROM t")
+            .unwrap()
+            .query(&[])
+            .err();
+        let read_created = FileSystem.exists(path).unwrap();
+        connection.execute("CREATE TABLE t(a)", &[]).unwrap();
+        connection.execute("INSERT INTO t VALUES (1)", &[]).unwrap();
+        drop(connection);
+
+        let connection = Connection::open(path).unwrap();
+        let rows = connection
+            .prepare("SELECT a FROM t")
+            .unwrap()
+            .query(&[])
+            .unwrap();
+        let rows = rows.map(|row| row.values().iter().join("|")).join(",");
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(error.unwrap().to_string(), "no table named t");
+        assert!(!read_created);
+        assert_eq!(rows, "1");
+    }
+
+    #[test]
+    fn memory_connections_are_separate() {
+        let a = Connection::open(":memory:").unwrap();
+        let b = Connection::open(":memory:").unwrap();
+        a.execute("CREATE TABLE t(a)", &[]).unwrap();
+        a.execute("INSERT INTO t VALUES (1)", &[]).unwrap();
+        b.execute("CREATE TABLE t(a)", &[]).unwrap();
+
+        let count = |connection: &Connection| {
+            let rows = connection.prepare("SELECT a FROM t").unwrap().query(&[]);
+            rows.unwrap().count()
+        };
+        assert_eq!((count(&a), count(&b)), (1, 0));
+        assert!(!FileSystem.exists(":memory:").unwrap());
+    }
+}
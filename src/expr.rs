@@ -0,0 +1,261 @@
+use std::cmp::Ordering;
+
+use anyhow::{anyhow, Result};
+
+use crate::format::{Affinity, RecordSerial, TableDefinition};
+
+/// The expressions of WHERE clauses and UPDATE assignments
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Literal(RecordSerial),
+    /// A placeholder, by index from 1
+    Parameter(usize),
+    Column(String),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    IsNull(Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Concat,
+}
+
+impl Expr {
+    pub fn binary(left: Expr, op: BinaryOp, right: Expr) -> Self {
+        Expr::Binary(Box::new(left), op, Box::new(right))
+    }
+
+    /// Replaces placeholders with their values, by index from 1
+    pub fn bind(&mut self, values: &[RecordSerial]) {
+        match self {
+            Expr::Parameter(i) => {
+                let value = values.get(*i - 1).cloned().unwrap_or(RecordSerial::Null);
+                *self = Expr::Literal(value);
+            }
+            Expr::Not(expr) | Expr::Negate(expr) | Expr::IsNull(expr) => expr.bind(values),
+            Expr::Binary(left, _, right) => {
+                left.bind(values);
+                right.bind(values);
+            }
+            Expr::Literal(_) | Expr::Column(_) => {}
+        }
+    }
+
+    /// Names of the columns the expression reads
+    pub fn columns(&self) -> Vec<&str> {
+        match self {
+            Expr::Column(name) => vec![name],
+            Expr::Not(expr) | Expr::Negate(expr) | Expr::IsNull(expr) => expr.columns(),
+            Expr::Binary(left, _, right) => {
+                let mut columns = left.columns();
+                columns.extend(right.columns());
+                columns
+            }
+            Expr::Literal(_) | Expr::Parameter(_) => vec![],
+        }
+    }
+
+    /// The value of an expression that reads no row, like a VALUES item
+    pub fn constant(&self) -> Result<RecordSerial> {
+        match self {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Parameter(i) => Err(anyhow!("parameter {} is not bound", i)),
+            _ => Err(anyhow!("not a constant: {:?}", self)),
+        }
+    }
+
+    /// The value for one row, `values` in the table's column order
+    pub fn evaluate(
+        &self,
+        table: &TableDefinition,
+        values: &[RecordSerial],
+    ) -> Result<RecordSerial> {
+        Ok(match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Parameter(i) => return Err(anyhow!("parameter {} is not bound", i)),
+            Expr::Column(name) => {
+                let i = table
+                    .column_index(name)
+                    .ok_or_else(|| anyhow!("no such column: {}", name))?;
+                values[i].clone()
+            }
+            Expr::Not(expr) => match truth(&expr.evaluate(table, values)?) {
+                Some(it) => boolean(!it),
+                None => RecordSerial::Null,
+            },
+            Expr::Negate(expr) => match expr.evaluate(table, values)? {
+                RecordSerial::Null => RecordSerial::Null,
+                value => arithmetic(&RecordSerial::I64(0), BinaryOp::Subtract, &value),
+            },
+            Expr::IsNull(expr) => {
+                boolean(matches!(expr.evaluate(table, values)?, RecordSerial::Null))
+            }
+            Expr::Binary(left, op, right) => {
+                let (a, b) = (
+                    left.evaluate(table, values)?,
+                    right.evaluate(table, values)?,
+                );
+                match op {
+                    BinaryOp::Or => match (truth(&a), truth(&b)) {
+                        (Some(true), _) | (_, Some(true)) => boolean(true),
+                        (Some(false), Some(false)) => boolean(false),
+                        _ => RecordSerial::Null,
+                    },
+                    BinaryOp::And => match (truth(&a), truth(&b)) {
+                        (Some(false), _) | (_, Some(false)) => boolean(false),
+                        (Some(true), Some(true)) => boolean(true),
+                        _ => RecordSerial::Null,
+                    },
+                    BinaryOp::Equal
+                    | BinaryOp::NotEqual
+                    | BinaryOp::Less
+                    | BinaryOp::LessOrEqual
+                    | BinaryOp::Greater
+                    | BinaryOp::GreaterOrEqual => {
+                        if matches!(a, RecordSerial::Null) || matches!(b, RecordSerial::Null) {
+                            return Ok(RecordSerial::Null);
+                        }
+                        let (a, b, collation) = comparable(table, left, a, right, b);
+                        let ordering = a.compare(&b, collation.as_deref());
+                        boolean(match op {
+                            BinaryOp::Equal => ordering == Ordering::Equal,
+                            BinaryOp::NotEqual => ordering != Ordering::Equal,
+                            BinaryOp::Less => ordering == Ordering::Less,
+                            BinaryOp::LessOrEqual => ordering != Ordering::Greater,
+                            BinaryOp::Greater => ordering == Ordering::Greater,
+                            _ => ordering != Ordering::Less,
+                        })
+                    }
+                    BinaryOp::Concat => match (&a, &b) {
+                        (RecordSerial::Null, _) | (_, RecordSerial::Null) => RecordSerial::Null,
+                        _ => RecordSerial::String(text(&a) + &text(&b)),
+                    },
+                    _ => arithmetic(&a, *op, &b),
+                }
+            }
+        })
+    }
+
+    /// WHERE keeps a row only when its condition is true, NULL isn't
+    pub fn matches(&self, table: &TableDefinition, values: &[RecordSerial]) -> Result<bool> {
+        Ok(truth(&self.evaluate(table, values)?).unwrap_or(false))
+    }
+}
+
+fn boolean(value: bool) -> RecordSerial {
+    RecordSerial::I64(value as i64)
+}
+
+/// None for NULL, numbers are true when not zero
+fn truth(value: &RecordSerial) -> Option<bool> {
+    match value {
+        RecordSerial::Null => None,
+        other => Some(number(other) != 0.0),
+    }
+}
+
+/// Before comparing, a column's affinity applies to the value on the other side,
+/// and the column's collation is used
+/// See https://www.sqlite.org/datatype3.html#type_conversions_prior_to_comparison
+fn comparable(
+    table: &TableDefinition,
+    left: &Expr,
+    a: RecordSerial,
+    right: &Expr,
+    b: RecordSerial,
+) -> (RecordSerial, RecordSerial, Option<String>) {
+    let column = |expr: &Expr| match expr {
+        Expr::Column(name) => table.column_index(name).map(|i| &table.columns[i]),
+        _ => None,
+    };
+    let collation = column(left)
+        .or(column(right))
+        .and_then(|it| it.collation.clone());
+    let convert = |affinity: Affinity, value: RecordSerial| match affinity {
+        Affinity::Blob => value,
+        // Only numeric affinities apply to text, text affinity only to numbers
+        Affinity::Text if matches!(value, RecordSerial::String(_)) => value,
+        _ if matches!(value, RecordSerial::Blob(_)) => value,
+        affinity => affinity.apply(value),
+    };
+    match (column(left), column(right)) {
+        (Some(column), None) => (a, convert(column.affinity(), b), collation),
+        (None, Some(column)) => (convert(column.affinity(), a), b, collation),
+        _ => (a, b, collation),
+    }
+}
+
+fn text(value: &RecordSerial) -> String {
+    match value {
+        RecordSerial::String(s) => s.clone(),
+        RecordSerial::Blob(b) => String::from_utf8_lossy(b).into_owned(),
+        other => match Affinity::Text.apply(other.clone()) {
+            RecordSerial::String(s) => s,
+            _ => String::new(),
+        },
+    }
+}
+
+/// Text and blobs used as numbers, not a number reads as 0
+fn number(value: &RecordSerial) -> f64 {
+    match Affinity::Numeric.apply(match value {
+        RecordSerial::Blob(b) => RecordSerial::String(String::from_utf8_lossy(b).into_owned()),
+        other => other.clone(),
+    }) {
+        RecordSerial::F64(f) => f,
+        other => other.as_i64().map_or(0.0, |it| it as f64),
+    }
+}
+
+fn arithmetic(a: &RecordSerial, op: BinaryOp, b: &RecordSerial) -> RecordSerial {
+    if matches!(a, RecordSerial::Null) || matches!(b, RecordSerial::Null) {
+        return RecordSerial::Null;
+    }
+    let integer = |value: &RecordSerial| match value {
+        RecordSerial::String(s) => s.trim().parse().ok(),
+        other => other.as_i64(),
+    };
+    if let (Some(x), Some(y)) = (integer(a), integer(b)) {
+        let result = match op {
+            BinaryOp::Add => x.checked_add(y),
+            BinaryOp::Subtract => x.checked_sub(y),
+            BinaryOp::Multiply => x.checked_mul(y),
+            BinaryOp::Divide if y == 0 => return RecordSerial::Null,
+            BinaryOp::Divide => x.checked_div(y),
+            BinaryOp::Remainder if y == 0 => return RecordSerial::Null,
+            _ => x.checked_rem(y),
+        };
+        // Integer overflow falls back to reals
+        if let Some(result) = result {
+            return RecordSerial::I64(result);
+        }
+    }
+    let (x, y) = (number(a), number(b));
+    match op {
+        BinaryOp::Add => RecordSerial::F64(x + y),
+        BinaryOp::Subtract => RecordSerial::F64(x - y),
+        BinaryOp::Multiply => RecordSerial::F64(x * y),
+        BinaryOp::Divide if y == 0.0 => RecordSerial::Null,
+        BinaryOp::Divide => RecordSerial::F64(x / y),
+        // Remainders are taken on the integer parts
+        _ => match (x as i64).checked_rem(y as i64) {
+            Some(it) => RecordSerial::F64(it as f64),
+            None => RecordSerial::Null,
+        },
+    }
+}
@@ -0,0 +1,10 @@
+/// The big-endian u32 at `at`, every integer in the file format, the journal
+/// and the WAL is stored like this
+pub fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// The native-endian u32 at `at`, as the wal-index stores them
+pub fn native_u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
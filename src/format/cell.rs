@@ -1,6 +1,8 @@
+use std::borrow::Cow;
+
 use crate::utils::Varint;
 
-use super::{DatabaseHeader, PageHeader, PageType, Record};
+use super::{DatabaseHeader, PageHeader, PageType, Record, RecordRef};
 
 #[derive(Debug)]
 pub enum Cell {
@@ -22,42 +24,316 @@ pub enum Cell {
     InteriorIndex {
         left_child: u32, // Option<Box<Cell>>
         size: Varint,
-        key: Varint,
+        payload: Record,
         overflow_page: Option<u32>,
     },
 }
 
+/// Reads a whole page by its number, used to follow overflow chains.
+pub type PageLoader<'a> = dyn FnMut(u32) -> Result<Vec<u8>, &'static str> + 'a;
+
 impl Cell {
     pub fn from_bytes(
         page_buf: &[u8],
         position: u64,
         page_header: &PageHeader,
         db_header: &DatabaseHeader,
+        load_page: &mut PageLoader,
     ) -> Result<Self, &'static str> {
+        let position = position as usize;
+        if position >= page_buf.len() {
+            return Err("Cell pointer out of page bounds");
+        }
         match page_header.kind {
             PageType::LeafTable => {
-                let size = Varint::from_bytes(&page_buf[(position as usize)..]);
+                let size = Varint::from_bytes(&page_buf[position..]);
                 let mut padding = size.size as usize;
-                let row_id = Varint::from_bytes(&page_buf[(position as usize + padding)..]);
+                let row_id = Varint::from_bytes(&page_buf[(position + padding)..]);
                 padding += row_id.size as usize;
-                let payload = Record::from_bytes(&page_buf, padding + position as usize, db_header)?;
-                // println!("TODO: overflow page");
+                let (payload, overflow_page) = Cell::read_payload(
+                    page_buf,
+                    position + padding,
+                    size.value as usize,
+                    &page_header.kind,
+                    db_header,
+                    load_page,
+                )?;
                 Ok(Cell::LeafTable {
                     size,
                     payload,
                     row_id,
-                    overflow_page: Option::None,
+                    overflow_page,
                 })
             }
             PageType::InteriorTable => {
-                unimplemented!()
+                let left_child = read_u32(page_buf, position)?;
+                let key = Varint::from_bytes(&page_buf[(position + 4)..]);
+                Ok(Cell::InteriorTable { left_child, key })
             }
             PageType::LeafIndex => {
-                unimplemented!()
+                let size = Varint::from_bytes(&page_buf[position..]);
+                let (payload, overflow_page) = Cell::read_payload(
+                    page_buf,
+                    position + size.size as usize,
+                    size.value as usize,
+                    &page_header.kind,
+                    db_header,
+                    load_page,
+                )?;
+                Ok(Cell::LeafIndex {
+                    size,
+                    payload,
+                    overflow_page,
+                })
             }
             PageType::InteriorIndex => {
-                unimplemented!()
+                let left_child = read_u32(page_buf, position)?;
+                let size = Varint::from_bytes(&page_buf[(position + 4)..]);
+                let (payload, overflow_page) = Cell::read_payload(
+                    page_buf,
+                    position + 4 + size.size as usize,
+                    size.value as usize,
+                    &page_header.kind,
+                    db_header,
+                    load_page,
+                )?;
+                Ok(Cell::InteriorIndex {
+                    left_child,
+                    size,
+                    payload,
+                    overflow_page,
+                })
+            }
+        }
+    }
+
+    pub fn left_child(&self) -> Option<u32> {
+        match self {
+            Cell::InteriorTable { left_child, .. } | Cell::InteriorIndex { left_child, .. } => {
+                Some(*left_child)
             }
+            _ => None,
         }
     }
+
+    pub fn payload(&self) -> Option<&Record> {
+        match self {
+            Cell::LeafTable { payload, .. }
+            | Cell::LeafIndex { payload, .. }
+            | Cell::InteriorIndex { payload, .. } => Some(payload),
+            Cell::InteriorTable { .. } => None,
+        }
+    }
+
+    /// How many bytes of a payload are stored on the b-tree page itself,
+    /// the rest spills into overflow pages.
+    /// See https://www.sqlite.org/fileformat.html#cell_payload
+    pub fn local_payload_size(payload_size: usize, usable_size: usize, kind: &PageType) -> usize {
+        let max_local = match kind {
+            PageType::LeafTable | PageType::InteriorTable => usable_size - 35,
+            _ => ((usable_size - 12) * 64 / 255) - 23,
+        };
+        if payload_size <= max_local {
+            return payload_size;
+        }
+        let min_local = ((usable_size - 12) * 32 / 255) - 23;
+        let k = min_local + ((payload_size - min_local) % (usable_size - 4));
+        if k <= max_local {
+            k
+        } else {
+            min_local
+        }
+    }
+
+    /// Bytes the cell at `position` takes on its page: its header, the local
+    /// part of the payload and the overflow page number
+    pub fn size_on_page(
+        page_buf: &[u8],
+        position: usize,
+        kind: &PageType,
+        usable_size: usize,
+    ) -> Result<usize, &'static str> {
+        let varint = |at: usize| {
+            page_buf
+                .get(at..)
+                .filter(|it| !it.is_empty())
+                .map(Varint::from_bytes)
+                .ok_or("Cell out of page bounds")
+        };
+        let (header, payload_size) = match kind {
+            PageType::InteriorTable => {
+                let key = varint(position + 4)?;
+                return Ok(4 + key.size as usize);
+            }
+            PageType::LeafTable => {
+                let size = varint(position)?;
+                let row_id = varint(position + size.size as usize)?;
+                (
+                    size.size as usize + row_id.size as usize,
+                    size.value as usize,
+                )
+            }
+            PageType::LeafIndex => {
+                let size = varint(position)?;
+                (size.size as usize, size.value as usize)
+            }
+            PageType::InteriorIndex => {
+                let size = varint(position + 4)?;
+                (4 + size.size as usize, size.value as usize)
+            }
+        };
+        let local = Cell::local_payload_size(payload_size, usable_size, kind);
+        let overflow_pointer = if local < payload_size { 4 } else { 0 };
+        // Cells are never smaller than 4 bytes, so they can become freeblocks
+        Ok((header + local + overflow_pointer).max(4))
+    }
+
+    /// Reads the cell at `position` without decoding its record. The
+    /// payload is borrowed from the page unless it spills into overflow pages
+    pub fn read_raw<'a>(
+        page_buf: &'a [u8],
+        position: usize,
+        kind: &PageType,
+        db_header: &DatabaseHeader,
+        load_page: &mut PageLoader,
+    ) -> Result<RawCell<'a>, &'static str> {
+        if position >= page_buf.len() {
+            return Err("Cell pointer out of page bounds");
+        }
+        let (left_child, header) = match kind {
+            PageType::InteriorTable | PageType::InteriorIndex => {
+                (Some(read_u32(page_buf, position)?), position + 4)
+            }
+            PageType::LeafTable | PageType::LeafIndex => (None, position),
+        };
+        let varint = |at: usize| {
+            page_buf
+                .get(at..)
+                .filter(|it| !it.is_empty())
+                .map(Varint::from_bytes)
+                .ok_or("Cell out of page bounds")
+        };
+        match kind {
+            PageType::InteriorTable => Ok(RawCell {
+                left_child,
+                row_id: Some(varint(header)?.value),
+                payload: None,
+            }),
+            PageType::LeafTable => {
+                let size = varint(header)?;
+                let row_id = varint(header + size.size as usize)?;
+                let start = header + size.size as usize + row_id.size as usize;
+                let (payload, _) = Cell::payload_bytes(
+                    page_buf,
+                    start,
+                    size.value as usize,
+                    kind,
+                    db_header,
+                    load_page,
+                )?;
+                Ok(RawCell {
+                    left_child,
+                    row_id: Some(row_id.value),
+                    payload: Some(payload),
+                })
+            }
+            PageType::LeafIndex | PageType::InteriorIndex => {
+                let size = varint(header)?;
+                let (payload, _) = Cell::payload_bytes(
+                    page_buf,
+                    header + size.size as usize,
+                    size.value as usize,
+                    kind,
+                    db_header,
+                    load_page,
+                )?;
+                Ok(RawCell {
+                    left_child,
+                    row_id: None,
+                    payload: Some(payload),
+                })
+            }
+        }
+    }
+
+    fn read_payload(
+        page_buf: &[u8],
+        position: usize,
+        payload_size: usize,
+        kind: &PageType,
+        db_header: &DatabaseHeader,
+        load_page: &mut PageLoader,
+    ) -> Result<(Record, Option<u32>), &'static str> {
+        let (bytes, overflow_page) =
+            Cell::payload_bytes(page_buf, position, payload_size, kind, db_header, load_page)?;
+        let payload = RecordRef::parse(&bytes, db_header)?.to_record()?;
+        Ok((payload, overflow_page))
+    }
+
+    /// The payload at `position` and its first overflow page. Only a payload
+    /// that overflows has to be copied, to put its pieces together
+    fn payload_bytes<'a>(
+        page_buf: &'a [u8],
+        position: usize,
+        payload_size: usize,
+        kind: &PageType,
+        db_header: &DatabaseHeader,
+        load_page: &mut PageLoader,
+    ) -> Result<(Cow<'a, [u8]>, Option<u32>), &'static str> {
+        let usable_size = db_header.usable_size();
+        let local = Cell::local_payload_size(payload_size, usable_size, kind);
+        if local == payload_size {
+            let end = (position + payload_size).min(page_buf.len());
+            let payload = page_buf.get(position..end).unwrap_or_default();
+            return Ok((Cow::Borrowed(payload), None));
+        }
+
+        let local_end = position + local;
+        let first_overflow = read_u32(page_buf, local_end)?;
+        let mut bytes = page_buf
+            .get(position..local_end)
+            .ok_or("Cell payload out of page bounds")?
+            .to_vec();
+
+        // Each overflow page starts with the next page number (0 at the end)
+        let mut next = first_overflow;
+        while bytes.len() < payload_size {
+            if next == 0 {
+                return Err("Overflow chain ended early");
+            }
+            let page = load_page(next)?;
+            next = read_u32(&page, 0)?;
+            let take = (payload_size - bytes.len()).min(usable_size - 4);
+            bytes.extend_from_slice(page.get(4..4 + take).ok_or("Overflow page too small")?);
+        }
+        Ok((Cow::Owned(bytes), Some(first_overflow)))
+    }
+}
+
+/// A cell as [`Cell::read_raw`] finds it, with its record still encoded
+#[derive(Debug)]
+pub struct RawCell<'a> {
+    pub left_child: Option<u32>,
+    /// The rowid of table leaves, the key of interior table cells
+    pub row_id: Option<i64>,
+    pub payload: Option<Cow<'a, [u8]>>,
+}
+
+impl RawCell<'_> {
+    /// Parses the record header, values are decoded as they are read
+    pub fn record(
+        &self,
+        db_header: &DatabaseHeader,
+    ) -> Result<Option<RecordRef<'_>>, &'static str> {
+        self.payload
+            .as_deref()
+            .map(|it| RecordRef::parse(it, db_header))
+            .transpose()
+    }
+}
+
+fn read_u32(buf: &[u8], position: usize) -> Result<u32, &'static str> {
+    buf.get(position..position + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or("Cell out of page bounds")
 }
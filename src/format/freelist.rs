@@ -0,0 +1,67 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+
+use super::{u32_at, SqliteFile};
+
+/// Unused pages, chained from the header through trunk pages that each
+/// list a batch of leaf pages.
+/// See https://www.sqlite.org/fileformat.html#the_freelist
+#[derive(Debug, Default)]
+pub struct Freelist {
+    pub trunks: Vec<u32>,
+    pub leaves: Vec<u32>,
+}
+
+impl Freelist {
+    pub fn read(db: &mut SqliteFile) -> Result<Self> {
+        let page_count = db.page_count()? as u32;
+        let max_leaves = (db.header.usable_size() / 4 - 2) as u32;
+        let mut freelist = Freelist::default();
+        let mut seen = HashSet::new();
+        let mut check = |page_number: u32| -> Result<()> {
+            if page_number == 0 || page_number > page_count {
+                bail!("invalid page number {}", page_number);
+            }
+            if !seen.insert(page_number) {
+                bail!("2nd reference to page {}", page_number);
+            }
+            Ok(())
+        };
+
+        let mut trunk = db.header.first_free_list_trunk;
+        while trunk != 0 {
+            check(trunk)?;
+            let buf = db.read_page_ref(trunk as u64)?;
+            let leaves = u32_at(&buf, 4);
+            if leaves > max_leaves {
+                bail!("freelist leaf count too big on page {}", trunk);
+            }
+            for i in 0..leaves as usize {
+                let leaf = u32_at(&buf, 8 + i * 4);
+                check(leaf)?;
+                freelist.leaves.push(leaf);
+            }
+            freelist.trunks.push(trunk);
+            trunk = u32_at(&buf, 0);
+        }
+        Ok(freelist)
+    }
+
+    /// Every free page, trunks included
+    pub fn pages(&self) -> HashSet<u32> {
+        self.trunks
+            .iter()
+            .chain(self.leaves.iter())
+            .copied()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.trunks.len() + self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trunks.is_empty()
+    }
+}
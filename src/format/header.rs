@@ -1,17 +1,29 @@
 use std::fmt::Display;
 
-#[derive(Debug)]
+use super::u32_at;
+
+#[derive(Debug, Clone, Copy)]
 pub enum TextEncoding {
     UTF8 = 1,
     UTF16LE = 2,
     UTF16BE = 3,
 }
 
-#[derive(Debug)]
+impl Display for TextEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UTF8 => write!(f, "utf8"),
+            Self::UTF16LE => write!(f, "utf16le"),
+            Self::UTF16BE => write!(f, "utf16be"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct SQLiteVersion {
     pub x: u8,
     pub y: u8,
-    pub z: u8
+    pub z: u8,
 }
 
 impl Display for SQLiteVersion {
@@ -21,19 +33,24 @@ impl Display for SQLiteVersion {
 }
 
 impl SQLiteVersion {
+    /// The version number as stored, e.g. 3034000 for 3.34.0
+    pub fn number(&self) -> u32 {
+        self.x as u32 * 1000000 + self.y as u32 * 1000 + self.z as u32
+    }
+
     fn parse(buf: [u8; 4]) -> Self {
         let version = u32::from_be_bytes(buf);
         SQLiteVersion {
             x: (version / 1000000) as u8,
-            y: ((version % 1000000) / 1000) as u8 ,
-            z: (version % 1000) as u8
+            y: ((version % 1000000) / 1000) as u8,
+            z: (version % 1000) as u8,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DatabaseHeader {
-    pub page_size: u16,
+    pub page_size: u32,
     pub write_version: u8,
     pub read_version: u8,
     pub page_reserved_bytes: u8, // most of the time 0,
@@ -46,7 +63,8 @@ pub struct DatabaseHeader {
     pub free_list_count: u32,
     pub schema_cookie: u32,
     pub schema_format_number: u32,
-    pub default_page_cache_size: u32,
+    /// Signed, negative for KiB like `cache_size`
+    pub default_page_cache_size: i32,
     pub largest_root_btree_page: u32,
     pub text_encoding: TextEncoding,
     pub user_version: u32,
@@ -57,42 +75,142 @@ pub struct DatabaseHeader {
     pub sqlite_version: SQLiteVersion,
 }
 
+/// `PRAGMA auto_vacuum`, which pages are given back to the file system
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AutoVacuum {
+    /// Free pages stay in the file for reuse
+    #[default]
+    None = 0,
+    /// Every commit truncates the file
+    Full = 1,
+    /// Only `PRAGMA incremental_vacuum` truncates it
+    Incremental = 2,
+}
+
 impl DatabaseHeader {
-    pub fn from_bytes(buf: &[u8; 100]) -> Self {
-        // TODO: im lazy
-        DatabaseHeader {
-            page_size: u16::from_be_bytes([buf[16], buf[17]]),
+    /// Page size minus the reserved space at the end of every page
+    pub fn usable_size(&self) -> usize {
+        self.page_size as usize - self.page_reserved_bytes as usize
+    }
+
+    /// The page holding the lock bytes at 1GB, never used for anything
+    pub fn pending_byte_page(&self) -> u32 {
+        0x4000_0000 / self.page_size + 1
+    }
+
+    /// Auto-vacuum files have a largest root page, and pointer map pages
+    pub fn auto_vacuum(&self) -> AutoVacuum {
+        match (self.largest_root_btree_page, self.incremental_vacuum_mode) {
+            (0, _) => AutoVacuum::None,
+            (_, false) => AutoVacuum::Full,
+            (_, true) => AutoVacuum::Incremental,
+        }
+    }
+
+    pub fn is_auto_vacuum(&self) -> bool {
+        self.largest_root_btree_page != 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; 100] {
+        let mut buf = [0; 100];
+        let mut put_u32 =
+            |i: usize, value: u32| buf[i..i + 4].copy_from_slice(&value.to_be_bytes());
+        put_u32(24, self.file_change_counter);
+        put_u32(28, self.pages_count);
+        put_u32(32, self.first_free_list_trunk);
+        put_u32(36, self.free_list_count);
+        put_u32(40, self.schema_cookie);
+        put_u32(44, self.schema_format_number);
+        put_u32(48, self.default_page_cache_size as u32);
+        put_u32(52, self.largest_root_btree_page);
+        put_u32(56, self.text_encoding as u32);
+        put_u32(60, self.user_version);
+        put_u32(64, self.incremental_vacuum_mode as u32);
+        put_u32(68, self.application_id);
+        put_u32(92, self.version_valid_for);
+        put_u32(96, self.sqlite_version.number());
+
+        buf[..16].copy_from_slice(b"SQLite format 3\0");
+        let page_size = if self.page_size == 65536 {
+            1
+        } else {
+            self.page_size as u16
+        };
+        buf[16..18].copy_from_slice(&page_size.to_be_bytes());
+        buf[18] = self.write_version;
+        buf[19] = self.read_version;
+        buf[20] = self.page_reserved_bytes;
+        buf[21] = self.maximum_embedded_payload_fraction;
+        buf[22] = self.minimum_embedded_payload_fraction;
+        buf[23] = self.leaf_payload_fraction;
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; 100]) -> Result<Self, &'static str> {
+        // 1 means 65536, which doesn't fit in two bytes
+        let page_size = match u16::from_be_bytes([buf[16], buf[17]]) {
+            1 => 65536,
+            n => n as u32,
+        };
+        if !page_size.is_power_of_two() || !(512..=65536).contains(&page_size) {
+            return Err("page size must be a power of two between 512 and 65536");
+        }
+        Ok(DatabaseHeader {
+            page_size,
             write_version: buf[18],
             read_version: buf[19],
             page_reserved_bytes: buf[20], // most of the time 0,
             maximum_embedded_payload_fraction: buf[21],
             minimum_embedded_payload_fraction: buf[22],
             leaf_payload_fraction: buf[23], // = 32
-            file_change_counter: u32::from_be_bytes([buf[24], buf[25], buf[26], buf[27]]),
-            pages_count: u32::from_be_bytes([buf[28], buf[29], buf[30], buf[31]]),
-            // first_free_list_trunk: buf[0..8].try_into().unwrap(),
-            first_free_list_trunk: 0,
-            free_list_count: 0,
-            schema_cookie: 0,
-            schema_format_number: 0,
-            default_page_cache_size: 0,
-            largest_root_btree_page: 0,
-            text_encoding: match u32::from_be_bytes([buf[56], buf[57], buf[58], buf[59]]) {
-                1 => TextEncoding::UTF8,
+            file_change_counter: u32_at(buf, 24),
+            pages_count: u32_at(buf, 28),
+            first_free_list_trunk: u32_at(buf, 32),
+            free_list_count: u32_at(buf, 36),
+            schema_cookie: u32_at(buf, 40),
+            schema_format_number: u32_at(buf, 44),
+            default_page_cache_size: u32_at(buf, 48) as i32,
+            largest_root_btree_page: u32_at(buf, 52),
+            text_encoding: match u32_at(buf, 56) {
+                // 0 until the first table is created
+                0 | 1 => TextEncoding::UTF8,
                 2 => TextEncoding::UTF16LE,
                 3 => TextEncoding::UTF16BE,
-                n => {
-                    println!("Found text_encoding:{} Fallback to utf8 (or should i panic)", n);
-                    TextEncoding::UTF8
-                }
+                _ => return Err("unknown text encoding"),
             },
-            user_version: 0,
-            incremental_vacuum_mode: false, // 4 bytes
-            application_id: 0,
+            user_version: u32_at(buf, 60),
+            incremental_vacuum_mode: u32_at(buf, 64) != 0, // 4 bytes
+            application_id: u32_at(buf, 68),
             // reserved 72+20
-            version_valid_for: 0,
+            version_valid_for: u32_at(buf, 92),
             sqlite_version: SQLiteVersion::parse(buf[96..100].try_into().unwrap()),
-        
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_sizes_are_powers_of_two_from_512() {
+        let mut buf = [0; 100];
+        for (bytes, page_size) in [
+            ([2, 0], Some(512)),
+            ([0, 1], Some(65536)),
+            ([0x80, 0], Some(32768)),
+        ] {
+            buf[16..18].copy_from_slice(&bytes);
+            let header = DatabaseHeader::from_bytes(&buf).ok();
+            assert_eq!(header.map(|it| it.page_size), page_size);
+        }
+        for bytes in [[0, 0], [1, 0], [3, 0xe8], [0xff, 0xff]] {
+            buf[16..18].copy_from_slice(&bytes);
+            let error = DatabaseHeader::from_bytes(&buf).err();
+            assert_eq!(
+                error,
+                Some("page size must be a power of two between 512 and 65536")
+            );
         }
     }
-}
\ No newline at end of file
+}
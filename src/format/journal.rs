@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use super::{u32_at, PageSource, Vfs};
+
+pub const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+
+/// The start of every segment of a `-journal` file, padded to a sector
+/// See https://www.sqlite.org/fileformat.html#the_rollback_journal
+#[derive(Debug, Clone)]
+pub struct JournalHeader {
+    /// u32::MAX means "up to the end of the file"
+    pub page_count: u32,
+    pub nonce: u32,
+    /// Size of the database in pages before the transaction started
+    pub initial_size: u32,
+    pub sector_size: u32,
+    pub page_size: u32,
+}
+
+impl JournalHeader {
+    /// `None` when the magic is missing, a zeroed header means a finished transaction
+    pub fn from_bytes(buf: &[u8; 28]) -> Option<Self> {
+        if buf[..8] != JOURNAL_MAGIC {
+            return None;
+        }
+        Some(JournalHeader {
+            page_count: u32_at(buf, 8),
+            nonce: u32_at(buf, 12),
+            initial_size: u32_at(buf, 16),
+            sector_size: u32_at(buf, 20),
+            page_size: u32_at(buf, 24),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; 28] {
+        let mut buf = [0; 28];
+        buf[..8].copy_from_slice(&JOURNAL_MAGIC);
+        buf[8..12].copy_from_slice(&self.page_count.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.nonce.to_be_bytes());
+        buf[16..20].copy_from_slice(&self.initial_size.to_be_bytes());
+        buf[20..24].copy_from_slice(&self.sector_size.to_be_bytes());
+        buf[24..28].copy_from_slice(&self.page_size.to_be_bytes());
+        buf
+    }
+}
+
+/// Checksum of a journaled page: the nonce plus every 200th byte from the end
+pub fn journal_checksum(page: &[u8], nonce: u32) -> u32 {
+    let mut checksum = nonce;
+    let mut i = page.len() as i64 - 200;
+    while i > 0 {
+        checksum = checksum.wrapping_add(page[i as usize] as u32);
+        i -= 200;
+    }
+    checksum
+}
+
+/// Writes the original pages of a transaction to a new `-journal`, one at
+/// a time as they're read. The records are synced before the header counts
+/// them, so a torn write is never taken for a complete journal
+pub fn write_journal(
+    vfs: &dyn Vfs,
+    path: &str,
+    header: &JournalHeader,
+    pages: impl IntoIterator<Item = Result<(u32, Vec<u8>)>>,
+) -> Result<()> {
+    let mut file = vfs.open(path, true)?;
+    file.set_size(0)?;
+    let mut buf = vec![0; header.sector_size as usize];
+    buf[..28].copy_from_slice(
+        &JournalHeader {
+            page_count: 0,
+            ..header.clone()
+        }
+        .to_bytes(),
+    );
+    file.write_at(0, &buf)?;
+    let mut offset = buf.len() as u64;
+    let mut page_count: u32 = 0;
+    for page in pages {
+        let (page_number, page) = page?;
+        let mut record = Vec::with_capacity(page.len() + 8);
+        record.extend_from_slice(&page_number.to_be_bytes());
+        record.extend_from_slice(&page);
+        record.extend_from_slice(&journal_checksum(&page, header.nonce).to_be_bytes());
+        file.write_at(offset, &record)?;
+        offset += record.len() as u64;
+        page_count += 1;
+    }
+    file.sync()?;
+
+    file.write_at(8, &page_count.to_be_bytes())?;
+    file.sync()?;
+    Ok(())
+}
+
+/// A hot journal: original copies of pages that an unfinished transaction
+/// may have overwritten in the database file
+pub struct Journal {
+    file: Box<dyn PageSource>,
+    pub header: JournalHeader,
+    /// Offset of the original content of each page, the first copy wins
+    pages: HashMap<u32, u64>,
+}
+
+impl Journal {
+    /// `None` if there is no journal or it doesn't hold a transaction to undo
+    pub fn open(vfs: &dyn Vfs, path: &str) -> Result<Option<Self>> {
+        if !vfs.exists(path)? {
+            return Ok(None);
+        }
+        let mut file = vfs.open(path, false)?;
+        if file.size()? < 28 {
+            return Ok(None);
+        }
+        let mut header_buf = [0; 28];
+        file.read_at(0, &mut header_buf)?;
+        let Some(header) = JournalHeader::from_bytes(&header_buf) else {
+            return Ok(None);
+        };
+        if !header.sector_size.is_power_of_two()
+            || !(512..=65536).contains(&header.sector_size)
+            || !header.page_size.is_power_of_two()
+            || !(512..=65536).contains(&header.page_size)
+        {
+            bail!("hot journal {} has an invalid header", path);
+        }
+
+        let mut journal = Journal {
+            file,
+            header,
+            pages: HashMap::new(),
+        };
+        journal.read_records()?;
+        Ok(Some(journal))
+    }
+
+    /// Walks every segment, stopping at the first record with a bad checksum
+    /// since it was never fully written
+    fn read_records(&mut self) -> Result<()> {
+        let journal_size = self.file.size()?;
+        let sector_size = self.header.sector_size as u64;
+        let page_size = self.header.page_size as u64;
+        let record_size = 4 + page_size + 4;
+        let mut segment_start = 0;
+        let mut header = self.header.clone();
+        let mut page = vec![0; page_size as usize];
+
+        loop {
+            let mut offset = segment_start + sector_size;
+            let records = match header.page_count {
+                u32::MAX => journal_size.saturating_sub(offset) / record_size,
+                n => n as u64,
+            };
+            for _ in 0..records {
+                let mut page_number = [0; 4];
+                let mut checksum = [0; 4];
+                if self.file.read_at(offset, &mut page_number).is_err()
+                    || self.file.read_at(offset + 4, &mut page).is_err()
+                    || self
+                        .file
+                        .read_at(offset + 4 + page_size, &mut checksum)
+                        .is_err()
+                {
+                    return Ok(());
+                }
+                if journal_checksum(&page, header.nonce) != u32::from_be_bytes(checksum) {
+                    return Ok(());
+                }
+                let page_number = u32::from_be_bytes(page_number);
+                // Pages past the original end are cut off anyway
+                if page_number != 0 && page_number <= self.header.initial_size {
+                    self.pages.entry(page_number).or_insert(offset + 4);
+                }
+                offset += record_size;
+            }
+
+            // The next segment starts on a sector boundary
+            segment_start = offset.div_ceil(sector_size) * sector_size;
+            let mut header_buf = [0; 28];
+            if self.file.read_at(segment_start, &mut header_buf).is_err() {
+                return Ok(());
+            }
+            match JournalHeader::from_bytes(&header_buf) {
+                Some(next) if records > 0 => header = next,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// The content of a page from before the transaction, if it was journaled
+    pub fn read_page(&mut self, page_number: u32) -> Result<Option<Vec<u8>>> {
+        let Some(offset) = self.pages.get(&page_number) else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; self.header.page_size as usize];
+        self.file.read_at(*offset, &mut buf)?;
+        Ok(Some(buf))
+    }
+
+    pub fn contains(&self, page_number: u32) -> bool {
+        self.pages.contains_key(&page_number)
+    }
+
+    /// Journaled page numbers, in no particular order
+    pub fn page_numbers(&self) -> impl Iterator<Item = u32> + '_ {
+        self.pages.keys().copied()
+    }
+}
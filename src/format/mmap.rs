@@ -0,0 +1,104 @@
+use std::{fs::File, io};
+
+/// The start of a file mapped read-only, so committed pages are read
+/// without a system call. Writes to the file show up in the mapping, a file
+/// cut shorter makes reading past its end crash with SIGBUS, like with
+/// SQLite's `mmap_size`. Bytes are only copied out, never lent, so that
+/// can't happen to a slice still borrowed once the file shrinks
+pub(crate) struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl Mmap {
+    /// Maps the first `len` bytes, which must be in the file
+    ///
+    /// # Safety
+    ///
+    /// Nothing may truncate that part of the file, in this process or
+    /// another, while `read` copies from it
+    pub unsafe fn map(file: &File, len: usize) -> io::Result<Self> {
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't map an empty range",
+            ));
+        }
+        let ptr = sys::map(file, len)?;
+        Ok(Mmap { ptr, len })
+    }
+
+    /// Copies the bytes at `offset` into `buf`, false when they're not all
+    /// mapped
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> bool {
+        if offset
+            .checked_add(buf.len())
+            .is_none_or(|end| end > self.len)
+        {
+            return false;
+        }
+        // The mapping lives until drop and is never written through
+        unsafe { std::ptr::copy_nonoverlapping(self.ptr.add(offset), buf.as_mut_ptr(), buf.len()) };
+        true
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        sys::unmap(self.ptr, self.len);
+    }
+}
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+mod sys {
+    use std::{ffi::c_void, fs::File, io, os::unix::io::AsRawFd, ptr};
+
+    const PROT_READ: i32 = 1;
+    const MAP_SHARED: i32 = 1;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            offset: i64,
+        ) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+
+    pub fn map(file: &File, len: usize) -> io::Result<*const u8> {
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ,
+                MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        // MAP_FAILED is -1
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ptr as *const u8)
+    }
+
+    pub fn unmap(ptr: *const u8, len: usize) {
+        unsafe { munmap(ptr as *mut c_void, len) };
+    }
+}
+
+/// Elsewhere pages are always read from the file
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+mod sys {
+    use std::{fs::File, io};
+
+    pub fn map(_file: &File, _len: usize) -> io::Result<*const u8> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn unmap(_ptr: *const u8, _len: usize) {}
+}
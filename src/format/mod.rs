@@ -1,13 +1,32 @@
+mod bytes;
 mod cell;
+mod freelist;
 mod header;
+mod journal;
+mod mmap;
 mod page;
+mod page_cache;
+mod ptrmap;
 mod record;
 mod sqlite_file;
 mod table;
+mod value;
+mod vfs;
+mod wal;
+mod wal_index;
 
+pub use bytes::*;
 pub use cell::*;
+pub use freelist::*;
 pub use header::*;
+pub use journal::*;
 pub use page::*;
+pub use page_cache::*;
+pub use ptrmap::*;
 pub use record::*;
 pub use sqlite_file::*;
-pub use table::*;
\ No newline at end of file
+pub use table::*;
+pub use value::*;
+pub use vfs::*;
+pub use wal::*;
+pub use wal_index::*;
\ No newline at end of file
@@ -1,6 +1,9 @@
-use super::{cell::Cell, DatabaseHeader};
+use super::{
+    cell::{Cell, PageLoader},
+    u32_at, DatabaseHeader,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PageType {
     InteriorIndex = 0x02,
     InteriorTable = 0x05,
@@ -23,44 +26,46 @@ pub struct PageHeader {
 pub struct Page {
     pub header: PageHeader,
     pub cell_pointers: Vec<u16>,
-     // content: &'a [u8],
+    // content: &'a [u8],
     // pub raw: &'a [u8],
     pub cells: Vec<Cell>,
 }
 
 impl Page {
-    pub fn from_bytes(buf: &[u8], db_header: &DatabaseHeader) -> Page {
-        Page::from_bytes_with_padding(&buf, db_header, 0)
-    }
-
-    pub fn from_bytes_with_padding(
+    pub fn from_bytes_with_overflow(
         buf: &[u8],
         db_header: &DatabaseHeader,
         padding: usize,
-    ) -> Page {
-        let header = PageHeader::from_bytes(&buf[padding..]);
-        let cell_pointers = Page::parse_cell_pointer_array(&buf, &header, padding);
+        load_page: &mut PageLoader,
+    ) -> Result<Page, &'static str> {
+        let header = PageHeader::from_bytes(&buf[padding..])?;
+        let cell_pointers = Page::parse_cell_pointer_array(buf, &header, padding)?;
         let cells = cell_pointers
             .iter()
-            .map(|cell: &u16| {
-                Cell::from_bytes(&buf, cell.to_owned().into(), &header, db_header).unwrap()
-            })
-            .collect();
+            .map(|cell: &u16| Cell::from_bytes(buf, (*cell).into(), &header, db_header, load_page))
+            .collect::<Result<_, _>>()?;
 
-        Page {
+        Ok(Page {
             header,
             cell_pointers,
             // raw: &buf,
-            cells
-        }
+            cells,
+        })
     }
 
-    fn parse_cell_pointer_array(buf: &[u8], header: &PageHeader, page_padding: usize) -> Vec<u16> {
-        let padding = page_padding
-            + match &header.kind {
-                PageType::InteriorIndex | &PageType::InteriorTable => 12,
-                _ => 8,
-            };
+    pub fn is_leaf(&self) -> bool {
+        matches!(self.header.kind, PageType::LeafIndex | PageType::LeafTable)
+    }
+
+    pub fn parse_cell_pointer_array(
+        buf: &[u8],
+        header: &PageHeader,
+        page_padding: usize,
+    ) -> Result<Vec<u16>, &'static str> {
+        let padding = page_padding + header.size();
+        if padding + header.number_of_cells as usize * 2 > buf.len() {
+            return Err("Cell pointer array out of page bounds");
+        }
 
         let mut out = Vec::<u16>::new();
         for i in 0..(header.number_of_cells as usize) {
@@ -69,18 +74,52 @@ impl Page {
                 buf[i * 2 + 1 + padding],
             ]));
         }
-        out
+        Ok(out)
+    }
+
+    /// Follows the freeblock chain, offsets are from the start of the page
+    pub fn parse_freeblocks(
+        buf: &[u8],
+        header: &PageHeader,
+    ) -> Result<Vec<Freeblock>, &'static str> {
+        let mut out = Vec::<Freeblock>::new();
+        let mut offset = header.first_freeblock;
+        while offset != 0 {
+            let position = offset as usize;
+            let bytes = buf
+                .get(position..position + 4)
+                .ok_or("Freeblock out of page bounds")?;
+            let next = u16::from_be_bytes([bytes[0], bytes[1]]);
+            let size = u16::from_be_bytes([bytes[2], bytes[3]]);
+            out.push(Freeblock { offset, size });
+            // The chain must be in increasing order, which also stops loops
+            if next != 0 && next <= offset {
+                return Err("Freeblock list is not in ascending order");
+            }
+            offset = next;
+        }
+        Ok(out)
     }
 }
 
+/// An unused chunk between cells, linked from the page header
+#[derive(Debug)]
+pub struct Freeblock {
+    pub offset: u16,
+    pub size: u16,
+}
+
 impl PageHeader {
-    pub fn from_bytes(page_buf: &[u8]) -> Self {
+    pub fn from_bytes(page_buf: &[u8]) -> Result<Self, &'static str> {
+        if page_buf.len() < 12 {
+            return Err("Page too small for a b-tree header");
+        }
         let kind: PageType = match &page_buf[0] {
             0x02 => PageType::InteriorIndex,
             0x05 => PageType::InteriorTable,
             0x0a => PageType::LeafIndex,
             0x0d => PageType::LeafTable,
-            _ => panic!("Invalid Sqlite"),
+            _ => return Err("Not a b-tree page"),
         };
 
         let first_freeblock = u16::from_be_bytes([page_buf[1], page_buf[2]]);
@@ -88,22 +127,25 @@ impl PageHeader {
         let first_cell_content = u16::from_be_bytes([page_buf[5], page_buf[6]]);
         let fragmented_free_bytes = page_buf[7];
         let page_number = match kind {
-            PageType::InteriorIndex | PageType::InteriorTable => Some(u32::from_be_bytes([
-                page_buf[8],
-                page_buf[9],
-                page_buf[10],
-                page_buf[11],
-            ])),
+            PageType::InteriorIndex | PageType::InteriorTable => Some(u32_at(page_buf, 8)),
             _ => None,
         };
 
-        PageHeader {
+        Ok(PageHeader {
             kind,
             first_freeblock,
             number_of_cells,
             first_cell_content,
             fragmented_free_bytes,
             page_number,
+        })
+    }
+
+    /// 12 bytes for interior pages because of the right-most pointer, 8 for leaves
+    pub fn size(&self) -> usize {
+        match self.kind {
+            PageType::InteriorIndex | PageType::InteriorTable => 12,
+            _ => 8,
         }
     }
 }
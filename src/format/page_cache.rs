@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// SQLite's default `cache_size`, negative for KiB: 2000 KiB of pages
+pub const DEFAULT_CACHE_SIZE: i64 = -2000;
+
+/// Committed pages kept in memory, the least recently used one goes first
+/// when it's full. Uncommitted pages never get here, they're dirty
+#[derive(Debug, Default)]
+pub struct PageCache {
+    capacity: usize,
+    /// Page bytes and when they were last used
+    pages: HashMap<u32, (Vec<u8>, u64)>,
+    /// Page numbers by last use, the first one is evicted
+    recent: BTreeMap<u64, u32>,
+    clock: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// How well the cache does, see `SqliteFile::cache_stats`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub pages: usize,
+    pub capacity: usize,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> Self {
+        PageCache {
+            capacity,
+            ..PageCache::default()
+        }
+    }
+
+    /// Pages a `cache_size` holds: a page count, or KiB when negative
+    pub fn capacity_for(cache_size: i64, page_size: u32) -> usize {
+        if cache_size >= 0 {
+            cache_size as usize
+        } else {
+            (cache_size.unsigned_abs() * 1024 / page_size as u64) as usize
+        }
+    }
+
+    /// Counts a hit, making the page the most recently used, or a miss
+    pub fn hit(&mut self, page_number: u32) -> bool {
+        let Some((_, used)) = self.pages.get_mut(&page_number) else {
+            self.misses += 1;
+            return false;
+        };
+        self.hits += 1;
+        self.clock += 1;
+        self.recent.remove(used);
+        self.recent.insert(self.clock, page_number);
+        *used = self.clock;
+        true
+    }
+
+    pub fn page(&self, page_number: u32) -> Option<&[u8]> {
+        self.pages.get(&page_number).map(|it| &it.0[..])
+    }
+
+    pub fn insert(&mut self, page_number: u32, page: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(page_number);
+        self.clock += 1;
+        self.recent.insert(self.clock, page_number);
+        self.pages.insert(page_number, (page, self.clock));
+        self.evict();
+    }
+
+    pub fn remove(&mut self, page_number: u32) {
+        if let Some((_, used)) = self.pages.remove(&page_number) {
+            self.recent.remove(&used);
+        }
+    }
+
+    /// Forgets the pages past `page_count`, after the file got shorter
+    pub fn truncate(&mut self, page_count: u32) {
+        let cut = self
+            .pages
+            .keys()
+            .copied()
+            .filter(|it| *it > page_count)
+            .collect::<Vec<_>>();
+        for page_number in cut {
+            self.remove(page_number);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.recent.clear();
+    }
+
+    pub fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.pages.len() > self.capacity {
+            let Some((_, page_number)) = self.recent.pop_first() else {
+                break;
+            };
+            self.pages.remove(&page_number);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            pages: self.pages.len(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::{CreateOptions, MemoryVfs},
+        testing::{create, execute, query_one},
+    };
+
+    #[test]
+    fn the_least_recently_used_page_goes_first() {
+        let mut cache = PageCache::new(2);
+        cache.insert(1, vec![1]);
+        cache.insert(2, vec![2]);
+        assert!(cache.hit(1));
+        cache.insert(3, vec![3]);
+        assert_eq!(cache.page(1), Some(&[1][..]));
+        assert_eq!(cache.page(2), None);
+        assert_eq!(cache.page(3), Some(&[3][..]));
+
+        // Inserting a page again only makes it the most recent
+        cache.insert(1, vec![4]);
+        cache.insert(5, vec![5]);
+        assert_eq!(cache.page(1), Some(&[4][..]));
+        assert_eq!(cache.page(3), None);
+        assert_eq!(cache.stats().pages, 2);
+    }
+
+    #[test]
+    fn lookups_count_hits_and_misses() {
+        let mut cache = PageCache::new(10);
+        assert!(!cache.hit(1));
+        cache.insert(1, vec![1]);
+        assert!(cache.hit(1));
+        assert!(cache.hit(1));
+        cache.remove(1);
+        assert!(!cache.hit(1));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+
+        // Nothing is kept without room
+        let mut cache = PageCache::new(0);
+        cache.insert(1, vec![1]);
+        assert!(!cache.hit(1));
+    }
+
+    #[test]
+    fn cache_size_is_pages_or_kib() {
+        assert_eq!(PageCache::capacity_for(100, 4096), 100);
+        assert_eq!(PageCache::capacity_for(DEFAULT_CACHE_SIZE, 4096), 500);
+        assert_eq!(PageCache::capacity_for(-1, 65536), 0);
+    }
+
+    #[test]
+    fn pragma_cache_size_resizes_the_cache() {
+        let vfs = MemoryVfs::default();
+        let options = CreateOptions {
+            page_size: 1024,
+            ..CreateOptions::default()
+        };
+        let mut db = create(&vfs, &options);
+        execute(&mut db, "CREATE TABLE t(a)").unwrap();
+        let sql = format!("INSERT INTO t VALUES ('{}')", "x".repeat(500));
+        for _ in 0..100 {
+            execute(&mut db, &sql).unwrap();
+        }
+        assert_eq!(query_one(&mut db, "PRAGMA cache_size"), "-2000");
+        assert_eq!(db.cache_stats().capacity, 2000);
+        query_one(&mut db, "SELECT COUNT(*) FROM t");
+        let pages = db.cache_stats().pages;
+        assert!(pages > 10);
+
+        execute(&mut db, "PRAGMA cache_size = 10").unwrap();
+        assert_eq!(query_one(&mut db, "PRAGMA cache_size"), "10");
+        assert_eq!(db.cache_stats().pages, 10);
+        query_one(&mut db, "SELECT COUNT(*) FROM t");
+        assert_eq!(db.cache_stats().pages, 10);
+
+        // KiB of pages
+        execute(&mut db, "PRAGMA cache_size = -5").unwrap();
+        assert_eq!(db.cache_stats().capacity, 5);
+        assert_eq!(db.cache_stats().pages, 5);
+    }
+}
@@ -0,0 +1,74 @@
+use super::{u32_at, DatabaseHeader};
+
+/// What a page is used for in an auto-vacuum database, so it can be moved
+/// by fixing up the one page pointing to it.
+/// See https://www.sqlite.org/fileformat.html#pointer_map_or_ptrmap_pages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PtrmapType {
+    RootPage = 1,
+    FreePage = 2,
+    /// First page of an overflow chain, the parent is the b-tree page of the cell
+    Overflow1 = 3,
+    /// Later page of an overflow chain, the parent is the previous one
+    Overflow2 = 4,
+    BTree = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PtrmapEntry {
+    pub kind: PtrmapType,
+    /// 0 for root and free pages
+    pub parent: u32,
+}
+
+impl PtrmapEntry {
+    pub fn new(kind: PtrmapType, parent: u32) -> Self {
+        PtrmapEntry { kind, parent }
+    }
+
+    /// `None` for an unknown type, like the zeroes of a page never mapped
+    pub fn from_bytes(buf: &[u8; 5]) -> Option<Self> {
+        let kind = match buf[0] {
+            1 => PtrmapType::RootPage,
+            2 => PtrmapType::FreePage,
+            3 => PtrmapType::Overflow1,
+            4 => PtrmapType::Overflow2,
+            5 => PtrmapType::BTree,
+            _ => return None,
+        };
+        let parent = u32_at(buf, 1);
+        Some(PtrmapEntry { kind, parent })
+    }
+
+    pub fn to_bytes(self) -> [u8; 5] {
+        let mut buf = [0; 5];
+        buf[0] = self.kind as u8;
+        buf[1..].copy_from_slice(&self.parent.to_be_bytes());
+        buf
+    }
+}
+
+/// The pointer map page with the entry of `page_number`. They start at
+/// page 2 and each one covers the pages up to the next.
+pub fn ptrmap_page(header: &DatabaseHeader, page_number: u32) -> u32 {
+    if page_number < 2 {
+        return 0;
+    }
+    let pages_per_map = header.usable_size() as u32 / 5 + 1;
+    let page = (page_number - 2) / pages_per_map * pages_per_map + 2;
+    // The lock byte page can't hold anything, the map moves past it
+    if page == header.pending_byte_page() {
+        page + 1
+    } else {
+        page
+    }
+}
+
+pub fn is_ptrmap_page(header: &DatabaseHeader, page_number: u32) -> bool {
+    page_number >= 2 && ptrmap_page(header, page_number) == page_number
+}
+
+/// Where the entry of `page_number` is on its pointer map page
+pub fn ptrmap_offset(header: &DatabaseHeader, page_number: u32) -> usize {
+    (page_number - ptrmap_page(header, page_number) - 1) as usize * 5
+}
@@ -1,11 +1,11 @@
-use std::fmt::Display;
+use std::{borrow::Cow, cmp::Ordering, fmt::Display};
 
 use itertools::Itertools;
 
-use super::{DatabaseHeader, TextEncoding};
+use super::{DatabaseHeader, TextEncoding, ValueRef};
 use crate::utils::Varint;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum RecordSerial {
     Null,
     I8(i8),
@@ -43,156 +43,398 @@ impl Display for RecordSerial {
     }
 }
 
-#[derive(Debug)]
+impl RecordSerial {
+    /// Integers take the smallest type that holds them, text and blobs
+    /// carry their size in bytes
+    pub fn serial_type(&self, content_size: usize) -> i64 {
+        match self {
+            Self::Null | Self::Reserved1 | Self::Reserved2 => 0,
+            Self::F64(_) => 7,
+            Self::Blob(_) => content_size as i64 * 2 + 12,
+            Self::String(_) => content_size as i64 * 2 + 13,
+            other => match other.as_i64().unwrap_or_default() {
+                0 => 8,
+                1 => 9,
+                i if i8::try_from(i).is_ok() => 1,
+                i if i16::try_from(i).is_ok() => 2,
+                i if (-(1 << 23)..(1 << 23)).contains(&i) => 3,
+                i if i32::try_from(i).is_ok() => 4,
+                i if (-(1 << 47)..(1 << 47)).contains(&i) => 5,
+                _ => 6,
+            },
+        }
+    }
+
+    /// The bytes of the value in the record body
+    pub fn encode_content(&self, encoding: &TextEncoding) -> Vec<u8> {
+        match self {
+            Self::F64(f) => f.to_be_bytes().to_vec(),
+            Self::Blob(b) => b.clone(),
+            Self::String(s) => match encoding {
+                TextEncoding::UTF8 => s.as_bytes().to_vec(),
+                TextEncoding::UTF16LE => s.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+                TextEncoding::UTF16BE => s.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            },
+            Self::Null | Self::Reserved1 | Self::Reserved2 => vec![],
+            other => {
+                let i = other.as_i64().unwrap_or_default();
+                let size = RecordSerial::content_size(self.serial_type(0));
+                i.to_be_bytes()[8 - size..].to_vec()
+            }
+        }
+    }
+
+    /// Bytes taken in the record body by a value of this serial type
+    pub fn content_size(serial_type: i64) -> usize {
+        match serial_type {
+            0 | 8..=11 => 0,
+            1..=4 => serial_type as usize,
+            5 => 6,
+            6 | 7 => 8,
+            n if n % 2 == 0 => ((n - 12) / 2) as usize,
+            n => ((n - 13) / 2) as usize,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::I8(i) => Some(*i as i64),
+            Self::I16(i) => Some(*i as i64),
+            Self::I24(i) | Self::I32(i) => Some(*i as i64),
+            Self::I48(i) | Self::I64(i) => Some(*i),
+            Self::Zero => Some(0),
+            Self::One => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Orders values the way SQLite sorts index keys: NULL, numbers, text, blobs.
+    /// Text uses the named collation, unknown ones fall back to BINARY.
+    pub fn compare(&self, other: &Self, collation: Option<&str>) -> Ordering {
+        fn class(value: &RecordSerial) -> u8 {
+            match value {
+                RecordSerial::Null | RecordSerial::Reserved1 | RecordSerial::Reserved2 => 0,
+                RecordSerial::String(_) => 2,
+                RecordSerial::Blob(_) => 3,
+                _ => 1,
+            }
+        }
+
+        match (self, other) {
+            (Self::String(a), Self::String(b)) => match collation {
+                Some(c) if c.eq_ignore_ascii_case("nocase") => a
+                    .to_ascii_lowercase()
+                    .as_bytes()
+                    .cmp(b.to_ascii_lowercase().as_bytes()),
+                Some(c) if c.eq_ignore_ascii_case("rtrim") => {
+                    a.trim_end_matches(' ').cmp(b.trim_end_matches(' '))
+                }
+                _ => a.as_bytes().cmp(b.as_bytes()),
+            },
+            (Self::Blob(a), Self::Blob(b)) => a.cmp(b),
+            (a, b) if class(a) == 1 && class(b) == 1 => match (a.as_i64(), b.as_i64()) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                _ => {
+                    let float = |v: &RecordSerial| match v {
+                        RecordSerial::F64(f) => *f,
+                        other => other.as_i64().unwrap_or_default() as f64,
+                    };
+                    float(a).partial_cmp(&float(b)).unwrap_or(Ordering::Equal)
+                }
+            },
+            (a, b) => class(a).cmp(&class(b)),
+        }
+    }
+
+    /// The value as a SQL literal that reads back to the same value
+    pub fn to_sql_literal(&self) -> String {
+        match self {
+            Self::Null | Self::Reserved1 | Self::Reserved2 => "NULL".to_string(),
+            Self::F64(f) if f.is_nan() => "NULL".to_string(),
+            Self::F64(f) if f.is_infinite() => {
+                if *f > 0.0 { "9.0e+999" } else { "-9.0e+999" }.to_string()
+            }
+            // Debug keeps the `.0` and switches to exponents for large numbers,
+            // and both are the shortest form that round-trips
+            Self::F64(f) => format!("{f:?}"),
+            Self::Blob(b) => format!(
+                "X'{}'",
+                b.iter().map(|byte| format!("{byte:02x}")).join("")
+            ),
+            Self::String(s) => format!("'{}'", s.replace('\'', "''")),
+            other => other.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Record {
     pub header_size: Varint,
+    pub serial_types: Vec<i64>,
     pub content: Vec<RecordSerial>,
 }
 
 impl Record {
+    /// Builds the on-disk record: a header of serial types, then the values
+    pub fn encode(values: &[RecordSerial], encoding: &TextEncoding) -> Vec<u8> {
+        let mut header = vec![];
+        let mut body = vec![];
+        for value in values {
+            let content = value.encode_content(encoding);
+            Varint::write(value.serial_type(content.len()), &mut header);
+            body.extend(content);
+        }
+
+        // The header size counts its own varint
+        let mut header_size = header.len() as i64 + 1;
+        while Varint::from_value(header_size).size as i64 + header.len() as i64 != header_size {
+            header_size = Varint::from_value(header_size).size as i64 + header.len() as i64;
+        }
+        let mut out = vec![];
+        Varint::write(header_size, &mut out);
+        out.extend(header);
+        out.extend(body);
+        out
+    }
+
     pub fn from_bytes(
         buf: &[u8],
         position: usize,
         db_header: &DatabaseHeader,
     ) -> Result<Self, &'static str> {
-        let lenght: usize = buf.len();
-        let header_size: Varint = Varint::from_bytes(&buf[position..]);
-
-        let mut header_current = position + header_size.size as usize;
-        let mut current = position + header_size.value as usize;
-        let mut content = Vec::<RecordSerial>::new();
-
-        // Read header from {padding} to {header_size}
-        while header_current < header_size.value as usize + position {
-            let serial_code: Varint = Varint::from_bytes(&buf[header_current..]);
-            header_current += serial_code.size as usize;
-            let (consumed, record_serial) = match serial_code.value {
-                0 => (0, RecordSerial::Null),
-                1 => (1, RecordSerial::I8(i8::from_be_bytes([buf[current]]))),
-                2 => (
-                    2,
-                    RecordSerial::I16(i16::from_be_bytes([buf[current], buf[current + 1]])),
-                ),
-                3 => (
-                    3,
-                    RecordSerial::I24(i32::from_be_bytes([
-                        0,
-                        buf[current],
-                        buf[current + 1],
-                        buf[current + 2],
-                    ])),
-                ),
-                4 => (
-                    4,
-                    RecordSerial::I32(i32::from_be_bytes([
-                        buf[current],
-                        buf[current + 1],
-                        buf[current + 2],
-                        buf[current + 3],
-                    ])),
-                ),
-                5 => (
-                    6,
-                    RecordSerial::I48(i64::from_be_bytes([
-                        0,
-                        0,
-                        buf[current],
-                        buf[current + 1],
-                        buf[current + 2],
-                        buf[current + 3],
-                        buf[current + 4],
-                        buf[current + 5],
-                    ])),
-                ),
-                6 => (
-                    8,
-                    RecordSerial::I64(i64::from_be_bytes([
-                        buf[current],
-                        buf[current + 1],
-                        buf[current + 2],
-                        buf[current + 3],
-                        buf[current + 4],
-                        buf[current + 5],
-                        buf[current + 6],
-                        buf[current + 7],
-                    ])),
-                ),
-
-                7 => (
-                    8,
-                    RecordSerial::F64(f64::from_be_bytes([
-                        buf[current],
-                        buf[current + 1],
-                        buf[current + 2],
-                        buf[current + 3],
-                        buf[current + 4],
-                        buf[current + 5],
-                        buf[current + 6],
-                        buf[current + 7],
-                    ])),
-                ),
-
-                8 => (0, RecordSerial::Zero),
-                9 => (0, RecordSerial::One),
-                10 => (0, RecordSerial::Reserved1),
-                11 => (0, RecordSerial::Reserved2),
-                n if n % 2 == 0 => {
-                    let size = (n - 12) / 2;
-                    (
-                        size,
-                        RecordSerial::Blob(buf[current..(current + size as usize)].into()),
-                    )
-                }
-                n => {
-                    let size = (n - 13) / 2;
-                    let bytes = buf[current..(current + size as usize)].to_vec();
-                    match &db_header.text_encoding {
-                        TextEncoding::UTF8 => match String::from_utf8(bytes) {
-                            Result::Ok(string) => (size, RecordSerial::String(string)),
-                            Result::Err(_) => return Err("Invalid utf8 string"),
-                        },
-                        TextEncoding::UTF16BE => {
-                            let bytes = bytes
-                                .iter()
-                                .chunks(2)
-                                .into_iter()
-                                .map(|b| {
-                                    let b: Vec<_> = b.collect();
-                                    // might panic
-                                    u16::from_be_bytes([*b[0], *b[1]])
-                                })
-                                .collect_vec();
-                            match String::from_utf16(&bytes) {
-                                Result::Ok(string) => (size, RecordSerial::String(string)),
-                                Result::Err(_) => return Err("Invalid utf8 string"),
-                            }
-                        }
-                        TextEncoding::UTF16LE => {
-                            let bytes = bytes
-                                .iter()
-                                .chunks(2)
-                                .into_iter()
-                                .map(|b| {
-                                    let b: Vec<_> = b.collect();
-                                    // might panic
-                                    u16::from_le_bytes([*b[0], *b[1]])
-                                })
-                                .collect_vec();
-                            match String::from_utf16(&bytes) {
-                                Result::Ok(string) => (size, RecordSerial::String(string)),
-                                Result::Err(_) => return Err("Invalid utf8 string"),
-                            }
-                        }
-                    }
-                }
-            };
-            // println!("Record serial {:#?}", record_serial);
-            current += consumed as usize;
-            content.push(record_serial);
+        RecordRef::parse(buf.get(position..).unwrap_or_default(), db_header)?.to_record()
+    }
+}
+
+/// A record read in place: only the header is parsed, values are decoded
+/// one at a time when asked for, with text and blobs borrowed from `buf`
+#[derive(Debug, Clone)]
+pub struct RecordRef<'a> {
+    buf: &'a [u8],
+    encoding: TextEncoding,
+    header_size: Varint,
+    /// Serial type and offset in `buf` of each value
+    columns: Vec<(i64, usize)>,
+}
+
+impl<'a> RecordRef<'a> {
+    /// `buf` starts with the record, the payload of a cell
+    pub fn parse(buf: &'a [u8], db_header: &DatabaseHeader) -> Result<Self, &'static str> {
+        let header_size = Varint::from_bytes(buf);
+        let header_end = header_size.value as usize;
+        if header_size.size == 0 || header_end > buf.len() {
+            return Err("Record header out of bounds");
         }
-        Ok(Record {
+
+        let mut header_current = header_size.size as usize;
+        let mut current = header_end;
+        let mut columns = vec![];
+        while header_current < header_end {
+            let serial_type = Varint::from_bytes(&buf[header_current..header_end]);
+            header_current += serial_type.size as usize;
+            if serial_type.value < 0 {
+                return Err("Invalid serial type");
+            }
+            let size = RecordSerial::content_size(serial_type.value);
+            if current.saturating_add(size) > buf.len() {
+                return Err("Record content out of bounds");
+            }
+            columns.push((serial_type.value, current));
+            current += size;
+        }
+        Ok(RecordRef {
+            buf,
+            encoding: db_header.text_encoding,
             header_size,
-            content,
+            columns,
         })
     }
+
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    pub fn serial_type(&self, index: usize) -> Option<i64> {
+        self.columns.get(index).map(|it| it.0)
+    }
+
+    /// The bytes of a value in the record body, empty past the last one
+    fn content(&self, index: usize) -> (i64, &'a [u8]) {
+        match self.columns.get(index) {
+            Some(&(serial_type, offset)) => {
+                let size = RecordSerial::content_size(serial_type);
+                (serial_type, &self.buf[offset..offset + size])
+            }
+            None => (0, &[]),
+        }
+    }
+
+    /// One value, borrowed. Values past the end of a record are NULL, like
+    /// columns added after it was written
+    pub fn value(&self, index: usize) -> Result<ValueRef<'a>, &'static str> {
+        let (serial_type, bytes) = self.content(index);
+        Ok(match serial_type {
+            0 | 10 | 11 => ValueRef::Null,
+            1..=6 => ValueRef::Integer(integer(bytes)),
+            7 => ValueRef::Real(f64::from_be_bytes(bytes.try_into().unwrap_or_default())),
+            8 => ValueRef::Integer(0),
+            9 => ValueRef::Integer(1),
+            n if n % 2 == 0 => ValueRef::Blob(bytes),
+            _ => ValueRef::Text(text(bytes, &self.encoding)?),
+        })
+    }
+
+    /// One value, copied with its exact serial type
+    pub fn serial(&self, index: usize) -> Result<RecordSerial, &'static str> {
+        let (serial_type, bytes) = self.content(index);
+        Ok(match serial_type {
+            0 => RecordSerial::Null,
+            1 => RecordSerial::I8(integer(bytes) as i8),
+            2 => RecordSerial::I16(integer(bytes) as i16),
+            3 => RecordSerial::I24(integer(bytes) as i32),
+            4 => RecordSerial::I32(integer(bytes) as i32),
+            5 => RecordSerial::I48(integer(bytes)),
+            6 => RecordSerial::I64(integer(bytes)),
+            7 => RecordSerial::F64(f64::from_be_bytes(bytes.try_into().unwrap_or_default())),
+            8 => RecordSerial::Zero,
+            9 => RecordSerial::One,
+            10 => RecordSerial::Reserved1,
+            11 => RecordSerial::Reserved2,
+            n if n % 2 == 0 => RecordSerial::Blob(bytes.to_vec()),
+            _ => RecordSerial::String(text(bytes, &self.encoding)?.into_owned()),
+        })
+    }
+
+    /// Every value decoded and copied
+    pub fn to_record(&self) -> Result<Record, &'static str> {
+        Ok(Record {
+            header_size: self.header_size.clone(),
+            serial_types: self.columns.iter().map(|it| it.0).collect(),
+            content: (0..self.len()).map(|i| self.serial(i)).try_collect()?,
+        })
+    }
+}
+
+/// Big-endian two's complement in 1 to 8 bytes
+fn integer(bytes: &[u8]) -> i64 {
+    let mut buf = [0; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    // shifting back keeps the sign
+    i64::from_be_bytes(buf) >> (64 - 8 * bytes.len())
+}
+
+/// UTF-8 is borrowed as it is, UTF-16 has to be converted
+fn text<'a>(bytes: &'a [u8], encoding: &TextEncoding) -> Result<Cow<'a, str>, &'static str> {
+    let units = |from: fn([u8; 2]) -> u16| {
+        let units = bytes
+            .chunks_exact(2)
+            .map(|b| from([b[0], b[1]]))
+            .collect_vec();
+        String::from_utf16(&units)
+            .map(Cow::Owned)
+            .map_err(|_| "Invalid utf16 string")
+    };
+    match encoding {
+        TextEncoding::UTF8 => std::str::from_utf8(bytes)
+            .map(Cow::Borrowed)
+            .map_err(|_| "Invalid utf8 string"),
+        TextEncoding::UTF16LE => units(u16::from_le_bytes),
+        TextEncoding::UTF16BE => units(u16::from_be_bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(encoding: TextEncoding) -> DatabaseHeader {
+        let mut buf = [0; 100];
+        buf[16..18].copy_from_slice(&4096u16.to_be_bytes());
+        buf[59] = encoding as u8;
+        DatabaseHeader::from_bytes(&buf).unwrap()
+    }
+
+    fn round_trip(values: &[RecordSerial], encoding: TextEncoding) -> Vec<RecordSerial> {
+        let bytes = Record::encode(values, &encoding);
+        let record = Record::from_bytes(&bytes, 0, &header(encoding)).unwrap();
+        record.content
+    }
+
+    fn literals(values: &[RecordSerial]) -> Vec<String> {
+        values.iter().map(RecordSerial::to_sql_literal).collect()
+    }
+
+    #[test]
+    fn integers_take_the_smallest_type() {
+        let values = [
+            0,
+            1,
+            -1,
+            0x7f,
+            0x80,
+            -0x8000,
+            1 << 23,
+            -(1 << 31),
+            1 << 40,
+            (1 << 56) - 1,
+            1 << 56,
+            i64::MIN,
+            i64::MAX,
+        ]
+        .map(RecordSerial::I64);
+        let read = round_trip(&values, TextEncoding::UTF8);
+        assert_eq!(
+            read.iter().map(|it| it.as_i64().unwrap()).collect_vec(),
+            values.iter().map(|it| it.as_i64().unwrap()).collect_vec()
+        );
+        assert_eq!(
+            read.iter().map(|it| it.serial_type(0)).collect_vec(),
+            [8, 9, 1, 1, 2, 2, 4, 4, 5, 6, 6, 6, 6]
+        );
+    }
+
+    #[test]
+    fn other_values() {
+        let values = [
+            RecordSerial::Null,
+            RecordSerial::F64(-1.5),
+            RecordSerial::String("héllo".to_string()),
+            RecordSerial::String(String::new()),
+            RecordSerial::Blob(vec![0, 1, 0xff]),
+        ];
+        for encoding in [
+            TextEncoding::UTF8,
+            TextEncoding::UTF16LE,
+            TextEncoding::UTF16BE,
+        ] {
+            let read = round_trip(&values, encoding);
+            assert_eq!(literals(&read), literals(&values));
+        }
+    }
+
+    #[test]
+    fn header_size_counts_its_own_varint() {
+        // 127 bytes of serial types make a 128 byte header, which needs a
+        // two byte varint, so the header is 129 bytes
+        let values = vec![RecordSerial::Null; 127];
+        let bytes = Record::encode(&values, &TextEncoding::UTF8);
+        assert_eq!(Varint::from_bytes(&bytes).value, 129);
+        assert_eq!(round_trip(&values, TextEncoding::UTF8).len(), 127);
+
+        let values = vec![RecordSerial::Null; 126];
+        let bytes = Record::encode(&values, &TextEncoding::UTF8);
+        assert_eq!(Varint::from_bytes(&bytes).value, 127);
+    }
+
+    #[test]
+    fn values_past_the_end_are_null() {
+        let bytes = Record::encode(&[RecordSerial::One], &TextEncoding::UTF8);
+        let record = RecordRef::parse(&bytes, &header(TextEncoding::UTF8)).unwrap();
+        assert_eq!(record.value(0).unwrap(), ValueRef::Integer(1));
+        assert_eq!(record.value(3).unwrap(), ValueRef::Null);
+    }
 }
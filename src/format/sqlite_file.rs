@@ -1,52 +1,1285 @@
-use std::{
-    fs::File,
-    io::{self, Read, Seek, SeekFrom},
+use std::{borrow::Cow, collections::BTreeMap, rc::Rc, thread::sleep};
+
+use anyhow::{anyhow, bail, Error, Result};
+use itertools::Itertools;
+
+use super::wal::{BUSY_RETRIES, BUSY_SLEEP};
+use super::{
+    is_ptrmap_page, ptrmap_offset, ptrmap_page, u32_at, write_journal, AutoVacuum, CacheStats,
+    CheckpointMode, CheckpointResult, DatabaseHeader, FileSystem, Freelist, Journal, JournalHeader,
+    Lock, MemoryVfs, Page, PageCache, PageSource, PageType, PtrmapEntry, PtrmapType, SQLiteVersion,
+    Table, TextEncoding, Vfs, Wal, DEFAULT_CACHE_SIZE,
 };
+use crate::utils::{incremental_vacuum, random_u32, scan_table};
+
+/// What to do when a crash left a hot `-journal` next to the database
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HotJournal {
+    /// Fail to open, the file may be half-written
+    Refuse,
+    /// Read the original pages from the journal, leaving both files untouched
+    #[default]
+    Replay,
+    /// Write the original pages back and delete the journal, like SQLite does
+    Rollback,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    pub hot_journal: HotJournal,
+}
 
-use anyhow::{Result, Error};
-use nom::ToUsize;
+/// Header fields of a new database, the rest is what SQLite would pick
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    pub page_size: u32,
+    pub text_encoding: TextEncoding,
+    pub user_version: u32,
+    pub application_id: u32,
+    pub auto_vacuum: AutoVacuum,
+}
 
-use super::{table, DatabaseHeader, Page, Table};
+impl Default for CreateOptions {
+    fn default() -> Self {
+        CreateOptions {
+            page_size: 4096,
+            text_encoding: TextEncoding::UTF8,
+            user_version: 0,
+            application_id: 0,
+            auto_vacuum: AutoVacuum::None,
+        }
+    }
+}
 
 pub struct SqliteFile {
-    file: File,
+    /// The name of the database in its VFS, the journal and WAL are next to it
+    path: String,
+    vfs: Rc<dyn Vfs>,
+    file: Box<dyn PageSource>,
+    /// Original pages from a hot journal, read instead of the database file
+    pub journal: Option<Journal>,
+    /// Pages committed in `<path>-wal` but not checkpointed yet
+    pub wal: Option<Wal>,
     pub header: DatabaseHeader,
-    pub tables: Vec<Table>
+    pub tables: Vec<Table>,
+    /// Pages written since the last commit, read before the file
+    dirty: BTreeMap<u32, Vec<u8>>,
+    /// Page count once the dirty pages are written
+    size: Option<u32>,
+    /// Inside BEGIN, statements leave their pages dirty for COMMIT
+    transaction: bool,
+    /// Page size of the database file, the header has another one while
+    /// VACUUM changes it
+    file_page_size: u32,
+    /// Set by `PRAGMA page_size`, used by the next VACUUM
+    pub pending_page_size: Option<u32>,
+    /// Set by `PRAGMA auto_vacuum` once there are tables, used by the next VACUUM
+    pub pending_auto_vacuum: Option<AutoVacuum>,
+    /// Committed pages, so the ones used often aren't read again
+    cache: PageCache,
+    /// Set by `PRAGMA cache_size`, pages or KiB when negative
+    cache_size: i64,
+    /// Set by `PRAGMA mmap_size`, in bytes
+    mmap_size: u64,
+    /// What to do with a hot journal found when reading starts
+    hot_journal: HotJournal,
+    /// Between `begin_read` and `end_read`, pages read are from one commit.
+    /// The file is locked SHARED meanwhile
+    reading: bool,
+    /// Since the first change, no other connection writes until `commit`
+    /// or `discard`. The file is locked RESERVED meanwhile
+    reserved: bool,
+    /// The file change counter of the commit we read, another connection
+    /// committed once the file has a different one
+    change_counter: u32,
 }
 
-impl<'a> SqliteFile {
+/// The uncommitted state before a statement, to undo only that statement
+pub struct Savepoint {
+    dirty: BTreeMap<u32, Vec<u8>>,
+    size: Option<u32>,
+    header: DatabaseHeader,
+}
+
+/// Written as the version of the last writer, this is the format we produce
+const SQLITE_VERSION: SQLiteVersion = SQLiteVersion {
+    x: 3,
+    y: 45,
+    z: 0,
+};
+
+/// Frames in the WAL after which a commit checkpoints, SQLite's default
+const WAL_AUTOCHECKPOINT: u64 = 1000;
+
+impl SqliteFile {
     pub fn open(path: &str) -> Result<Self> {
-        let mut file = File::open(&path)?;
+        SqliteFile::open_with(path, &OpenOptions::default())
+    }
+
+    /// `:memory:` and `file::memory:` open a new empty database, like `memory`
+    pub fn open_with(path: &str, options: &OpenOptions) -> Result<Self> {
+        if is_memory_path(path) {
+            return SqliteFile::memory(&CreateOptions::default());
+        }
+        SqliteFile::open_vfs(Rc::new(FileSystem), path, options)
+    }
+
+    /// Writes a database with only an empty sqlite_schema, `path` must not exist yet
+    pub fn create(path: &str, options: &CreateOptions) -> Result<Self> {
+        if is_memory_path(path) {
+            return SqliteFile::memory(options);
+        }
+        SqliteFile::create_vfs(Rc::new(FileSystem), path, options)
+    }
+
+    /// A new database only in memory, gone once it's dropped. Each one is
+    /// separate, even with the same name
+    pub fn memory(options: &CreateOptions) -> Result<Self> {
+        let vfs = MemoryVfs::default();
+        SqliteFile::create_vfs(Rc::new(vfs), ":memory:", options)
+    }
+
+    /// A database that's in no VFS, like bytes in memory. It's called
+    /// `name`, its journal is kept in memory
+    pub fn open_source(name: &str, source: Box<dyn PageSource>) -> Result<Self> {
+        let vfs = MemoryVfs::default();
+        SqliteFile::open_file(Rc::new(vfs), name, source, &OpenOptions::default())
+    }
 
-        let mut header_buf: [u8; 100] = [0; 100];
-        file.read_exact(&mut header_buf)?;
-        let header = DatabaseHeader::from_bytes(&header_buf);
+    pub fn create_vfs(vfs: Rc<dyn Vfs>, path: &str, options: &CreateOptions) -> Result<Self> {
+        let page_size = options.page_size;
+        if !page_size.is_power_of_two() || !(512..=65536).contains(&page_size) {
+            bail!("page size must be a power of two between 512 and 65536");
+        }
+        let header = DatabaseHeader {
+            page_size,
+            write_version: 1,
+            read_version: 1,
+            page_reserved_bytes: 0,
+            maximum_embedded_payload_fraction: 64,
+            minimum_embedded_payload_fraction: 32,
+            leaf_payload_fraction: 32,
+            file_change_counter: 1,
+            pages_count: 1,
+            first_free_list_trunk: 0,
+            free_list_count: 0,
+            schema_cookie: 0,
+            schema_format_number: 4,
+            default_page_cache_size: 0,
+            // Auto-vacuum is on from the start, with sqlite_schema as the only root
+            largest_root_btree_page: (options.auto_vacuum != AutoVacuum::None) as u32,
+            text_encoding: options.text_encoding,
+            user_version: options.user_version,
+            incremental_vacuum_mode: options.auto_vacuum == AutoVacuum::Incremental,
+            application_id: options.application_id,
+            version_valid_for: 1,
+            sqlite_version: SQLITE_VERSION,
+        };
 
-        let mut buf: Vec<u8> = vec![0; header.page_size as usize];
-        file.seek(SeekFrom::Start(0))?;
-        file.read_exact(&mut buf)?;
+        // sqlite_schema is an empty leaf after the header, its content starts
+        // at the end of the page (0 for 65536)
+        let mut page = vec![0; page_size as usize];
+        page[..100].copy_from_slice(&header.to_bytes());
+        page[100] = PageType::LeafTable as u8;
+        page[105..107].copy_from_slice(&(page_size as u16).to_be_bytes());
 
-        let tables = Table::from_page(
-            &Page::from_bytes_with_padding(&buf, &header, 100)
-        ).map_err(|e| Error::msg(e))?;
+        if vfs.exists(path)? {
+            bail!("{} already exists", path);
+        }
+        let mut file = vfs.open(path, true)?;
+        file.write_at(0, &page)?;
+        file.sync()?;
+        SqliteFile::open_file(vfs, path, file, &OpenOptions::default())
+    }
+
+    pub fn open_vfs(vfs: Rc<dyn Vfs>, path: &str, options: &OpenOptions) -> Result<Self> {
+        let file = vfs.open(path, false)?;
+        SqliteFile::open_file(vfs, path, file, options)
+    }
+
+    fn open_file(
+        vfs: Rc<dyn Vfs>,
+        path: &str,
+        mut file: Box<dyn PageSource>,
+        options: &OpenOptions,
+    ) -> Result<Self> {
+        lock_wait(&mut *file, Lock::Shared)?;
+        let mut journal = hot_journal(&*vfs, &mut *file, path, options.hot_journal)?;
+
+        let mut header = file_header(&mut *file)?;
+        let change_counter = header.file_change_counter;
+
+        // The page 1 from before the interrupted transaction has the right header
+        if let Some(page) = journal
+            .as_mut()
+            .map(|it| it.read_page(1))
+            .transpose()?
+            .flatten()
+        {
+            header = DatabaseHeader::from_bytes(page[..100].try_into()?).map_err(Error::msg)?;
+        }
 
-        Ok(Self {
+        // A newer page 1 in the WAL has a newer header. Databases in WAL mode
+        // get one even before the first write, like SQLite does
+        let wal_mode = header.read_version == 2 && header.write_version == 2;
+        let mut wal = vfs.open_wal(path, header.page_size, wal_mode)?;
+        if let Some(page) = wal
+            .as_mut()
+            .map(|it| it.read_page(1))
+            .transpose()?
+            .flatten()
+        {
+            header = DatabaseHeader::from_bytes(page[..100].try_into()?).map_err(Error::msg)?;
+        }
+
+        // Like SQLite, the header only suggests a size
+        let cache_size = match header.default_page_cache_size {
+            0 => DEFAULT_CACHE_SIZE,
+            size => (size as i64).abs(),
+        };
+        let mut db = Self {
+            cache: PageCache::new(PageCache::capacity_for(cache_size, header.page_size)),
+            cache_size,
+            mmap_size: 0,
+            path: path.to_string(),
+            vfs,
             file,
+            journal,
+            wal,
+            file_page_size: header.page_size,
             header,
-            tables
-        })
+            tables: vec![],
+            dirty: BTreeMap::new(),
+            size: None,
+            transaction: false,
+            pending_page_size: None,
+            pending_auto_vacuum: None,
+            hot_journal: options.hot_journal,
+            reading: true,
+            reserved: false,
+            change_counter,
+        };
+        db.read_schema()?;
+        db.end_read()?;
+        Ok(db)
+    }
+
+    /// Starts a read transaction, reading starts without one too. No other
+    /// connection writes the file until `end_read`. A hot journal is rolled
+    /// back first, and the cached pages are dropped when another connection
+    /// committed since they were read: the file change counter moved, or in
+    /// WAL mode the wal-index did
+    pub fn begin_read(&mut self) -> Result<()> {
+        if self.reading {
+            return Ok(());
+        }
+        lock_wait(&mut *self.file, Lock::Shared)?;
+        self.reading = true;
+        let result = self.read_latest();
+        if result.is_err() {
+            self.end_read()?;
+        }
+        result
+    }
+
+    fn read_latest(&mut self) -> Result<()> {
+        let mut changed = false;
+        if self.journal.is_none() {
+            self.journal = hot_journal(&*self.vfs, &mut *self.file, &self.path, self.hot_journal)?;
+            changed = self.journal.is_some();
+        }
+        changed |= if let Some(wal) = &mut self.wal {
+            wal.begin_read()?
+        } else if self.journal.is_some() {
+            // A replayed journal is read as it was when the database was opened
+            false
+        } else {
+            file_header(&mut *self.file)?.file_change_counter != self.change_counter
+        };
+        if changed {
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the read transaction, unless there are changes to commit first
+    pub fn end_read(&mut self) -> Result<()> {
+        if self.transaction || !self.dirty.is_empty() {
+            return Ok(());
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.end_read()?;
+        }
+        self.file.lock(Lock::Unlocked)?;
+        self.reading = false;
+        Ok(())
+    }
+
+    /// Reads the header and sqlite_schema again after another connection
+    /// committed, nothing cached is from the latest commit
+    fn reload(&mut self) -> Result<()> {
+        self.cache.clear();
+        self.remap()?;
+        if self.wal.is_none() {
+            let header = file_header(&mut *self.file)?;
+            self.change_counter = header.file_change_counter;
+            self.file_page_size = header.page_size;
+        }
+        let page = self.read_raw_page(1)?;
+        self.header = DatabaseHeader::from_bytes(page[..100].try_into()?).map_err(Error::msg)?;
+        self.set_cache_size(self.cache_size);
+        self.read_schema()
+    }
+
+    /// sqlite_schema is a regular table b-tree rooted at page 1
+    pub fn read_schema(&mut self) -> Result<()> {
+        self.tables = scan_table(self, 1)?
+            .iter()
+            .map(|row| Table::from_schema_record(&row.record))
+            .collect::<Result<_, _>>()
+            .map_err(Error::msg)?;
+        Ok(())
+    }
+
+    /// Bumps the schema cookie so other connections re-read sqlite_schema too
+    pub fn schema_changed(&mut self) -> Result<()> {
+        self.header.schema_cookie = self.header.schema_cookie.wrapping_add(1);
+        self.read_schema()
     }
 
     pub fn read_page(&mut self, page_number: u64) -> Result<Page> {
-        let start = (page_number - 1) * self.header.page_size as u64;
-        let size = self.header.page_size.to_usize();
+        let buf = self.read_raw_page(page_number)?;
+        self.parse_page(page_number, &buf)
+    }
+
+    /// Parses bytes from `read_raw_page`, following overflow chains
+    pub fn parse_page(&mut self, page_number: u64, buf: &[u8]) -> Result<Page> {
         let padding = if page_number == 1 { 100 } else { 0 };
 
-        let mut buf: Vec<u8> = vec![0; size];
-        self.file.seek(SeekFrom::Start(start))?;
-        self.file.read_exact(&mut buf)?;
+        let header = self.header.clone();
+        Page::from_bytes_with_overflow(buf, &header, padding, &mut |n| {
+            self.read_raw_page(n as u64)
+                .map_err(|_| "Failed to read overflow page")
+        })
+        .map_err(Error::msg)
+    }
+
+    /// Number of pages, from the file length since the header's count may be stale
+    pub fn page_count(&self) -> Result<u64> {
+        if let Some(size) = self.size {
+            return Ok(size as u64);
+        }
+        if let Some(journal) = &self.journal {
+            return Ok(journal.header.initial_size as u64);
+        }
+        if let Some(size) = self.wal.as_ref().and_then(|it| it.database_size) {
+            return Ok(size as u64);
+        }
+        Ok(self.file.size()? / self.file_page_size as u64)
+    }
+
+    pub fn cache_size(&self) -> i64 {
+        self.cache_size
+    }
+
+    /// Pages or, when negative, KiB of pages to keep in memory
+    pub fn set_cache_size(&mut self, cache_size: i64) {
+        self.cache_size = cache_size;
+        let capacity = PageCache::capacity_for(cache_size, self.header.page_size);
+        self.cache.resize(capacity);
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn freelist(&mut self) -> Result<Freelist> {
+        Freelist::read(self)
+    }
+
+    /// Page bytes without any parsing, page 1 still includes the database header
+    pub fn read_raw_page(&mut self, page_number: u64) -> Result<Vec<u8>> {
+        Ok(self.read_page_ref(page_number)?.into_owned())
+    }
+
+    /// Like `read_raw_page`, borrowing the page when it's already in memory:
+    /// dirty, cached or in a source held in memory
+    pub fn read_page_ref(&mut self, page_number: u64) -> Result<Cow<'_, [u8]>> {
+        if page_number == 0 {
+            return Err(Error::msg("page 0 does not exist"));
+        }
+        self.begin_read()?;
+        let key = page_number as u32;
+        if self.dirty.contains_key(&key) {
+            return Ok(Cow::Borrowed(&self.dirty[&key]));
+        }
+        // Pages of a source in memory are there already, caching them would
+        // only copy them, and they're neither cache hits nor misses. A newer
+        // version in the journal or the WAL is cached like pages read from
+        // the file. Returning the first lookup would keep `self` borrowed below
+        let logged = self.journal.as_ref().is_some_and(|it| it.contains(key))
+            || self.wal.as_ref().is_some_and(|it| it.contains(key));
+        if !logged && self.memory_page(page_number).is_some() {
+            return Ok(Cow::Borrowed(
+                self.memory_page(page_number).unwrap_or_default(),
+            ));
+        }
+        if self.cache.hit(key) {
+            return Ok(Cow::Borrowed(self.cache.page(key).unwrap_or_default()));
+        }
+        if let Some(page) = self.read_log_page(page_number)? {
+            self.cache.insert(key, page.clone());
+            return Ok(Cow::Owned(page));
+        }
+        let page = self.read_file_page(page_number)?;
+        self.cache.insert(key, page.clone());
+        Ok(Cow::Owned(page))
+    }
+
+    /// A committed page newer than the database file, from a replayed
+    /// journal or from the WAL
+    fn read_log_page(&mut self, page_number: u64) -> Result<Option<Vec<u8>>> {
+        if let Some(page) = self
+            .journal
+            .as_mut()
+            .map(|it| it.read_page(page_number as u32))
+            .transpose()?
+            .flatten()
+        {
+            return Ok(Some(page));
+        }
+        Ok(self
+            .wal
+            .as_mut()
+            .map(|it| it.read_page(page_number as u32))
+            .transpose()?
+            .flatten())
+    }
+
+    /// The page when the database file is held in memory
+    fn memory_page(&self, page_number: u64) -> Option<&[u8]> {
+        let page_size = self.file_page_size as usize;
+        let start = (page_number as usize - 1) * page_size;
+        self.file.bytes()?.get(start..start + page_size)
+    }
+
+    /// The page as it is in the database file, ignoring everything else
+    fn read_file_page(&mut self, page_number: u64) -> Result<Vec<u8>> {
+        if let Some(page) = self.memory_page(page_number) {
+            return Ok(page.to_vec());
+        }
+        let page_size = self.file_page_size as u64;
+        let mut buf: Vec<u8> = vec![0; page_size as usize];
+        self.file.read_at((page_number - 1) * page_size, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn mmap_size(&self) -> u64 {
+        self.mmap_size
+    }
+
+    /// Maps up to `mmap_size` bytes of the file, 0 reads every page with a
+    /// system call. Where mapping isn't supported pages are read anyway
+    pub fn set_mmap_size(&mut self, mmap_size: u64) -> Result<()> {
+        self.mmap_size = mmap_size;
+        self.remap()
+    }
+
+    /// Maps the file again once its size changed
+    fn remap(&mut self) -> Result<()> {
+        self.file.map(self.mmap_size)
+    }
+
+    /// The name of the database in its VFS
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Pages changed since the last commit, they're all in memory
+    pub fn dirty_page_count(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// Where the database, its journal and WAL are
+    pub fn vfs(&self) -> Rc<dyn Vfs> {
+        self.vfs.clone()
+    }
+
+    /// Checks the database can be written, then keeps other writers out
+    /// until `commit` or `discard`. A replayed journal has to be rolled back
+    /// first, and a WAL is only written to in WAL mode. In WAL mode the WAL
+    /// is locked by `commit` only
+    fn begin_write(&mut self) -> Result<()> {
+        if self.journal.is_some() {
+            bail!("{} has a hot journal, roll it back before writing", self.path);
+        }
+        if self.wal.is_some() && self.header.write_version != 2 {
+            bail!("{} has a WAL but isn't in WAL mode", self.path);
+        }
+        if self.wal.is_none() && self.header.write_version == 2 {
+            bail!("{} is in WAL mode, its VFS has no WAL", self.path);
+        }
+        if self.reserved || self.wal.is_some() {
+            return Ok(());
+        }
+        self.begin_read()?;
+        if !self.file.lock(Lock::Reserved)? {
+            bail!("database is locked");
+        }
+        // What we read is stale once another connection committed, the
+        // locks don't stop that where the VFS has none
+        if file_header(&mut *self.file)?.file_change_counter != self.change_counter {
+            self.file.lock(Lock::Shared)?;
+            bail!("database is locked");
+        }
+        self.reserved = true;
+        Ok(())
+    }
 
-        Ok(Page::from_bytes_with_padding(&buf, &self.header, padding))
+    /// Replaces a whole page, kept in memory until `commit`
+    pub fn write_page(&mut self, page_number: u32, buf: Vec<u8>) -> Result<()> {
+        self.begin_write()?;
+        if page_number == 0 || page_number as u64 > self.page_count()? {
+            bail!("page {} is out of range", page_number);
+        }
+        self.dirty.insert(page_number, buf);
+        Ok(())
     }
 
+    /// A zeroed page, off the freelist when it has one, otherwise at the end of the file
+    pub fn allocate_page(&mut self) -> Result<u32> {
+        self.begin_write()?;
+        let page_size = self.header.page_size as usize;
+        let trunk = self.header.first_free_list_trunk;
+        let page_number = if trunk != 0 {
+            let mut buf = self.read_raw_page(trunk as u64)?;
+            let leaves = u32_at(&buf, 4);
+            if leaves > 0 {
+                // Take the last leaf, the trunk stays
+                let leaf = u32_at(&buf, 4 + leaves as usize * 4);
+                buf[4..8].copy_from_slice(&(leaves - 1).to_be_bytes());
+                self.dirty.insert(trunk, buf);
+                leaf
+            } else {
+                // An empty trunk is used itself, the next one takes its place
+                self.header.first_free_list_trunk = u32_at(&buf, 0);
+                trunk
+            }
+        } else {
+            return self.allocate_end();
+        };
+        self.header.free_list_count -= 1;
+        self.dirty.insert(page_number, vec![0; page_size]);
+        Ok(page_number)
+    }
+
+    /// A zeroed page past the end of the file
+    fn allocate_end(&mut self) -> Result<u32> {
+        self.begin_write()?;
+        let page_size = self.header.page_size as usize;
+        // Changing the lock byte page would make SQLite think the file is
+        // locked, it stays zeroed like new pointer map pages
+        // See https://www.sqlite.org/fileformat.html#the_lock_byte_page
+        let mut page_number = self.page_count()? as u32 + 1;
+        while page_number == self.header.pending_byte_page()
+            || (self.header.is_auto_vacuum() && is_ptrmap_page(&self.header, page_number))
+        {
+            self.size = Some(page_number);
+            self.dirty.insert(page_number, vec![0; page_size]);
+            page_number += 1;
+        }
+        self.size = Some(page_number);
+        self.dirty.insert(page_number, vec![0; page_size]);
+        Ok(page_number)
+    }
+
+    /// Puts a page on the freelist, as a leaf of the first trunk while it has room
+    pub fn free_page(&mut self, page_number: u32) -> Result<()> {
+        self.begin_write()?;
+        let page_size = self.header.page_size as usize;
+        let trunk = self.header.first_free_list_trunk;
+        let max_leaves = (self.header.usable_size() / 4 - 2) as u32;
+        self.set_ptrmap_entry(page_number, PtrmapEntry::new(PtrmapType::FreePage, 0))?;
+        self.header.free_list_count += 1;
+        if trunk != 0 {
+            let mut buf = self.read_raw_page(trunk as u64)?;
+            let leaves = u32_at(&buf, 4);
+            if leaves < max_leaves {
+                let at = 8 + leaves as usize * 4;
+                buf[at..at + 4].copy_from_slice(&page_number.to_be_bytes());
+                buf[4..8].copy_from_slice(&(leaves + 1).to_be_bytes());
+                self.dirty.insert(trunk, buf);
+                return Ok(());
+            }
+        }
+        // A full (or missing) trunk, the page becomes the new first trunk
+        let mut buf = vec![0; page_size];
+        buf[..4].copy_from_slice(&trunk.to_be_bytes());
+        self.dirty.insert(page_number, buf);
+        self.header.first_free_list_trunk = page_number;
+        Ok(())
+    }
+
+    /// Takes a specific page, off the freelist or by growing the file. False
+    /// if it's in use
+    pub fn allocate_exact(&mut self, page_number: u32) -> Result<bool> {
+        let page_count = self.page_count()? as u32;
+        if page_number > page_count {
+            // Pages skipped on the way are free
+            let mut skipped = vec![];
+            loop {
+                let next = self.allocate_end()?;
+                if next == page_number {
+                    break;
+                }
+                if next > page_number {
+                    bail!("page {} is reserved", page_number);
+                }
+                skipped.push(next);
+            }
+            for page in skipped {
+                self.free_page(page)?;
+            }
+            return Ok(true);
+        }
+        let mut free = self.freelist()?.pages();
+        if !free.remove(&page_number) {
+            return Ok(false);
+        }
+        self.set_freelist(&free.into_iter().sorted().collect_vec())?;
+        self.dirty
+            .insert(page_number, vec![0; self.header.page_size as usize]);
+        Ok(true)
+    }
+
+    /// Builds the freelist again from scratch with these pages
+    pub fn set_freelist(&mut self, pages: &[u32]) -> Result<()> {
+        self.header.first_free_list_trunk = 0;
+        self.header.free_list_count = 0;
+        for page_number in pages {
+            self.free_page(*page_number)?;
+        }
+        self.touch_header()
+    }
+
+    /// Cuts the file to `page_count` pages at the next commit
+    pub fn truncate(&mut self, page_count: u32) -> Result<()> {
+        self.begin_write()?;
+        self.dirty.retain(|page_number, _| *page_number <= page_count);
+        self.size = Some(page_count);
+        self.touch_header()
+    }
+
+    /// Page 1 carries the header, so it's written by the next commit even
+    /// when no other page changed
+    fn touch_header(&mut self) -> Result<()> {
+        if !self.dirty.contains_key(&1) {
+            let page = self.read_raw_page(1)?;
+            self.dirty.insert(1, page);
+        }
+        Ok(())
+    }
+
+    /// The pointer map entry of a page, `None` unless it's an auto-vacuum database
+    pub fn ptrmap_entry(&mut self, page_number: u32) -> Result<Option<PtrmapEntry>> {
+        if !self.header.is_auto_vacuum()
+            || page_number < 3
+            || is_ptrmap_page(&self.header, page_number)
+        {
+            return Ok(None);
+        }
+        let offset = ptrmap_offset(&self.header, page_number);
+        let buf = self.read_page_ref(ptrmap_page(&self.header, page_number) as u64)?;
+        Ok(PtrmapEntry::from_bytes(buf[offset..offset + 5].try_into()?))
+    }
+
+    /// Points a page at its parent, nothing to do without auto-vacuum
+    pub fn set_ptrmap_entry(&mut self, page_number: u32, entry: PtrmapEntry) -> Result<()> {
+        if !self.header.is_auto_vacuum()
+            || page_number < 3
+            || is_ptrmap_page(&self.header, page_number)
+        {
+            return Ok(());
+        }
+        let map_page = ptrmap_page(&self.header, page_number);
+        let offset = ptrmap_offset(&self.header, page_number);
+        let mut buf = self.read_raw_page(map_page as u64)?;
+        if buf[offset..offset + 5] != entry.to_bytes() {
+            buf[offset..offset + 5].copy_from_slice(&entry.to_bytes());
+            self.write_page(map_page, buf)?;
+        }
+        Ok(())
+    }
+
+    /// Swaps every page for those of `copy` at the next commit, they may
+    /// have another page size. VACUUM builds the copy in a temporary file
+    pub fn replace_content(&mut self, header: DatabaseHeader, copy: &mut SqliteFile) -> Result<()> {
+        self.begin_write()?;
+        if self.wal.is_some() && header.page_size != self.file_page_size {
+            bail!("the page size can't change in WAL mode");
+        }
+        let page_count = copy.page_count()?;
+        let pages = (1..=page_count)
+            .map(|page_number| Ok((page_number as u32, copy.read_raw_page(page_number)?)))
+            .collect::<Result<_>>()?;
+        self.header = header;
+        self.dirty = pages;
+        self.size = Some(page_count as u32);
+        Ok(())
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction
+    }
+
+    /// Holds every change until `commit` or `discard`
+    pub fn begin(&mut self) -> Result<()> {
+        if self.transaction {
+            bail!("cannot start a transaction within a transaction");
+        }
+        self.transaction = true;
+        Ok(())
+    }
+
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            dirty: self.dirty.clone(),
+            size: self.size,
+            header: self.header.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, savepoint: Savepoint) -> Result<()> {
+        self.dirty = savepoint.dirty;
+        self.size = savepoint.size;
+        self.header = savepoint.header;
+        self.read_schema()
+    }
+
+    /// Writes the dirty pages and the header, bumping the change counter so
+    /// other connections drop their cache. The original pages go to the
+    /// `-journal` first, the transaction is done once it's deleted.
+    /// In WAL mode the pages are appended to the WAL instead. When it fails
+    /// the transaction is still open, to try again or discard it
+    pub fn commit(&mut self) -> Result<()> {
+        self.write_changes()?;
+        self.transaction = false;
+        // Like SQLite's, the checkpoint after a commit is only an attempt,
+        // the commit stands whether or not it could be done
+        let frames = self.wal.as_ref().map_or(0, |it| it.frame_count);
+        if frames >= WAL_AUTOCHECKPOINT {
+            let _ = self.checkpoint(CheckpointMode::Passive);
+        }
+        Ok(())
+    }
+
+    fn write_changes(&mut self) -> Result<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        self.begin_write()?;
+        // Full auto-vacuum gives free pages back on every commit
+        if self.header.auto_vacuum() == AutoVacuum::Full && self.header.free_list_count > 0 {
+            incremental_vacuum(self, None)?;
+        }
+        let page_count = self.page_count()? as u32;
+        // The wal-index tells readers about changes, the counter stays
+        if self.wal.is_none() {
+            self.header.file_change_counter = self.header.file_change_counter.wrapping_add(1);
+            self.header.version_valid_for = self.header.file_change_counter;
+        }
+        self.header.pages_count = page_count;
+        let mut page = self.read_raw_page(1)?;
+        page[..100].copy_from_slice(&self.header.to_bytes());
+        self.dirty.insert(1, page);
+
+        // What's cached is the state before the commit
+        for page_number in self.dirty.keys() {
+            self.cache.remove(*page_number);
+        }
+        self.cache.truncate(page_count);
+
+        if let Some(wal) = &mut self.wal {
+            wal.commit(&self.dirty, page_count)?;
+            self.dirty.clear();
+            self.size = None;
+            return Ok(());
+        }
+
+        // Readers have to be done before the file changes, and a commit
+        // since ours started would be lost
+        lock_wait(&mut *self.file, Lock::Exclusive)?;
+        let result = file_header(&mut *self.file).and_then(|header| {
+            if header.file_change_counter != self.change_counter {
+                bail!("database is locked");
+            }
+            self.write_file(page_count)
+        });
+        self.file.lock(Lock::Shared)?;
+        self.reserved = false;
+        result?;
+        self.change_counter = self.header.file_change_counter;
+        if self.file_page_size != self.header.page_size {
+            self.file_page_size = self.header.page_size;
+            self.cache.clear();
+            self.set_cache_size(self.cache_size);
+        }
+        self.remap()?;
+        self.dirty.clear();
+        self.size = None;
+        Ok(())
+    }
+
+    /// Journals the original pages, then writes the dirty ones
+    fn write_file(&mut self, page_count: u32) -> Result<()> {
+        // Pages changed or cut off, as they were before the transaction
+        let page_size = self.file_page_size as u64;
+        let initial_size = (self.file.size()? / page_size) as u32;
+        let changed = (1..=initial_size)
+            .filter(|it| *it > page_count || self.dirty.contains_key(it))
+            .collect_vec();
+        let journal_path = format!("{}-journal", self.path);
+        let header = JournalHeader {
+            page_count: changed.len() as u32,
+            nonce: random_u32(),
+            initial_size,
+            sector_size: 512,
+            page_size: page_size as u32,
+        };
+        let vfs = self.vfs.clone();
+        let originals = changed
+            .into_iter()
+            .map(|it| Ok((it, self.read_file_page(it as u64)?)));
+        write_journal(&*vfs, &journal_path, &header, originals)?;
+
+        // After VACUUM the pages have a new size
+        let page_size = self.header.page_size as u64;
+        for (page_number, page) in &self.dirty {
+            self.file
+                .write_at((*page_number as u64 - 1) * page_size, page)?;
+        }
+        self.file.set_size(page_count as u64 * page_size)?;
+        self.file.sync()?;
+        self.vfs.delete(&journal_path)
+    }
+
+    /// Copies the WAL back into the database file, see `Wal::checkpoint`.
+    /// Not in WAL mode there's nothing to do
+    pub fn checkpoint(&mut self, mode: CheckpointMode) -> Result<CheckpointResult> {
+        if self.transaction || !self.dirty.is_empty() {
+            bail!("database table is locked");
+        }
+        let Some(wal) = &mut self.wal else {
+            return Ok(CheckpointResult {
+                busy: false,
+                log_frames: -1,
+                checkpointed: -1,
+            });
+        };
+        let result = wal.checkpoint(&mut *self.file, mode)?;
+        self.remap()?;
+        // Reading starts over from the latest commit
+        let page = self.read_raw_page(1)?;
+        self.header = DatabaseHeader::from_bytes(page[..100].try_into()?).map_err(Error::msg)?;
+        self.read_schema()?;
+        Ok(result)
+    }
+
+    /// Forgets every change since the last commit and ends the transaction
+    pub fn discard(&mut self) -> Result<()> {
+        self.transaction = false;
+        self.dirty.clear();
+        self.size = None;
+        if self.reserved {
+            self.file.lock(Lock::Shared)?;
+            self.reserved = false;
+        }
+        let page = self.read_raw_page(1)?;
+        self.header = DatabaseHeader::from_bytes(page[..100].try_into()?).map_err(Error::msg)?;
+        self.read_schema()
+    }
+}
+
+/// The database header as it is in the file, page 1 may be newer elsewhere
+fn file_header(file: &mut dyn PageSource) -> Result<DatabaseHeader> {
+    let mut buf = [0; 100];
+    file.read_at(0, &mut buf)?;
+    DatabaseHeader::from_bytes(&buf).map_err(Error::msg)
+}
+
+/// `:memory:`, or the URI `file::memory:` with any query like `?cache=shared`
+pub fn is_memory_path(path: &str) -> bool {
+    let path = path.strip_prefix("file:").unwrap_or(path);
+    path.split('?').next() == Some(":memory:")
+}
+
+/// Takes `lock`, waiting a little while another connection holds a
+/// conflicting one, like a busy timeout
+fn lock_wait(file: &mut dyn PageSource, lock: Lock) -> Result<()> {
+    for attempt in 0..BUSY_RETRIES {
+        if attempt > 0 {
+            sleep(BUSY_SLEEP);
+        }
+        if file.lock(lock)? {
+            return Ok(());
+        }
+    }
+    bail!("database is locked")
+}
+
+/// The `-journal` of a writer that crashed, found while holding SHARED.
+/// It's hot when no one holds RESERVED, a writer still alive would.
+/// Depending on `hot_journal` it's refused, replayed or rolled back, after
+/// that the file is locked SHARED again
+fn hot_journal(
+    vfs: &dyn Vfs,
+    file: &mut dyn PageSource,
+    path: &str,
+    hot_journal: HotJournal,
+) -> Result<Option<Journal>> {
+    let journal_path = format!("{path}-journal");
+    if !vfs.exists(&journal_path)? || !file.lock(Lock::Reserved)? {
+        return Ok(None);
+    }
+    let result = match Journal::open(vfs, &journal_path) {
+        Ok(Some(hot)) => match hot_journal {
+            HotJournal::Refuse => Err(anyhow!(
+                "{} has a hot journal, an interrupted transaction needs to be rolled back",
+                path
+            )),
+            HotJournal::Replay => Ok(Some(hot)),
+            HotJournal::Rollback => rollback(vfs, file, &journal_path, hot).map(|_| None),
+        },
+        other => other,
+    };
+    file.lock(Lock::Shared)?;
+    result
+}
+
+/// Puts the journaled pages back, cuts the file to its size before the
+/// transaction, then deletes the journal once the database is synced
+fn rollback(
+    vfs: &dyn Vfs,
+    file: &mut dyn PageSource,
+    journal_path: &str,
+    mut journal: Journal,
+) -> Result<()> {
+    lock_wait(file, Lock::Exclusive)?;
+    let page_size = journal.header.page_size as u64;
+    let page_numbers: Vec<u32> = journal.page_numbers().collect();
+    for page_number in page_numbers {
+        if let Some(page) = journal.read_page(page_number)? {
+            file.write_at((page_number as u64 - 1) * page_size, &page)?;
+        }
+    }
+    file.set_size(journal.header.initial_size as u64 * page_size)?;
+    file.sync()?;
+    vfs.delete(journal_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::SliceSource,
+        testing::{content, create, execute, integrity_check, query_one, reopen, reopen_with},
+    };
+
+    /// A database with an empty `t` in 1024 byte pages, so a few rows split them
+    fn database(vfs: &MemoryVfs) -> SqliteFile {
+        let options = CreateOptions {
+            page_size: 1024,
+            ..CreateOptions::default()
+        };
+        let mut db = create(vfs, &options);
+        execute(&mut db, "CREATE TABLE t(a)").unwrap();
+        db
+    }
+
+    fn count(db: &mut SqliteFile) -> String {
+        query_one(db, "SELECT COUNT(*) FROM t")
+    }
+
+    #[test]
+    fn bytes_are_opened_and_read() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        execute(&mut db, "INSERT INTO t VALUES ('apple'), ('pear')").unwrap();
+
+        let source = Box::new(SliceSource::new(content(&vfs, "test.db")));
+        let mut db = SqliteFile::open_source("fixture.db", source).unwrap();
+        assert_eq!(db.header.page_size, 1024);
+        assert_eq!(db.tables.len(), 1);
+        let rows = execute(&mut db, "SELECT a FROM t").unwrap();
+        let names = rows.iter().map(|row| row[0].to_string()).join(",");
+        assert_eq!(names, "apple,pear");
+        let error = execute(&mut db, "INSERT INTO t VALUES ('fig')").unwrap_err();
+        assert_eq!(error.to_string(), "attempt to write a readonly database");
+    }
+
+    #[test]
+    fn rows_survive_splits_and_overflow() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        execute(&mut db, "CREATE TABLE big(id INTEGER PRIMARY KEY, a TEXT)").unwrap();
+        // Rows of 1000 bytes and more overflow, 300 of them make the tree
+        // three levels deep
+        for id in 0..300 {
+            let a = "x".repeat(id % 4 * 1000);
+            let sql = format!("INSERT INTO big VALUES ({id}, '{a}')");
+            execute(&mut db, &sql).unwrap();
+        }
+        execute(&mut db, "DELETE FROM big WHERE id % 4 = 3").unwrap();
+
+        let mut db = reopen(&vfs);
+        let rows = execute(&mut db, "SELECT id, a FROM big").unwrap();
+        assert_eq!(rows.len(), 225);
+        for row in rows {
+            let id: usize = row[0].to_string().parse().unwrap();
+            assert_eq!(row[1].to_string(), "x".repeat(id % 4 * 1000));
+        }
+        assert!(db.header.free_list_count > 0);
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    /// The database after a crash halfway through deleting every row, the
+    /// journal has page 1 and the leaves as they were
+    fn crashed(vfs: &MemoryVfs) {
+        let mut db = database(vfs);
+        for _ in 0..50 {
+            let sql = format!("INSERT INTO t VALUES ('{}')", "x".repeat(100));
+            execute(&mut db, &sql).unwrap();
+        }
+        let before = content(vfs, "test.db");
+        execute(&mut db, "DELETE FROM t").unwrap();
+        let page_size = 1024;
+        let originals = before
+            .chunks(page_size)
+            .zip(1..)
+            .map(|(page, page_number)| (page_number, page.to_vec()))
+            .collect_vec();
+        let header = JournalHeader {
+            page_count: originals.len() as u32,
+            nonce: 7,
+            initial_size: originals.len() as u32,
+            sector_size: 512,
+            page_size: page_size as u32,
+        };
+        let originals = originals.into_iter().map(Ok);
+        write_journal(vfs, "test.db-journal", &header, originals).unwrap();
+    }
+
+    #[test]
+    fn hot_journals_are_replayed() {
+        let vfs = MemoryVfs::default();
+        crashed(&vfs);
+        let after = content(&vfs, "test.db");
+
+        let mut db = reopen(&vfs);
+        assert_eq!(count(&mut db), "50");
+        assert_eq!(integrity_check(&mut db), "ok");
+        let error = execute(&mut db, "INSERT INTO t VALUES (1)").unwrap_err();
+        assert!(error.to_string().contains("hot journal"));
+        // Replaying only reads the journal
+        assert_eq!(content(&vfs, "test.db"), after);
+        assert!(vfs.exists("test.db-journal").unwrap());
+
+        let error = reopen_with(&vfs, HotJournal::Refuse).err().unwrap();
+        assert!(error.to_string().contains("hot journal"));
+
+        let mut db = reopen_with(&vfs, HotJournal::Rollback).unwrap();
+        assert!(!vfs.exists("test.db-journal").unwrap());
+        assert_eq!(count(&mut db), "50");
+        execute(&mut db, "INSERT INTO t VALUES (1)").unwrap();
+        let mut db = reopen(&vfs);
+        assert_eq!(count(&mut db), "51");
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    #[test]
+    fn wal_mode_stays_in_the_vfs() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        execute(&mut db, "INSERT INTO t VALUES (1)").unwrap();
+        // Versions 2 are WAL mode, what `PRAGMA journal_mode = WAL` writes
+        db.file.write_at(18, &[2, 2]).unwrap();
+        drop(db);
+
+        let mut db = reopen(&vfs);
+        assert!(db.wal.is_some());
+        let before = content(&vfs, "test.db");
+        for a in 2..=100 {
+            execute(&mut db, &format!("INSERT INTO t VALUES ({a})")).unwrap();
+        }
+        assert_eq!(content(&vfs, "test.db"), before);
+        assert!(vfs.exists("test.db-wal").unwrap());
+        assert!(vfs.exists("test.db-shm").unwrap());
+
+        let mut other = reopen(&vfs);
+        assert_eq!(count(&mut other), "100");
+        execute(&mut other, "DELETE FROM t WHERE a > 50").unwrap();
+        assert_eq!(count(&mut db), "50");
+
+        let result = execute(&mut db, "PRAGMA wal_checkpoint(TRUNCATE)").unwrap();
+        assert_eq!(result[0][0].to_string(), "0");
+        assert_ne!(content(&vfs, "test.db"), before);
+        assert!(content(&vfs, "test.db-wal").is_empty());
+        let mut db = reopen(&vfs);
+        assert_eq!(count(&mut db), "50");
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    #[test]
+    fn commits_checkpoint_large_wals() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        let sql = format!("INSERT INTO t VALUES ('{}')", "x".repeat(500));
+        execute(&mut db, &sql).unwrap();
+        db.file.write_at(18, &[2, 2]).unwrap();
+        drop(db);
+
+        // Each transaction doubles the rows, the last ones pass 1000 frames
+        let mut db = reopen(&vfs);
+        let before = content(&vfs, "test.db");
+        for _ in 0..11 {
+            execute(&mut db, "BEGIN; INSERT INTO t SELECT a FROM t; COMMIT").unwrap();
+        }
+        assert_ne!(content(&vfs, "test.db"), before);
+        assert_eq!(count(&mut db), "2048");
+        let mut db = reopen(&vfs);
+        assert_eq!(count(&mut db), "2048");
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    #[test]
+    fn commits_of_another_connection_are_read() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        execute(&mut db, "INSERT INTO t VALUES (1)").unwrap();
+        assert_eq!(count(&mut db), "1");
+
+        let mut other = reopen(&vfs);
+        for a in 2..=500 {
+            execute(&mut other, &format!("INSERT INTO t VALUES ({a})")).unwrap();
+        }
+        execute(&mut other, "CREATE TABLE u(b)").unwrap();
+
+        // The pages cached above are from before those commits
+        assert_eq!(count(&mut db), "500");
+        execute(&mut db, "INSERT INTO t VALUES (501)").unwrap();
+        assert_eq!(db.tables.len(), 2);
+        assert_eq!(count(&mut other), "501");
+        assert_eq!(integrity_check(&mut other), "ok");
+    }
+
+    #[test]
+    fn writing_a_stale_snapshot_fails() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+
+        // Memory files have no locks, only the change counter catches this
+        let mut other = reopen(&vfs);
+        execute(&mut other, "BEGIN").unwrap();
+        assert_eq!(count(&mut other), "0");
+        execute(&mut db, "INSERT INTO t VALUES (1)").unwrap();
+        let error = execute(&mut other, "INSERT INTO t VALUES (2)").unwrap_err();
+        assert_eq!(error.to_string(), "database is locked");
+        execute(&mut other, "ROLLBACK").unwrap();
+
+        // A change made before the other commit fails at COMMIT instead
+        execute(&mut other, "BEGIN").unwrap();
+        execute(&mut other, "INSERT INTO t VALUES (2)").unwrap();
+        execute(&mut db, "INSERT INTO t VALUES (3)").unwrap();
+        let error = execute(&mut other, "COMMIT").unwrap_err();
+        assert_eq!(error.to_string(), "database is locked");
+        execute(&mut other, "ROLLBACK").unwrap();
+        assert_eq!(count(&mut other), "2");
+        assert_eq!(integrity_check(&mut other), "ok");
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn locks_outlive_other_descriptors() {
+        let path = std::env::temp_dir().join(format!("locks-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut db = SqliteFile::create(path, &CreateOptions::default()).unwrap();
+        db.begin_read().unwrap();
+
+        // Closing a descriptor dropped every lock of the process with POSIX locks
+        drop(SqliteFile::open(path).unwrap());
+        let mut other = SqliteFile::open(path).unwrap();
+        let locked = other.file.lock(Lock::Exclusive).unwrap();
+        db.end_read().unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(!locked);
+    }
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn mapped_files_shrink_between_reads_only() {
+        let path = std::env::temp_dir().join(format!("mapped-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let options = CreateOptions {
+            page_size: 1024,
+            ..CreateOptions::default()
+        };
+        let mut db = SqliteFile::create(path, &options).unwrap();
+        execute(&mut db, "CREATE TABLE t(a)").unwrap();
+        let sql = format!("INSERT INTO t VALUES ('{}')", "x".repeat(500));
+        for _ in 0..100 {
+            execute(&mut db, &sql).unwrap();
+        }
+        db.set_mmap_size(1 << 30).unwrap();
+        let page = db.read_page_ref(40).unwrap();
+
+        // Cutting the file would crash reading the page from the map
+        let mut other = SqliteFile::open(path).unwrap();
+        let error = execute(&mut other, "DELETE FROM t; VACUUM").unwrap_err();
+        let locked = page.len() == 1024 && error.to_string() == "database is locked";
+        db.end_read().unwrap();
+        let vacuumed = execute(&mut other, "DELETE FROM t; VACUUM").is_ok();
+        let missing = db.read_page_ref(40).is_err();
+        let rows = query_one(&mut db, "SELECT COUNT(*) FROM t");
+        std::fs::remove_file(path).unwrap();
+        assert!(locked && vacuumed && missing);
+        assert_eq!(rows, "0");
+    }
+
+    #[test]
+    fn pages_in_memory_are_not_cache_misses() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        execute(&mut db, "INSERT INTO t VALUES (1)").unwrap();
+
+        let source = Box::new(SliceSource::new(content(&vfs, "test.db")));
+        let mut db = SqliteFile::open_source("test.db", source).unwrap();
+        assert_eq!(count(&mut db), "1");
+        let stats = db.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (0, 0));
+    }
+
+    #[test]
+    fn memory_paths() {
+        assert!(is_memory_path(":memory:"));
+        assert!(is_memory_path("file::memory:"));
+        assert!(is_memory_path("file::memory:?cache=shared"));
+        assert!(!is_memory_path("memory.db"));
+        assert!(!is_memory_path("file:memory.db"));
+        assert!(!is_memory_path("./:memory:"));
+        assert!(!is_memory_path(":memory:.db"));
+    }
+
+    #[test]
+    fn memory_databases_are_separate() {
+        let mut a = SqliteFile::open(":memory:").unwrap();
+        let mut b = SqliteFile::open("file::memory:?cache=shared").unwrap();
+        execute(&mut a, "CREATE TABLE t(a); INSERT INTO t VALUES (1)").unwrap();
+        assert!(execute(&mut b, "SELECT a FROM t").is_err());
+        execute(&mut b, "CREATE TABLE t(a)").unwrap();
+        assert_eq!(query_one(&mut a, "SELECT COUNT(*) FROM t"), "1");
+        assert_eq!(query_one(&mut b, "SELECT COUNT(*) FROM t"), "0");
+        // Nothing was written next to the tests
+        assert!(!FileSystem.exists(":memory:").unwrap());
+        assert!(!FileSystem.exists("file::memory:").unwrap());
+    }
 }
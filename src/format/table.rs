@@ -1,12 +1,15 @@
+use std::cmp::Ordering;
+
 use itertools::Itertools;
 
 use super::{
     cell::Cell,
     page::Page,
-    record::{Record, RecordSerial},
+    record::{Record, RecordRef, RecordSerial},
+    value::{FromSql, Value},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Table {
     pub kind: String,
     pub name: String,
@@ -34,24 +37,24 @@ impl Table {
         }
 
         let (kind, name, table_name, root_page, sql) = match &record.content[0..5] {
-            [RecordSerial::String(kind), RecordSerial::String(name), RecordSerial::String(table_name), root_page, RecordSerial::String(sql)] =>
+            [RecordSerial::String(kind), RecordSerial::String(name), RecordSerial::String(table_name), root_page, sql] =>
             {
-                let root_page = match root_page {
-                    RecordSerial::I8(i) => *i as i64,
-                    RecordSerial::I16(i) => *i as i64,
-                    RecordSerial::I24(i) => *i as i64,
-                    RecordSerial::I32(i) => *i as i64,
-                    RecordSerial::I48(i) => *i as i64,
-                    RecordSerial::I64(i) => *i as i64,
+                // Automatic indexes have no sql
+                let sql = match sql {
+                    RecordSerial::String(sql) => sql,
+                    RecordSerial::Null => "",
                     _ => return Err("Not a table schema"),
                 };
+                // Views and triggers have 0 as their root page
+                let root_page =
+                    i64::from_sql(&Value::from(root_page)).map_err(|_| "Not a table schema")?;
 
                 (
                     kind.clone(),
                     name.clone(),
                     table_name.clone(),
                     root_page,
-                    sql.clone(),
+                    sql.to_string(),
                 )
             }
             _ => return Err("Not a table schema"),
@@ -66,3 +69,636 @@ impl Table {
         })
     }
 }
+
+#[derive(Debug)]
+pub struct Column {
+    pub name: String,
+    pub type_name: String,
+    pub collation: Option<String>,
+    pub not_null: bool,
+    /// The DEFAULT expression as written
+    pub default: Option<String>,
+}
+
+/// How a column converts the values stored into it
+/// See https://www.sqlite.org/datatype3.html#determination_of_column_affinity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Affinity {
+    Integer,
+    Text,
+    Blob,
+    Real,
+    Numeric,
+}
+
+impl Column {
+    pub fn affinity(&self) -> Affinity {
+        let type_name = self.type_name.to_ascii_uppercase();
+        if type_name.contains("INT") {
+            Affinity::Integer
+        } else if ["CHAR", "CLOB", "TEXT"]
+            .iter()
+            .any(|it| type_name.contains(it))
+        {
+            Affinity::Text
+        } else if type_name.is_empty() || type_name.contains("BLOB") {
+            Affinity::Blob
+        } else if ["REAL", "FLOA", "DOUB"]
+            .iter()
+            .any(|it| type_name.contains(it))
+        {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+}
+
+impl Affinity {
+    /// Converts a value like SQLite does before storing it
+    pub fn apply(&self, value: RecordSerial) -> RecordSerial {
+        match (self, value) {
+            (Affinity::Text, RecordSerial::F64(f)) => RecordSerial::String(format_real(f)),
+            (Affinity::Text, value) if value.as_i64().is_some() => {
+                RecordSerial::String(value.as_i64().unwrap_or_default().to_string())
+            }
+            (Affinity::Integer | Affinity::Numeric | Affinity::Real, RecordSerial::String(s)) => {
+                let trimmed = s.trim();
+                let number = if let Ok(i) = trimmed.parse::<i64>() {
+                    RecordSerial::I64(i)
+                } else if let Some(f) = trimmed.parse::<f64>().ok().filter(|_| {
+                    trimmed
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+                }) {
+                    RecordSerial::F64(f)
+                } else {
+                    return RecordSerial::String(s);
+                };
+                self.apply(number)
+            }
+            // Reals that are whole numbers are stored as integers
+            (Affinity::Integer | Affinity::Numeric, RecordSerial::F64(f))
+                if f.fract() == 0.0 && f.abs() < 9.0e18 =>
+            {
+                RecordSerial::I64(f as i64)
+            }
+            (Affinity::Real, value) if value.as_i64().is_some() => {
+                RecordSerial::F64(value.as_i64().unwrap_or_default() as f64)
+            }
+            (_, value) => value,
+        }
+    }
+}
+
+/// Reals as text keep a decimal point, like `CAST(1.0 AS TEXT)` gives `1.0`
+fn format_real(f: f64) -> String {
+    if f.fract() == 0.0 && f.abs() < 1.0e15 {
+        format!("{f:.1}")
+    } else {
+        f.to_string()
+    }
+}
+
+/// What we need to know about a `CREATE TABLE` statement to read its rows
+#[derive(Debug)]
+pub struct TableDefinition {
+    pub columns: Vec<Column>,
+    /// Column indexes in primary key order
+    pub primary_key: Vec<usize>,
+    pub without_rowid: bool,
+    /// New rowids never reuse one from sqlite_sequence
+    pub autoincrement: bool,
+    /// Columns of each `sqlite_autoindex_<table>_N`, in order of N
+    pub autoindexes: Vec<Vec<usize>>,
+}
+
+#[derive(Debug)]
+pub struct IndexColumn {
+    /// None when the index is on an expression
+    pub column: Option<String>,
+    pub collation: Option<String>,
+    pub descending: bool,
+}
+
+/// The key of a `CREATE INDEX` or of an automatic index
+#[derive(Debug)]
+pub struct IndexDefinition {
+    pub unique: bool,
+    pub columns: Vec<IndexColumn>,
+    /// Indexes with a WHERE clause only hold some of the rows
+    pub partial: bool,
+}
+
+impl TableDefinition {
+    /// An `INTEGER PRIMARY KEY` column is stored as the rowid, the record only holds a NULL
+    pub fn rowid_alias(&self) -> Option<usize> {
+        match self.primary_key[..] {
+            [i] if !self.without_rowid
+                && self.columns[i].type_name.eq_ignore_ascii_case("integer") =>
+            {
+                Some(i)
+            }
+            _ => None,
+        }
+    }
+
+    /// Record values in column order, `WITHOUT ROWID` tables store primary key columns first
+    pub fn row_values(&self, row_id: Option<i64>, record: Record) -> Vec<RecordSerial> {
+        let mut values: Vec<RecordSerial> = if self.without_rowid && !self.primary_key.is_empty() {
+            let mut content = record.content.into_iter().map(Some).collect_vec();
+            let mut values = (0..self.columns.len()).map(|_| None).collect_vec();
+            let mut rest = (0..self.columns.len()).filter(|i| !self.primary_key.contains(i));
+            for (position, value) in content.iter_mut().enumerate() {
+                let column = match self.primary_key.get(position) {
+                    Some(i) => Some(*i),
+                    None => rest.next(),
+                };
+                if let Some(column) = column {
+                    values[column] = value.take();
+                }
+            }
+            values
+                .into_iter()
+                .map(|it| it.unwrap_or(RecordSerial::Null))
+                .collect()
+        } else {
+            record.content
+        };
+
+        // Columns added by ALTER TABLE are missing from older records
+        while values.len() < self.columns.len() {
+            values.push(RecordSerial::Null);
+        }
+        if let (Some(i), Some(row_id)) = (self.rowid_alias(), row_id) {
+            values[i] = RecordSerial::I64(row_id);
+        }
+        values
+    }
+
+    /// Where a column sits in the record, the inverse of `row_values`
+    pub fn record_index(&self, column: usize) -> usize {
+        if !self.without_rowid || self.primary_key.is_empty() {
+            return column;
+        }
+        match self.primary_key.iter().position(|it| *it == column) {
+            Some(position) => position,
+            None => {
+                let before = (0..column).filter(|i| !self.primary_key.contains(i));
+                self.primary_key.len() + before.count()
+            }
+        }
+    }
+
+    /// One column of a row, decoded from a record read in place
+    pub fn column_value(
+        &self,
+        row_id: Option<i64>,
+        record: &RecordRef,
+        column: usize,
+    ) -> Result<RecordSerial, &'static str> {
+        match (self.rowid_alias(), row_id) {
+            (Some(i), Some(row_id)) if i == column => Ok(RecordSerial::I64(row_id)),
+            _ => record.serial(self.record_index(column)),
+        }
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|it| it.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The definition behind `sqlite_autoindex_<table>_<n>`, n starts at 1
+    pub fn autoindex_definition(&self, n: usize) -> Option<IndexDefinition> {
+        let columns = self.autoindexes.get(n.checked_sub(1)?)?;
+        Some(IndexDefinition {
+            unique: true,
+            columns: columns
+                .iter()
+                .map(|i| IndexColumn {
+                    column: Some(self.columns[*i].name.clone()),
+                    collation: self.columns[*i].collation.clone(),
+                    descending: false,
+                })
+                .collect(),
+            partial: false,
+        })
+    }
+}
+
+impl IndexDefinition {
+    /// Orders index keys with each column's collation and direction, only
+    /// comparing as many values as the shorter key has
+    pub fn compare(&self, a: &[RecordSerial], b: &[RecordSerial]) -> Ordering {
+        a.iter()
+            .zip(b.iter())
+            .enumerate()
+            .map(|(column, (a, b))| {
+                let key = self.columns.get(column);
+                let ordering = a.compare(b, key.and_then(|it| it.collation.as_deref()));
+                if key.is_some_and(|it| it.descending) {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+            .find(|it| it.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl Table {
+    /// The key of this index and where its columns are in the table, None for
+    /// expression and partial indexes since there's no expression evaluator
+    pub fn index_columns(&self, table: &TableDefinition) -> Option<(IndexDefinition, Vec<usize>)> {
+        let definition = if self.sql.is_empty() {
+            // sqlite_autoindex_<table>_<n>
+            self.name
+                .rsplit('_')
+                .next()
+                .and_then(|n| n.parse().ok())
+                .and_then(|n| table.autoindex_definition(n))
+        } else {
+            self.index_definition().ok()
+        };
+        let mut definition = definition.filter(|it| !it.partial)?;
+        let columns = definition
+            .columns
+            .iter()
+            .map(|it| it.column.as_ref().and_then(|name| table.column_index(name)))
+            .collect::<Option<Vec<_>>>()?;
+        // Without their own COLLATE, index columns sort like the table column
+        for (key, i) in definition.columns.iter_mut().zip(&columns) {
+            if key.collation.is_none() {
+                key.collation = table.columns[*i].collation.clone();
+            }
+        }
+        Some((definition, columns))
+    }
+
+    pub fn definition(&self) -> Result<TableDefinition, &'static str> {
+        let open = self.sql.find('(').ok_or("Not a CREATE TABLE statement")?;
+        let close = self.sql.rfind(')').ok_or("Not a CREATE TABLE statement")?;
+        if close < open {
+            return Err("Not a CREATE TABLE statement");
+        }
+        let without_rowid = tokenize(&self.sql[close + 1..])
+            .iter()
+            .map(|it| it.to_ascii_uppercase())
+            .collect_vec()
+            == ["WITHOUT", "ROWID"];
+
+        let mut columns = vec![];
+        // Column names of every PRIMARY KEY (true) and UNIQUE (false) in the order they appear
+        let mut constraints: Vec<(Vec<String>, bool)> = vec![];
+        for definition in split_top_level(&self.sql[open + 1..close]) {
+            let tokens = tokenize(definition);
+            let Some(first) = tokens.first() else {
+                continue;
+            };
+            let keywords = tokens
+                .iter()
+                .map(|it| it.to_ascii_uppercase())
+                .collect_vec();
+            if TABLE_CONSTRAINTS.contains(&keywords[0].as_str()) {
+                let group = |i: usize| {
+                    tokens
+                        .get(i)
+                        .map(|group| {
+                            split_top_level(group.trim_start_matches('(').trim_end_matches(')'))
+                                .iter()
+                                .filter_map(|it| tokenize(it).first().map(|it| unquote(it)))
+                                .collect_vec()
+                        })
+                        .unwrap_or_default()
+                };
+                if let Some(i) = keywords.iter().position(|it| it == "PRIMARY") {
+                    constraints.push((group(i + 2), true));
+                } else if let Some(i) = keywords.iter().position(|it| it == "UNIQUE") {
+                    constraints.push((group(i + 1), false));
+                }
+                continue;
+            }
+
+            let name = unquote(first);
+            let type_end = keywords
+                .iter()
+                .skip(1)
+                .position(|it| COLUMN_CONSTRAINTS.contains(&it.as_str()))
+                .map_or(tokens.len(), |it| it + 1);
+            let type_name = tokens[1..type_end].iter().fold(String::new(), |acc, it| {
+                if acc.is_empty() || it.starts_with('(') {
+                    acc + it
+                } else {
+                    acc + " " + it
+                }
+            });
+            let collation = keywords
+                .iter()
+                .position(|it| it == "COLLATE")
+                .and_then(|i| tokens.get(i + 1))
+                .map(|it| unquote(it));
+            if keywords
+                .windows(2)
+                .any(|it| it[0] == "PRIMARY" && it[1] == "KEY")
+            {
+                constraints.push((vec![name.clone()], true));
+            }
+            if keywords.iter().any(|it| it == "UNIQUE") {
+                constraints.push((vec![name.clone()], false));
+            }
+            let not_null = keywords
+                .windows(2)
+                .any(|it| it[0] == "NOT" && it[1] == "NULL");
+            // A signed number is two tokens, `DEFAULT -1`
+            let default = keywords
+                .iter()
+                .position(|it| it == "DEFAULT")
+                .and_then(|i| match tokens.get(i + 1).map(String::as_str) {
+                    Some(sign @ ("-" | "+")) => tokens.get(i + 2).map(|it| format!("{sign}{it}")),
+                    other => other.map(str::to_string),
+                });
+            columns.push(Column {
+                name,
+                type_name,
+                collation,
+                not_null,
+                default,
+            });
+        }
+
+        let find = |name: &String| {
+            columns
+                .iter()
+                .position(|it| it.name.eq_ignore_ascii_case(name))
+        };
+        let primary_key: Vec<usize> = constraints
+            .iter()
+            .find(|(_, is_primary)| *is_primary)
+            .map(|(names, _)| names.iter().filter_map(find).collect())
+            .unwrap_or_default();
+        let rowid_alias = !without_rowid
+            && matches!(primary_key[..], [i] if columns[i].type_name.eq_ignore_ascii_case("integer"));
+        let autoindexes = constraints
+            .iter()
+            // The rowid alias and WITHOUT ROWID tables are their own primary key index
+            .filter(|(_, is_primary)| !is_primary || !(without_rowid || rowid_alias))
+            .map(|(names, _)| names.iter().filter_map(find).collect())
+            .collect();
+
+        let autoincrement = split_top_level(&self.sql[open + 1..close])
+            .iter()
+            .flat_map(|it| tokenize(it))
+            .any(|it| it.eq_ignore_ascii_case("AUTOINCREMENT"));
+
+        Ok(TableDefinition {
+            columns,
+            primary_key,
+            without_rowid,
+            autoincrement,
+            autoindexes,
+        })
+    }
+
+    /// Parses `CREATE [UNIQUE] INDEX name ON table (columns) [WHERE ...]`,
+    /// automatic indexes have no sql and come from `TableDefinition::autoindex_definition`
+    pub fn index_definition(&self) -> Result<IndexDefinition, &'static str> {
+        let tokens = tokenize(&self.sql);
+        let keywords = tokens
+            .iter()
+            .map(|it| it.to_ascii_uppercase())
+            .collect_vec();
+        if keywords.first().map(String::as_str) != Some("CREATE") {
+            return Err("Not a CREATE INDEX statement");
+        }
+        let on = keywords
+            .iter()
+            .position(|it| it == "ON")
+            .ok_or("Not a CREATE INDEX statement")?;
+        let group = tokens
+            .get(on + 2)
+            .filter(|it| it.starts_with('('))
+            .ok_or("Not a CREATE INDEX statement")?;
+
+        let columns = split_top_level(group.trim_start_matches('(').trim_end_matches(')'))
+            .iter()
+            .map(|it| {
+                let tokens = tokenize(it);
+                let keywords = tokens
+                    .iter()
+                    .map(|it| it.to_ascii_uppercase())
+                    .collect_vec();
+                let collate = keywords.iter().position(|it| it == "COLLATE");
+                let descending = keywords.last().map(String::as_str) == Some("DESC");
+                // Anything else than a single name before COLLATE/ASC/DESC is an expression
+                let key_end = collate.unwrap_or(
+                    tokens.len()
+                        - usize::from(matches!(
+                            keywords.last().map(String::as_str),
+                            Some("ASC" | "DESC")
+                        )),
+                );
+                IndexColumn {
+                    column: (key_end == 1 && !tokens[0].starts_with('('))
+                        .then(|| unquote(&tokens[0])),
+                    collation: collate
+                        .and_then(|i| tokens.get(i + 1))
+                        .map(|it| unquote(it)),
+                    descending,
+                }
+            })
+            .collect();
+
+        Ok(IndexDefinition {
+            unique: keywords.get(1).map(String::as_str) == Some("UNIQUE"),
+            columns,
+            partial: keywords[on + 3..].iter().any(|it| it == "WHERE"),
+        })
+    }
+}
+
+const TABLE_CONSTRAINTS: [&str; 5] = ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"];
+const COLUMN_CONSTRAINTS: [&str; 11] = [
+    "CONSTRAINT",
+    "PRIMARY",
+    "NOT",
+    "NULL",
+    "UNIQUE",
+    "CHECK",
+    "DEFAULT",
+    "COLLATE",
+    "REFERENCES",
+    "GENERATED",
+    "AS",
+];
+
+/// Splits on commas that are not nested in parentheses or quotes
+fn split_top_level(sql: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in sql.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(&sql[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&sql[start..]);
+    parts
+}
+
+/// Words, quoted names and whole parenthesized groups, comments are dropped
+fn tokenize(sql: &str) -> Vec<String> {
+    let chars = sql.chars().collect_vec();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '-' if chars.get(i + 1) == Some(&'-') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i - 1] == '*' && chars[i] == '/') {
+                    i += 1;
+                }
+                i += 1;
+                continue;
+            }
+            '\'' | '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                i += 1;
+                while i < chars.len() {
+                    if chars[i] == close {
+                        // doubled quotes are escapes
+                        if chars.get(i + 1) == Some(&close) && close != ']' {
+                            i += 2;
+                            continue;
+                        }
+                        break;
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            '(' => {
+                let mut depth = 0;
+                while i < chars.len() {
+                    match chars[i] {
+                        '(' => depth += 1,
+                        ')' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            _ => {
+                while i < chars.len()
+                    && !chars[i].is_whitespace()
+                    && !matches!(chars[i], '(' | '\'' | '"' | '`' | '[')
+                {
+                    i += 1;
+                }
+            }
+        }
+        tokens.push(chars[start..i.min(chars.len())].iter().collect());
+    }
+    tokens
+}
+
+/// `"name"`, `` `name` `` and `[name]` all mean `name`
+pub fn unquote(name: &str) -> String {
+    let mut chars = name.chars();
+    match (chars.next(), name.chars().last()) {
+        (Some(q @ ('"' | '`' | '\'')), Some(end)) if end == q && name.len() > 1 => {
+            let doubled = format!("{q}{q}");
+            name[1..name.len() - 1].replace(&doubled, &q.to_string())
+        }
+        (Some('['), Some(']')) => name[1..name.len() - 1].to_string(),
+        _ => name.to_string(),
+    }
+}
+
+/// Quotes a name only when SQL would not read it back as-is
+pub fn quote_identifier(name: &str) -> String {
+    let plain = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name.to_ascii_uppercase().as_str());
+    if plain {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+const KEYWORDS: [&str; 40] = [
+    "ALL",
+    "ALTER",
+    "AND",
+    "AS",
+    "BETWEEN",
+    "BY",
+    "CASE",
+    "CHECK",
+    "COLLATE",
+    "COLUMN",
+    "CONSTRAINT",
+    "CREATE",
+    "DEFAULT",
+    "DELETE",
+    "DISTINCT",
+    "DROP",
+    "ELSE",
+    "EXISTS",
+    "FOREIGN",
+    "FROM",
+    "GROUP",
+    "IN",
+    "INDEX",
+    "INSERT",
+    "INTO",
+    "IS",
+    "JOIN",
+    "KEY",
+    "NOT",
+    "NULL",
+    "ON",
+    "OR",
+    "ORDER",
+    "PRIMARY",
+    "REFERENCES",
+    "SELECT",
+    "TABLE",
+    "UNIQUE",
+    "VALUES",
+    "WHERE",
+];
@@ -0,0 +1,381 @@
+use std::{borrow::Cow, fmt::Display};
+
+use thiserror::Error;
+
+use super::RecordSerial;
+
+/// A value as SQL sees it, whichever serial type stored it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    /// The name `typeof()` gives
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Integer(_) => "integer",
+            Value::Real(_) => "real",
+            Value::Text(_) => "text",
+            Value::Blob(_) => "blob",
+        }
+    }
+}
+
+impl From<RecordSerial> for Value {
+    fn from(value: RecordSerial) -> Self {
+        match value {
+            RecordSerial::Null | RecordSerial::Reserved1 | RecordSerial::Reserved2 => Value::Null,
+            RecordSerial::F64(f) => Value::Real(f),
+            RecordSerial::String(s) => Value::Text(s),
+            RecordSerial::Blob(b) => Value::Blob(b),
+            other => Value::Integer(other.as_i64().unwrap_or_default()),
+        }
+    }
+}
+
+impl From<&RecordSerial> for Value {
+    fn from(value: &RecordSerial) -> Self {
+        match value {
+            RecordSerial::String(s) => Value::Text(s.clone()),
+            RecordSerial::Blob(b) => Value::Blob(b.clone()),
+            other => Value::from(other.clone()),
+        }
+    }
+}
+
+/// Integers get their smallest serial type when the record is encoded
+impl From<Value> for RecordSerial {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => RecordSerial::Null,
+            Value::Integer(i) => RecordSerial::I64(i),
+            Value::Real(f) => RecordSerial::F64(f),
+            Value::Text(s) => RecordSerial::String(s),
+            Value::Blob(b) => RecordSerial::Blob(b),
+        }
+    }
+}
+
+/// A value borrowed from the page it was read from. UTF-16 text has to be
+/// converted, so only UTF-8 text is borrowed
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueRef<'a> {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(Cow<'a, str>),
+    Blob(&'a [u8]),
+}
+
+impl ValueRef<'_> {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ValueRef::Text(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_blob(&self) -> Option<&[u8]> {
+        match self {
+            ValueRef::Blob(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl From<ValueRef<'_>> for Value {
+    fn from(value: ValueRef<'_>) -> Self {
+        match value {
+            ValueRef::Null => Value::Null,
+            ValueRef::Integer(i) => Value::Integer(i),
+            ValueRef::Real(f) => Value::Real(f),
+            ValueRef::Text(s) => Value::Text(s.into_owned()),
+            ValueRef::Blob(b) => Value::Blob(b.to_vec()),
+        }
+    }
+}
+
+impl<'a> From<&'a Value> for ValueRef<'a> {
+    fn from(value: &'a Value) -> Self {
+        match value {
+            Value::Null => ValueRef::Null,
+            Value::Integer(i) => ValueRef::Integer(*i),
+            Value::Real(f) => ValueRef::Real(*f),
+            Value::Text(s) => ValueRef::Text(Cow::Borrowed(s)),
+            Value::Blob(b) => ValueRef::Blob(b),
+        }
+    }
+}
+
+/// The way the CLI prints values
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => write!(f, "Null"),
+            Value::Integer(i) => write!(f, "{i}"),
+            Value::Real(r) => write!(f, "{r}"),
+            Value::Text(s) => write!(f, "{s}"),
+            Value::Blob(b) => write!(f, "{}", b.escape_ascii()),
+        }
+    }
+}
+
+/// Why a value can't become the Rust type asked for
+#[derive(Debug, Error, PartialEq)]
+pub enum FromSqlError {
+    #[error("expected {expected}, got {actual}")]
+    InvalidType {
+        expected: &'static str,
+        actual: &'static str,
+    },
+    #[error("{value} is out of range for {target}")]
+    OutOfRange { value: i64, target: &'static str },
+}
+
+/// A Rust value bound to a placeholder
+pub trait ToSql {
+    fn to_sql(&self) -> Value;
+}
+
+/// A Rust value read from a column. Integers convert to floats, nothing
+/// else converts, and NULL only reads as `Option`
+pub trait FromSql: Sized {
+    fn from_sql(value: &Value) -> Result<Self, FromSqlError>;
+}
+
+fn invalid_type(expected: &'static str, value: &Value) -> FromSqlError {
+    FromSqlError::InvalidType {
+        expected,
+        actual: value.type_name(),
+    }
+}
+
+macro_rules! integer {
+    ($($t:ty),*) => {$(
+        impl ToSql for $t {
+            fn to_sql(&self) -> Value {
+                Value::Integer(*self as i64)
+            }
+        }
+
+        impl FromSql for $t {
+            fn from_sql(value: &Value) -> Result<Self, FromSqlError> {
+                match value {
+                    Value::Integer(i) => <$t>::try_from(*i).map_err(|_| FromSqlError::OutOfRange {
+                        value: *i,
+                        target: stringify!($t),
+                    }),
+                    other => Err(invalid_type("integer", other)),
+                }
+            }
+        }
+    )*};
+}
+
+integer!(i8, i16, i32, i64, u8, u16, u32);
+
+impl FromSql for u64 {
+    fn from_sql(value: &Value) -> Result<Self, FromSqlError> {
+        match value {
+            Value::Integer(i) => u64::try_from(*i).map_err(|_| FromSqlError::OutOfRange {
+                value: *i,
+                target: "u64",
+            }),
+            other => Err(invalid_type("integer", other)),
+        }
+    }
+}
+
+impl ToSql for bool {
+    fn to_sql(&self) -> Value {
+        Value::Integer(*self as i64)
+    }
+}
+
+/// Any integer but 0 is true
+impl FromSql for bool {
+    fn from_sql(value: &Value) -> Result<Self, FromSqlError> {
+        i64::from_sql(value).map(|it| it != 0)
+    }
+}
+
+impl ToSql for f64 {
+    fn to_sql(&self) -> Value {
+        Value::Real(*self)
+    }
+}
+
+impl FromSql for f64 {
+    fn from_sql(value: &Value) -> Result<Self, FromSqlError> {
+        match value {
+            Value::Real(f) => Ok(*f),
+            Value::Integer(i) => Ok(*i as f64),
+            other => Err(invalid_type("real", other)),
+        }
+    }
+}
+
+impl ToSql for f32 {
+    fn to_sql(&self) -> Value {
+        Value::Real(*self as f64)
+    }
+}
+
+impl FromSql for f32 {
+    fn from_sql(value: &Value) -> Result<Self, FromSqlError> {
+        f64::from_sql(value).map(|it| it as f32)
+    }
+}
+
+impl ToSql for str {
+    fn to_sql(&self) -> Value {
+        Value::Text(self.to_string())
+    }
+}
+
+impl ToSql for String {
+    fn to_sql(&self) -> Value {
+        Value::Text(self.clone())
+    }
+}
+
+impl FromSql for String {
+    fn from_sql(value: &Value) -> Result<Self, FromSqlError> {
+        match value {
+            Value::Text(s) => Ok(s.clone()),
+            other => Err(invalid_type("text", other)),
+        }
+    }
+}
+
+impl ToSql for [u8] {
+    fn to_sql(&self) -> Value {
+        Value::Blob(self.to_vec())
+    }
+}
+
+impl ToSql for Vec<u8> {
+    fn to_sql(&self) -> Value {
+        Value::Blob(self.clone())
+    }
+}
+
+impl FromSql for Vec<u8> {
+    fn from_sql(value: &Value) -> Result<Self, FromSqlError> {
+        match value {
+            Value::Blob(b) => Ok(b.clone()),
+            other => Err(invalid_type("blob", other)),
+        }
+    }
+}
+
+impl ToSql for Value {
+    fn to_sql(&self) -> Value {
+        self.clone()
+    }
+}
+
+impl FromSql for Value {
+    fn from_sql(value: &Value) -> Result<Self, FromSqlError> {
+        Ok(value.clone())
+    }
+}
+
+/// `None` is NULL
+impl<T: ToSql> ToSql for Option<T> {
+    fn to_sql(&self) -> Value {
+        self.as_ref().map_or(Value::Null, ToSql::to_sql)
+    }
+}
+
+impl<T: FromSql> FromSql for Option<T> {
+    fn from_sql(value: &Value) -> Result<Self, FromSqlError> {
+        match value {
+            Value::Null => Ok(None),
+            other => T::from_sql(other).map(Some),
+        }
+    }
+}
+
+impl<T: ToSql + ?Sized> ToSql for &T {
+    fn to_sql(&self) -> Value {
+        (**self).to_sql()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_out_of_range_are_errors() {
+        assert_eq!(
+            u8::from_sql(&Value::Integer(300)),
+            Err(FromSqlError::OutOfRange {
+                value: 300,
+                target: "u8"
+            })
+        );
+        assert_eq!(
+            u64::from_sql(&Value::Integer(-1)),
+            Err(FromSqlError::OutOfRange {
+                value: -1,
+                target: "u64"
+            })
+        );
+        assert_eq!(u8::from_sql(&Value::Integer(255)), Ok(255));
+        assert_eq!(
+            FromSqlError::OutOfRange {
+                value: 300,
+                target: "u8"
+            }
+            .to_string(),
+            "300 is out of range for u8"
+        );
+    }
+
+    #[test]
+    fn integers_read_as_floats_only() {
+        assert_eq!(f64::from_sql(&Value::Integer(3)), Ok(3.0));
+        assert_eq!(f32::from_sql(&Value::Integer(-2)), Ok(-2.0));
+        assert_eq!(
+            i64::from_sql(&Value::Real(3.0)),
+            Err(FromSqlError::InvalidType {
+                expected: "integer",
+                actual: "real"
+            })
+        );
+    }
+
+    #[test]
+    fn null_reads_as_none_only() {
+        assert_eq!(Option::<i64>::from_sql(&Value::Null), Ok(None));
+        assert_eq!(Option::<String>::from_sql(&Value::Null), Ok(None));
+        assert_eq!(Option::<i64>::from_sql(&Value::Integer(7)), Ok(Some(7)));
+        assert_eq!(
+            Option::<i64>::from_sql(&Value::Text("7".to_string())),
+            Err(FromSqlError::InvalidType {
+                expected: "integer",
+                actual: "text"
+            })
+        );
+        assert_eq!(
+            String::from_sql(&Value::Null).unwrap_err().to_string(),
+            "expected text, got null"
+        );
+    }
+
+    #[test]
+    fn invalid_types_name_both_types() {
+        let error = Vec::<u8>::from_sql(&Value::Real(1.5)).unwrap_err();
+        assert_eq!(error.to_string(), "expected blob, got real");
+        let error = f64::from_sql(&Value::Blob(vec![])).unwrap_err();
+        assert_eq!(error.to_string(), "expected real, got blob");
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::{bail, Result};
+
+use super::{mmap::Mmap, Wal};
+
+/// Where database files live: the database itself, its `-journal` and
+/// its `-wal`, named by what the database was opened as
+pub trait Vfs {
+    /// Opens `path` to read and write it, creating an empty file when `create` is set
+    fn open(&self, path: &str, create: bool) -> Result<Box<dyn PageSource>>;
+    fn exists(&self, path: &str) -> Result<bool>;
+    fn delete(&self, path: &str) -> Result<()>;
+    /// The WAL of a database in WAL mode, `<path>-wal` with its wal-index
+    /// in `<path>-shm`
+    fn open_wal(&self, path: &str, page_size: u32, create: bool) -> Result<Option<Wal>> {
+        Wal::open(self, path, page_size, create)
+    }
+}
+
+/// An open file of a `Vfs`
+pub trait PageSource {
+    /// Fills `buf` from `offset`, it's an error to read past the end
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()>;
+    /// Returns once everything written is durable
+    fn sync(&mut self) -> Result<()>;
+    /// False when another connection holds a conflicting lock
+    fn lock(&mut self, lock: Lock) -> Result<bool>;
+    /// Locks one byte of a wal-index, `Reserved` is the same as `Exclusive`.
+    /// False when another connection holds a conflicting lock
+    fn lock_byte(&mut self, _offset: u64, _lock: Lock) -> Result<bool> {
+        Ok(true)
+    }
+    fn size(&self) -> Result<u64>;
+    fn set_size(&mut self, size: u64) -> Result<()>;
+    /// The content when the source holds it in memory, pages are borrowed
+    /// from there instead of read. A mapped file is read instead, a page
+    /// borrowed from the map would crash the process once the file shrinks
+    fn bytes(&self) -> Option<&[u8]> {
+        None
+    }
+    /// Keeps up to `len` bytes in memory where that's cheap, `read_at`
+    /// copies from there
+    fn map(&mut self, _len: u64) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The locks SQLite takes on a database file, from weakest to strongest:
+/// readers share one, one writer reserves the file while it prepares its
+/// changes, then needs it alone to write them
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Lock {
+    Unlocked,
+    Shared,
+    Reserved,
+    Exclusive,
+}
+
+/// Files on disk
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSystem;
+
+impl Vfs for FileSystem {
+    /// A file we may not write is opened read-only, writing it fails later
+    fn open(&self, path: &str, create: bool) -> Result<Box<dyn PageSource>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .open(path);
+        let file = match file {
+            Err(e) if !create && e.kind() == ErrorKind::PermissionDenied => File::open(path)?,
+            file => file?,
+        };
+        Ok(Box::new(FileSource {
+            file,
+            lock: Lock::Unlocked,
+            map: None,
+            new_in: create.then(|| directory(path)),
+        }))
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        Ok(Path::new(path).try_exists()?)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        Ok(fs::remove_file(path)?)
+    }
+}
+
+fn directory(path: &str) -> PathBuf {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+pub struct FileSource {
+    file: File,
+    lock: Lock,
+    /// The start of the file, see `PRAGMA mmap_size`
+    map: Option<Mmap>,
+    /// The directory of a file we may have created, its entry has to
+    /// survive a crash too
+    new_in: Option<PathBuf>,
+}
+
+impl FileSource {
+    /// Takes each lock between the one held and `lock` in turn
+    fn upgrade(&mut self, lock: Lock) -> Result<bool> {
+        if self.lock == Lock::Unlocked && lock > Lock::Unlocked {
+            if !lock::set(&self.file, PENDING_BYTE, 1, lock::SHARED)? {
+                return Ok(false);
+            }
+            let locked = lock::set(&self.file, SHARED_FIRST, SHARED_SIZE, lock::SHARED)?;
+            lock::set(&self.file, PENDING_BYTE, 1, lock::UNLOCKED)?;
+            if !locked {
+                return Ok(false);
+            }
+            self.lock = Lock::Shared;
+        }
+        if self.lock == Lock::Shared && lock > Lock::Shared {
+            if !lock::set(&self.file, RESERVED_BYTE, 1, lock::EXCLUSIVE)? {
+                return Ok(false);
+            }
+            self.lock = Lock::Reserved;
+        }
+        if self.lock == Lock::Reserved && lock == Lock::Exclusive {
+            let locked = lock::set(&self.file, PENDING_BYTE, 1, lock::EXCLUSIVE)?
+                && lock::set(&self.file, SHARED_FIRST, SHARED_SIZE, lock::EXCLUSIVE)?;
+            if !locked {
+                lock::set(&self.file, PENDING_BYTE, 1, lock::UNLOCKED)?;
+                return Ok(false);
+            }
+            self.lock = Lock::Exclusive;
+        }
+        Ok(true)
+    }
+}
+
+/// SQLite's lock bytes, in the page at 1 GiB that no database uses
+/// See https://www.sqlite.org/fileformat.html#the_lock_byte_page
+const PENDING_BYTE: u64 = 0x40000000;
+const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+const SHARED_SIZE: u64 = 510;
+
+impl PageSource for FileSource {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        if self
+            .map
+            .as_ref()
+            .is_some_and(|it| it.read(offset as usize, buf))
+        {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        if let Some(dir) = self.new_in.take() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Readers share the SHARED bytes, unless a writer holds PENDING. A
+    /// writer holds RESERVED to keep other writers out, then PENDING to keep
+    /// new readers out, and waits for none of the current ones to be left.
+    /// When a lock can't be taken the previous one is kept
+    fn lock(&mut self, lock: Lock) -> Result<bool> {
+        if lock < self.lock {
+            if lock == Lock::Unlocked {
+                lock::set(&self.file, PENDING_BYTE, SHARED_SIZE + 2, lock::UNLOCKED)?;
+            } else {
+                lock::set(&self.file, SHARED_FIRST, SHARED_SIZE, lock::SHARED)?;
+                let len = if lock == Lock::Shared { 2 } else { 1 };
+                lock::set(&self.file, PENDING_BYTE, len, lock::UNLOCKED)?;
+            }
+            self.lock = lock;
+            return Ok(true);
+        }
+        let previous = self.lock;
+        if !self.upgrade(lock)? {
+            self.lock(previous)?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn lock_byte(&mut self, offset: u64, lock: Lock) -> Result<bool> {
+        let kind = match lock {
+            Lock::Unlocked => lock::UNLOCKED,
+            Lock::Shared => lock::SHARED,
+            Lock::Reserved | Lock::Exclusive => lock::EXCLUSIVE,
+        };
+        Ok(lock::set(&self.file, offset, 1, kind)?)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_size(&mut self, size: u64) -> Result<()> {
+        Ok(self.file.set_len(size)?)
+    }
+
+    /// Maps the file again, its size may have changed since
+    fn map(&mut self, len: u64) -> Result<()> {
+        self.map = None;
+        let len = len.min(self.size()?);
+        if len > 0 {
+            // Pages are read from the file when it can't be mapped.
+            // SAFETY: `read_at` only copies from the map, and pages are read
+            // holding SHARED. Connections only cut the file shorter holding
+            // EXCLUSIVE, which waits for every SHARED lock, those of this
+            // process too since they belong to the descriptor. In WAL mode
+            // a checkpoint only cuts pages no reader's snapshot has. A
+            // connection maps the file again once it changed. Like with
+            // SQLite, a process ignoring the locks can still make a read
+            // crash, that's what setting `mmap_size` accepts
+            self.map = unsafe { Mmap::map(&self.file, len as usize) }.ok();
+        }
+        Ok(())
+    }
+}
+
+/// Content of each file by path, shared with the sources open on them
+type MemoryFiles = HashMap<String, Rc<RefCell<Vec<u8>>>>;
+
+/// Files in memory, gone with the last `MemoryVfs` and source using them
+#[derive(Debug, Clone, Default)]
+pub struct MemoryVfs {
+    files: Rc<RefCell<MemoryFiles>>,
+}
+
+impl MemoryVfs {
+    /// Adds a file, replacing any of the same name
+    pub fn insert(&self, path: &str, content: Vec<u8>) {
+        let file = Rc::new(RefCell::new(content));
+        self.files.borrow_mut().insert(path.to_string(), file);
+    }
+
+    /// The paths of every file, sorted
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths = self.files.borrow().keys().cloned().collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn open(&self, path: &str, create: bool) -> Result<Box<dyn PageSource>> {
+        let mut files = self.files.borrow_mut();
+        let content = match files.get(path) {
+            Some(content) => content.clone(),
+            None if create => files.entry(path.to_string()).or_default().clone(),
+            None => bail!("unable to open database file: {}", path),
+        };
+        Ok(Box::new(MemoryFile { content }))
+    }
+
+    fn exists(&self, path: &str) -> Result<bool> {
+        Ok(self.files.borrow().contains_key(path))
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        match self.files.borrow_mut().remove(path) {
+            Some(_) => Ok(()),
+            None => bail!("no such file: {}", path),
+        }
+    }
+}
+
+/// A growable buffer, shared with the `MemoryVfs` it came from
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFile {
+    content: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemoryFile {
+    pub fn new(content: Vec<u8>) -> Self {
+        MemoryFile {
+            content: Rc::new(RefCell::new(content)),
+        }
+    }
+}
+
+impl PageSource for MemoryFile {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        read_slice(&self.content.borrow(), offset, buf)
+    }
+
+    /// Writing past the end fills the gap with zeros, like a file
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let mut content = self.content.borrow_mut();
+        let start = offset as usize;
+        if content.len() < start + buf.len() {
+            content.resize(start + buf.len(), 0);
+        }
+        content[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Only this process can see it
+    fn lock(&mut self, _lock: Lock) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.content.borrow().len() as u64)
+    }
+
+    fn set_size(&mut self, size: u64) -> Result<()> {
+        self.content.borrow_mut().resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+/// Read-only bytes, like a database embedded with `include_bytes!` or
+/// taken out of an archive. Pages are borrowed from them
+#[derive(Debug, Clone)]
+pub struct SliceSource {
+    content: Cow<'static, [u8]>,
+}
+
+impl SliceSource {
+    pub fn new(content: impl Into<Cow<'static, [u8]>>) -> Self {
+        SliceSource {
+            content: content.into(),
+        }
+    }
+}
+
+impl PageSource for SliceSource {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        read_slice(&self.content, offset, buf)
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> Result<()> {
+        bail!("attempt to write a readonly database")
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn lock(&mut self, _lock: Lock) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.content.len() as u64)
+    }
+
+    fn set_size(&mut self, _size: u64) -> Result<()> {
+        bail!("attempt to write a readonly database")
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(&self.content)
+    }
+}
+
+fn read_slice(content: &[u8], offset: u64, buf: &mut [u8]) -> Result<()> {
+    let start = offset as usize;
+    match content.get(start..start + buf.len()) {
+        Some(bytes) => buf.copy_from_slice(bytes),
+        None => bail!("read past the end of the file at {}", offset),
+    }
+    Ok(())
+}
+
+/// Advisory locks on byte ranges, the same ones SQLite takes. They are
+/// open file description locks: they belong to the descriptor, not the
+/// process, so connections of one process conflict with each other and
+/// closing another descriptor of the file keeps them. They still conflict
+/// with the POSIX locks of other processes
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+mod lock {
+    use std::{fs::File, io, os::unix::io::AsRawFd};
+
+    pub const SHARED: i16 = 0;
+    pub const EXCLUSIVE: i16 = 1;
+    pub const UNLOCKED: i16 = 2;
+    const F_OFD_SETLK: i32 = 37;
+
+    #[repr(C)]
+    struct Flock {
+        l_type: i16,
+        l_whence: i16,
+        l_start: i64,
+        l_len: i64,
+        l_pid: i32,
+    }
+
+    extern "C" {
+        fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    }
+
+    pub fn set(file: &File, offset: u64, len: u64, kind: i16) -> io::Result<bool> {
+        let flock = Flock {
+            l_type: kind,
+            l_whence: 0,
+            l_start: offset as i64,
+            l_len: len as i64,
+            l_pid: 0,
+        };
+        if unsafe { fcntl(file.as_raw_fd(), F_OFD_SETLK, &flock as *const Flock) } == 0 {
+            return Ok(true);
+        }
+        let error = io::Error::last_os_error();
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::PermissionDenied => Ok(false),
+            _ => Err(error),
+        }
+    }
+}
+
+/// Elsewhere nothing is locked, one process at a time
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+mod lock {
+    use std::{fs::File, io};
+
+    pub const SHARED: i16 = 0;
+    pub const EXCLUSIVE: i16 = 1;
+    pub const UNLOCKED: i16 = 2;
+
+    pub fn set(_file: &File, _offset: u64, _len: u64, _kind: i16) -> io::Result<bool> {
+        Ok(true)
+    }
+}
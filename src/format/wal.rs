@@ -0,0 +1,666 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    thread::sleep,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+
+use super::{
+    read_lock, u32_at, PageSource, Vfs, WalIndex, WalIndexHeader, CHECKPOINT_LOCK, READERS,
+    READ_MARK_UNUSED, WRITE_LOCK,
+};
+use crate::utils::random_u32;
+
+/// The 32 bytes at the start of a `-wal` file
+/// See https://www.sqlite.org/fileformat.html#the_write_ahead_log
+#[derive(Debug, Clone)]
+pub struct WalHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub page_size: u32,
+    pub checkpoint_sequence: u32,
+    pub salt: [u32; 2],
+    pub checksum: [u32; 2],
+}
+
+pub const WAL_HEADER_SIZE: u64 = 32;
+pub const WAL_FRAME_HEADER_SIZE: u64 = 24;
+pub const WAL_MAGIC: u32 = 0x377f0682;
+const WAL_VERSION: u32 = 3007000;
+
+/// How often and how long to wait for a lock held by another connection
+pub(super) const BUSY_RETRIES: usize = 200;
+pub(super) const BUSY_SLEEP: Duration = Duration::from_millis(10);
+
+impl WalHeader {
+    pub fn from_bytes(buf: &[u8; 32]) -> Self {
+        WalHeader {
+            magic: u32_at(buf, 0),
+            version: u32_at(buf, 4),
+            // 1 means 65536, same as the database header
+            page_size: match u32_at(buf, 8) {
+                1 => 65536,
+                n => n,
+            },
+            checkpoint_sequence: u32_at(buf, 12),
+            salt: [u32_at(buf, 16), u32_at(buf, 20)],
+            checksum: [u32_at(buf, 24), u32_at(buf, 28)],
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut buf = [0; 32];
+        buf[..4].copy_from_slice(&self.magic.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.version.to_be_bytes());
+        buf[8..12].copy_from_slice(&(self.page_size & 0xffff | self.page_size >> 16).to_be_bytes());
+        buf[12..16].copy_from_slice(&self.checkpoint_sequence.to_be_bytes());
+        buf[16..20].copy_from_slice(&self.salt[0].to_be_bytes());
+        buf[20..24].copy_from_slice(&self.salt[1].to_be_bytes());
+        buf[24..28].copy_from_slice(&self.checksum[0].to_be_bytes());
+        buf[28..32].copy_from_slice(&self.checksum[1].to_be_bytes());
+        buf
+    }
+
+    /// The low bit of the magic number picks the byte order of checksum words
+    pub fn big_endian_checksum(&self) -> bool {
+        self.magic & 1 == 1
+    }
+
+    fn is_valid(&self, buf: &[u8; 32], page_size: u32) -> bool {
+        self.magic & !1 == WAL_MAGIC
+            && self.page_size == page_size
+            && wal_checksum(&buf[..24], [0, 0], self.big_endian_checksum()) == self.checksum
+    }
+}
+
+/// Fletcher-like checksum used by the WAL, continued from `seed`
+pub fn wal_checksum(buf: &[u8], seed: [u32; 2], big_endian: bool) -> [u32; 2] {
+    let [mut s0, mut s1] = seed;
+    for words in buf.chunks_exact(8) {
+        let word = |i: usize| {
+            let bytes = [words[i], words[i + 1], words[i + 2], words[i + 3]];
+            if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        s0 = s0.wrapping_add(word(0)).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(4)).wrapping_add(s0);
+    }
+    [s0, s1]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckpointMode {
+    /// Copy what no reader needs anymore, without waiting on anyone
+    Passive,
+    /// Also wait for the writer, so everything can be copied
+    Full,
+    /// Like FULL, then wait until readers stop using the WAL
+    Restart,
+    /// Like RESTART, then empty the WAL file
+    Truncate,
+}
+
+/// The three columns of `PRAGMA wal_checkpoint`, -1 when nothing was attempted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CheckpointResult {
+    pub busy: bool,
+    pub log_frames: i64,
+    pub checkpointed: i64,
+}
+
+impl CheckpointResult {
+    pub const BUSY: CheckpointResult = CheckpointResult {
+        busy: true,
+        log_frames: -1,
+        checkpointed: -1,
+    };
+}
+
+/// A connection's view of the `-wal` file: the frames committed when it started
+/// reading, newer than what's in the database file. The wal-index in `-shm`
+/// coordinates it with other connections, SQLite's included
+pub struct Wal {
+    file: Box<dyn PageSource>,
+    index: WalIndex,
+    pub header: WalHeader,
+    /// Latest committed frame of each page, as the offset of its data in the file
+    frames: HashMap<u32, u64>,
+    /// The page of every committed frame, in order
+    pages: Vec<u32>,
+    /// Frames up to and including the last commit
+    pub frame_count: u64,
+    /// Size of the database in pages after the last commit
+    pub database_size: Option<u32>,
+    /// Checksum of the last committed frame, the next frame continues it
+    checksum: [u32; 2],
+    /// The reader slot holding our snapshot, so checkpoints leave its pages alone
+    reader: Option<usize>,
+    /// The wal-index header our frames were read at
+    snapshot: Option<WalIndexHeader>,
+}
+
+impl Wal {
+    /// `None` when there is no WAL, unless `create` asks for an empty one
+    pub fn open(
+        vfs: &(impl Vfs + ?Sized),
+        path: &str,
+        page_size: u32,
+        create: bool,
+    ) -> Result<Option<Self>> {
+        let wal_path = format!("{path}-wal");
+        if !create && !vfs.exists(&wal_path)? {
+            return Ok(None);
+        }
+        let mut wal = Wal {
+            file: vfs.open(&wal_path, create)?,
+            index: WalIndex::open(vfs, &format!("{path}-shm"))?,
+            header: WalHeader::from_bytes(&[0; 32]),
+            frames: HashMap::new(),
+            pages: vec![],
+            frame_count: 0,
+            database_size: None,
+            checksum: [0, 0],
+            reader: None,
+            snapshot: None,
+        };
+        // The page size the index was built for wins over a stale database header
+        wal.header.page_size = page_size;
+        wal.begin_read()?;
+        Ok(Some(wal))
+    }
+
+    /// Takes a reader slot marked at the last commit, then reads the frames up
+    /// to it. True when they changed since the last time
+    pub fn begin_read(&mut self) -> Result<bool> {
+        self.end_read()?;
+        for attempt in 0..BUSY_RETRIES {
+            if attempt > 0 {
+                sleep(BUSY_SLEEP);
+            }
+            let Some(header) = self.index.header()? else {
+                self.recover()?;
+                continue;
+            };
+            if let Some(reader) = self.lock_reader(header.max_frame)? {
+                // Nothing may have moved while we were taking the slot
+                if self.index.header()?.as_ref() == Some(&header)
+                    && self.index.checkpoint_info()?.read_marks[reader] == header.max_frame
+                {
+                    self.reader = Some(reader);
+                    let changed = self.snapshot.as_ref() != Some(&header);
+                    if changed {
+                        self.read_frames(header.max_frame, Some(header.salt))?;
+                        self.snapshot = Some(header);
+                    }
+                    return Ok(changed);
+                }
+                self.index.unlock(read_lock(reader))?;
+            }
+        }
+        bail!("database is locked")
+    }
+
+    pub fn end_read(&mut self) -> Result<()> {
+        if let Some(reader) = self.reader.take() {
+            self.index.unlock(read_lock(reader))?;
+        }
+        Ok(())
+    }
+
+    /// Shares a slot already marked at `max_frame`, or takes over a free one
+    fn lock_reader(&mut self, max_frame: u32) -> Result<Option<usize>> {
+        let info = self.index.checkpoint_info()?;
+        for reader in 1..READERS {
+            if info.read_marks[reader] == max_frame && self.index.lock(read_lock(reader), false)? {
+                return Ok(Some(reader));
+            }
+        }
+        for reader in 1..READERS {
+            if self.index.lock(read_lock(reader), true)? {
+                self.index.set_read_mark(reader, max_frame)?;
+                self.index.lock(read_lock(reader), false)?;
+                return Ok(Some(reader));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Rebuilds the wal-index from the WAL, after a crash or once every
+    /// connection went away. Everyone else is locked out meanwhile
+    fn recover(&mut self) -> Result<()> {
+        let mut locked = vec![];
+        for slot in WRITE_LOCK..read_lock(READERS) {
+            if !self.index.lock(slot, true)? {
+                break;
+            }
+            locked.push(slot);
+        }
+        let result = if locked.len() as u64 == read_lock(READERS) && self.index.header()?.is_none()
+        {
+            self.rebuild_index()
+        } else {
+            Ok(())
+        };
+        for slot in locked {
+            self.index.unlock(slot)?;
+        }
+        result
+    }
+
+    fn rebuild_index(&mut self) -> Result<()> {
+        self.read_frames(u32::MAX, None)?;
+        for (i, page_number) in self.pages.iter().enumerate() {
+            self.index.append(i as u32 + 1, *page_number)?;
+        }
+        let max_frame = self.frame_count as u32;
+        self.index.set_backfill(0)?;
+        self.index.set_backfill_attempted(max_frame)?;
+        self.index.set_read_mark(0, 0)?;
+        for reader in 1..READERS {
+            let mark = if reader == 1 && max_frame > 0 {
+                max_frame
+            } else {
+                READ_MARK_UNUSED
+            };
+            self.index.set_read_mark(reader, mark)?;
+        }
+        self.index.write_header(&WalIndexHeader {
+            change: 0,
+            big_endian_checksum: self.header.big_endian_checksum(),
+            page_size: self.header.page_size,
+            max_frame,
+            page_count: self.database_size.unwrap_or(0),
+            frame_checksum: self.checksum,
+            salt: self.header.salt,
+        })
+    }
+
+    /// Frames count only up to the last valid commit frame, anything after it
+    /// belongs to a transaction that never finished. A WAL started over since
+    /// `salt` was read holds nothing of ours
+    fn read_frames(&mut self, max_frame: u32, salt: Option<[u32; 2]>) -> Result<()> {
+        let page_size = self.header.page_size;
+        self.snapshot = None;
+        self.frames.clear();
+        self.pages.clear();
+        self.frame_count = 0;
+        self.database_size = None;
+
+        let mut header_buf = [0; 32];
+        if self.file.read_at(0, &mut header_buf).is_err() {
+            return Ok(());
+        }
+        let header = WalHeader::from_bytes(&header_buf);
+        if !header.is_valid(&header_buf, page_size) || salt.is_some_and(|it| it != header.salt) {
+            return Ok(());
+        }
+        self.header = header;
+        self.checksum = self.header.checksum;
+
+        let page_size = page_size as u64;
+        let big_endian = self.header.big_endian_checksum();
+        let mut checksum = self.header.checksum;
+        let mut pending = vec![];
+        let mut frame_header = [0; WAL_FRAME_HEADER_SIZE as usize];
+        let mut page = vec![0; page_size as usize];
+
+        for frame in 0..max_frame as u64 {
+            let offset = Wal::frame_offset(frame, page_size);
+            if self.file.read_at(offset, &mut frame_header).is_err()
+                || self
+                    .file
+                    .read_at(offset + WAL_FRAME_HEADER_SIZE, &mut page)
+                    .is_err()
+            {
+                break;
+            }
+            let page_number = u32_at(&frame_header, 0);
+            let database_size = u32_at(&frame_header, 4);
+            if [u32_at(&frame_header, 8), u32_at(&frame_header, 12)] != self.header.salt
+                || page_number == 0
+            {
+                break;
+            }
+            checksum = wal_checksum(&frame_header[..8], checksum, big_endian);
+            checksum = wal_checksum(&page, checksum, big_endian);
+            if checksum != [u32_at(&frame_header, 16), u32_at(&frame_header, 20)] {
+                break;
+            }
+
+            pending.push(page_number);
+            if database_size != 0 {
+                for page_number in pending.drain(..) {
+                    let data_offset = Wal::frame_offset(self.pages.len() as u64, page_size)
+                        + WAL_FRAME_HEADER_SIZE;
+                    self.frames.insert(page_number, data_offset);
+                    self.pages.push(page_number);
+                }
+                self.frame_count = frame + 1;
+                self.database_size = Some(database_size);
+                self.checksum = checksum;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where frame `n` (from 0) starts, header included
+    pub fn frame_offset(n: u64, page_size: u64) -> u64 {
+        WAL_HEADER_SIZE + n * (WAL_FRAME_HEADER_SIZE + page_size)
+    }
+
+    /// The newest committed version of a page, if the WAL has one
+    pub fn read_page(&mut self, page_number: u32) -> Result<Option<Vec<u8>>> {
+        let Some(offset) = self.frames.get(&page_number) else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; self.header.page_size as usize];
+        self.file.read_at(*offset, &mut buf)?;
+        Ok(Some(buf))
+    }
+
+    pub fn contains(&self, page_number: u32) -> bool {
+        self.frames.contains_key(&page_number)
+    }
+
+    /// Appends a transaction as frames, the last one marking the commit.
+    /// Fails when another connection committed since our snapshot was taken
+    pub fn commit(&mut self, pages: &BTreeMap<u32, Vec<u8>>, database_size: u32) -> Result<()> {
+        if !self.lock_wait(WRITE_LOCK)? {
+            bail!("database is locked");
+        }
+        let result = self.write_frames(pages, database_size);
+        self.index.unlock(WRITE_LOCK)?;
+        result
+    }
+
+    fn write_frames(&mut self, pages: &BTreeMap<u32, Vec<u8>>, database_size: u32) -> Result<()> {
+        let Some(mut header) = self.index.header()? else {
+            bail!("database is locked");
+        };
+        if header.max_frame as u64 != self.frame_count {
+            bail!("database is locked");
+        }
+        self.index.truncate(header.max_frame)?;
+        if header.max_frame == 0 {
+            self.restart(&mut header)?;
+        } else if self.index.checkpoint_info()?.backfill == header.max_frame {
+            // Everything is in the database file, start over if no one reads the WAL
+            let readers = self.lock_readers()?;
+            if readers.len() == READERS - 1 {
+                self.restart(&mut header)?;
+            }
+            self.release(readers)?;
+        }
+
+        let page_size = self.header.page_size as u64;
+        let big_endian = self.header.big_endian_checksum();
+        let mut checksum = self.checksum;
+        let mut buf =
+            Vec::with_capacity(pages.len() * (WAL_FRAME_HEADER_SIZE + page_size) as usize);
+        for (i, (page_number, page)) in pages.iter().enumerate() {
+            let commit = if i + 1 == pages.len() {
+                database_size
+            } else {
+                0
+            };
+            let mut frame_header = [0; WAL_FRAME_HEADER_SIZE as usize];
+            frame_header[..4].copy_from_slice(&page_number.to_be_bytes());
+            frame_header[4..8].copy_from_slice(&commit.to_be_bytes());
+            frame_header[8..12].copy_from_slice(&self.header.salt[0].to_be_bytes());
+            frame_header[12..16].copy_from_slice(&self.header.salt[1].to_be_bytes());
+            checksum = wal_checksum(&frame_header[..8], checksum, big_endian);
+            checksum = wal_checksum(page, checksum, big_endian);
+            frame_header[16..20].copy_from_slice(&checksum[0].to_be_bytes());
+            frame_header[20..24].copy_from_slice(&checksum[1].to_be_bytes());
+            buf.extend_from_slice(&frame_header);
+            buf.extend_from_slice(page);
+        }
+        self.file
+            .write_at(Wal::frame_offset(self.frame_count, page_size), &buf)?;
+        self.file.sync()?;
+
+        // Readers only see the frames once the header counts them
+        for page_number in pages.keys() {
+            let frame = self.pages.len() as u64;
+            self.index.append(frame as u32 + 1, *page_number)?;
+            self.frames.insert(
+                *page_number,
+                Wal::frame_offset(frame, page_size) + WAL_FRAME_HEADER_SIZE,
+            );
+            self.pages.push(*page_number);
+        }
+        self.frame_count = self.pages.len() as u64;
+        self.database_size = Some(database_size);
+        self.checksum = checksum;
+        // An index recovered from an empty WAL doesn't know the page size yet
+        header.change = header.change.wrapping_add(1);
+        header.big_endian_checksum = big_endian;
+        header.page_size = self.header.page_size;
+        header.max_frame = self.frame_count as u32;
+        header.page_count = database_size;
+        header.frame_checksum = checksum;
+        header.salt = self.header.salt;
+        self.index.write_header(&header)?;
+        self.snapshot = Some(header);
+        Ok(())
+    }
+
+    /// Writes a new WAL header with new salts, so frames already in the file
+    /// are ignored from now on and get overwritten
+    fn restart(&mut self, header: &mut WalIndexHeader) -> Result<()> {
+        let mut wal_header = WalHeader {
+            magic: WAL_MAGIC | cfg!(target_endian = "big") as u32,
+            version: WAL_VERSION,
+            page_size: self.header.page_size,
+            checkpoint_sequence: self.header.checkpoint_sequence.wrapping_add(1),
+            salt: [header.salt[0].wrapping_add(1), random_u32()],
+            checksum: [0, 0],
+        };
+        wal_header.checksum = wal_checksum(
+            &wal_header.to_bytes()[..24],
+            [0, 0],
+            wal_header.big_endian_checksum(),
+        );
+        self.file.write_at(0, &wal_header.to_bytes())?;
+
+        self.checksum = wal_header.checksum;
+        self.header = wal_header;
+        self.frames.clear();
+        self.pages.clear();
+        self.frame_count = 0;
+        if header.max_frame > 0 {
+            self.index.set_backfill(0)?;
+            self.index.set_backfill_attempted(0)?;
+            for reader in 1..READERS {
+                self.index
+                    .set_read_mark(reader, if reader == 1 { 0 } else { READ_MARK_UNUSED })?;
+            }
+            if let Some(reader) = self.reader {
+                self.index.set_read_mark(reader, 0)?;
+            }
+        }
+        header.max_frame = 0;
+        Ok(())
+    }
+
+    /// Copies frames no reader needs anymore back into the database file,
+    /// then starts reading again from the new state
+    pub fn checkpoint(
+        &mut self,
+        db: &mut dyn PageSource,
+        mode: CheckpointMode,
+    ) -> Result<CheckpointResult> {
+        self.end_read()?;
+        let mut locked = vec![];
+        let result = self.backfill(db, mode, &mut locked);
+        for slot in locked {
+            self.index.unlock(slot)?;
+        }
+        self.begin_read()?;
+        result
+    }
+
+    fn backfill(
+        &mut self,
+        db: &mut dyn PageSource,
+        mode: CheckpointMode,
+        locked: &mut Vec<u64>,
+    ) -> Result<CheckpointResult> {
+        if !self.index.lock(CHECKPOINT_LOCK, true)? {
+            return Ok(CheckpointResult::BUSY);
+        }
+        locked.push(CHECKPOINT_LOCK);
+        if mode != CheckpointMode::Passive {
+            if !self.lock_wait(WRITE_LOCK)? {
+                return Ok(CheckpointResult::BUSY);
+            }
+            locked.push(WRITE_LOCK);
+        }
+        let Some(mut header) = self.index.header()? else {
+            return Ok(CheckpointResult::BUSY);
+        };
+        let info = self.index.checkpoint_info()?;
+
+        // Frames past a reader's mark may hide pages it still reads from the database file
+        let mut safe = header.max_frame;
+        for reader in 1..READERS {
+            let mark = info.read_marks[reader];
+            if safe <= mark {
+                continue;
+            }
+            if self.index.lock(read_lock(reader), true)? {
+                let mark = if reader == 1 { safe } else { READ_MARK_UNUSED };
+                self.index.set_read_mark(reader, mark)?;
+                self.index.unlock(read_lock(reader))?;
+            } else {
+                safe = mark;
+            }
+        }
+
+        // Readers of slot 0 use only the database file, nothing is copied under them
+        let wait = mode != CheckpointMode::Passive;
+        if info.backfill < safe
+            && (self.index.lock(read_lock(0), true)? || wait && self.lock_wait(read_lock(0))?)
+        {
+            let result = self.copy_frames(db, &header, info.backfill, safe);
+            self.index.unlock(read_lock(0))?;
+            result?;
+        }
+
+        let checkpointed = self.index.checkpoint_info()?.backfill;
+        let busy = wait && checkpointed < header.max_frame;
+        let mut result = CheckpointResult {
+            busy,
+            log_frames: header.max_frame as i64,
+            checkpointed: checkpointed as i64,
+        };
+        if busy || !matches!(mode, CheckpointMode::Restart | CheckpointMode::Truncate) {
+            return Ok(result);
+        }
+
+        // Wait for readers to be done with the WAL, the next writer starts it over
+        let mut readers = self.lock_readers()?;
+        for _ in 0..BUSY_RETRIES {
+            if readers.len() == READERS - 1 {
+                break;
+            }
+            self.release(readers)?;
+            sleep(BUSY_SLEEP);
+            readers = self.lock_readers()?;
+        }
+        if readers.len() < READERS - 1 {
+            result.busy = true;
+        } else if mode == CheckpointMode::Truncate {
+            self.index.set_backfill(0)?;
+            self.index.set_backfill_attempted(0)?;
+            for reader in 1..READERS {
+                self.index
+                    .set_read_mark(reader, if reader == 1 { 0 } else { READ_MARK_UNUSED })?;
+            }
+            header.change = header.change.wrapping_add(1);
+            header.max_frame = 0;
+            header.frame_checksum = [0, 0];
+            self.index.write_header(&header)?;
+            self.file.set_size(0)?;
+            self.file.sync()?;
+            result.log_frames = 0;
+            result.checkpointed = 0;
+        }
+        self.release(readers)?;
+        Ok(result)
+    }
+
+    /// Writes the newest frame of each page up to `safe` into the database file
+    fn copy_frames(
+        &mut self,
+        db: &mut dyn PageSource,
+        header: &WalIndexHeader,
+        backfill: u32,
+        safe: u32,
+    ) -> Result<()> {
+        self.index.set_backfill_attempted(safe)?;
+        self.read_frames(safe, Some(header.salt))?;
+        let page_size = self.header.page_size as u64;
+        let latest: BTreeMap<u32, u64> = self
+            .pages
+            .iter()
+            .enumerate()
+            .skip(backfill as usize)
+            .map(|(frame, page_number)| (*page_number, frame as u64))
+            .collect();
+        // The WAL has to be durable before the database file changes
+        self.file.sync()?;
+        let mut page = vec![0; page_size as usize];
+        for (page_number, frame) in latest {
+            self.file.read_at(
+                Wal::frame_offset(frame, page_size) + WAL_FRAME_HEADER_SIZE,
+                &mut page,
+            )?;
+            db.write_at((page_number as u64 - 1) * page_size, &page)?;
+        }
+        if safe == header.max_frame {
+            db.set_size(header.page_count as u64 * page_size)?;
+        }
+        db.sync()?;
+        self.index.set_backfill(safe)
+    }
+
+    /// Takes every reader slot it can, stopping at the first one in use
+    fn lock_readers(&mut self) -> Result<Vec<usize>> {
+        let mut locked = vec![];
+        for reader in 1..READERS {
+            if !self.index.lock(read_lock(reader), true)? {
+                break;
+            }
+            locked.push(reader);
+        }
+        Ok(locked)
+    }
+
+    /// Unlocks reader slots, going back to sharing the one holding our snapshot
+    fn release(&mut self, readers: Vec<usize>) -> Result<()> {
+        for reader in readers {
+            if Some(reader) == self.reader {
+                self.index.lock(read_lock(reader), false)?;
+            } else {
+                self.index.unlock(read_lock(reader))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits a little for a lock another connection holds, like a busy timeout
+    fn lock_wait(&mut self, slot: u64) -> Result<bool> {
+        for _ in 0..BUSY_RETRIES {
+            if self.index.lock(slot, true)? {
+                return Ok(true);
+            }
+            sleep(BUSY_SLEEP);
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{bail, Result};
+
+use super::{native_u32_at, u32_at, wal_checksum, Lock, PageSource, Vfs};
+
+/// The wal-index lives in `<path>-shm`, shared by every connection to the database.
+/// Unlike the rest of the format it's in native byte order
+/// See https://www.sqlite.org/walformat.html#the_wal_index_file_format
+const VERSION: u32 = 3007000;
+/// Two copies of the header, then the checkpoint info
+const HEADER_SIZE: u64 = 136;
+const BLOCK_SIZE: u64 = 32768;
+/// Frames indexed by each 32KB block, the first one also holds the header
+const BLOCK_FRAMES: u32 = 4096;
+const FIRST_BLOCK_FRAMES: u32 = BLOCK_FRAMES - HEADER_SIZE as u32 / 4;
+const HASH_SLOTS: usize = 8192;
+
+/// A read mark no reader uses
+pub const READ_MARK_UNUSED: u32 = 0xffffffff;
+pub const READERS: usize = 5;
+
+/// Lock slots, as bytes 120 to 127 of the file, with the "dead man switch" after them
+pub const WRITE_LOCK: u64 = 0;
+pub const CHECKPOINT_LOCK: u64 = 1;
+pub const RECOVER_LOCK: u64 = 2;
+const DMS_LOCK: u64 = 8;
+const LOCK_OFFSET: u64 = 120;
+
+pub fn read_lock(reader: usize) -> u64 {
+    3 + reader as u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalIndexHeader {
+    /// Bumped on every commit, so readers can tell the WAL changed
+    pub change: u32,
+    pub big_endian_checksum: bool,
+    pub page_size: u32,
+    /// Last committed frame, counted from 1, 0 for an empty WAL
+    pub max_frame: u32,
+    /// Size of the database in pages
+    pub page_count: u32,
+    /// Checksum of the last committed frame
+    pub frame_checksum: [u32; 2],
+    pub salt: [u32; 2],
+}
+
+impl WalIndexHeader {
+    /// `None` unless the header was fully written
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let checksum = wal_checksum(&buf[..40], [0, 0], cfg!(target_endian = "big"));
+        if native_u32_at(buf, 0) != VERSION
+            || buf[12] != 1
+            || checksum != [native_u32_at(buf, 40), native_u32_at(buf, 44)]
+        {
+            return None;
+        }
+        Some(WalIndexHeader {
+            change: native_u32_at(buf, 8),
+            big_endian_checksum: buf[13] == 1,
+            // 1 means 65536 here too
+            page_size: match u16::from_ne_bytes([buf[14], buf[15]]) {
+                1 => 65536,
+                n => n as u32,
+            },
+            max_frame: native_u32_at(buf, 16),
+            page_count: native_u32_at(buf, 20),
+            frame_checksum: [native_u32_at(buf, 24), native_u32_at(buf, 28)],
+            // Salts are kept as they are in the WAL, big-endian
+            salt: [u32_at(buf, 32), u32_at(buf, 36)],
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; 48] {
+        let mut buf = [0; 48];
+        buf[..4].copy_from_slice(&VERSION.to_ne_bytes());
+        buf[8..12].copy_from_slice(&self.change.to_ne_bytes());
+        buf[12] = 1;
+        buf[13] = self.big_endian_checksum as u8;
+        buf[14..16].copy_from_slice(
+            &((self.page_size & 0xff00 | self.page_size >> 16) as u16).to_ne_bytes(),
+        );
+        buf[16..20].copy_from_slice(&self.max_frame.to_ne_bytes());
+        buf[20..24].copy_from_slice(&self.page_count.to_ne_bytes());
+        buf[24..28].copy_from_slice(&self.frame_checksum[0].to_ne_bytes());
+        buf[28..32].copy_from_slice(&self.frame_checksum[1].to_ne_bytes());
+        buf[32..36].copy_from_slice(&self.salt[0].to_be_bytes());
+        buf[36..40].copy_from_slice(&self.salt[1].to_be_bytes());
+        let checksum = wal_checksum(&buf[..40], [0, 0], cfg!(target_endian = "big"));
+        buf[40..44].copy_from_slice(&checksum[0].to_ne_bytes());
+        buf[44..48].copy_from_slice(&checksum[1].to_ne_bytes());
+        buf
+    }
+}
+
+/// How far checkpoints got and where each reader's snapshot ends
+#[derive(Debug, Clone)]
+pub struct CheckpointInfo {
+    /// Frames already copied back into the database file
+    pub backfill: u32,
+    pub read_marks: [u32; READERS],
+    pub backfill_attempted: u32,
+}
+
+pub struct WalIndex {
+    file: Box<dyn PageSource>,
+}
+
+impl WalIndex {
+    /// Every connection holds the dead man switch shared, the first one to
+    /// open the file clears whatever a crash may have left in it
+    pub fn open(vfs: &(impl Vfs + ?Sized), path: &str) -> Result<Self> {
+        let mut index = WalIndex {
+            file: vfs.open(path, true)?,
+        };
+        if index.lock(DMS_LOCK, true)? {
+            index.file.set_size(0)?;
+        }
+        if !index.lock(DMS_LOCK, false)? {
+            bail!("database is locked");
+        }
+        if index.file.size()? < BLOCK_SIZE {
+            index.file.set_size(BLOCK_SIZE)?;
+        }
+        Ok(index)
+    }
+
+    /// `None` while the index has to be rebuilt from the WAL
+    pub fn header(&mut self) -> Result<Option<WalIndexHeader>> {
+        let mut buf = [0; 96];
+        self.read_at(0, &mut buf)?;
+        // A writer could be halfway through, it writes the second copy first
+        if buf[..48] != buf[48..] {
+            return Ok(None);
+        }
+        Ok(WalIndexHeader::from_bytes(&buf[..48]))
+    }
+
+    pub fn write_header(&mut self, header: &WalIndexHeader) -> Result<()> {
+        let buf = header.to_bytes();
+        self.write_at(48, &buf)?;
+        self.write_at(0, &buf)
+    }
+
+    pub fn checkpoint_info(&mut self) -> Result<CheckpointInfo> {
+        let mut buf = [0; 40];
+        self.read_at(96, &mut buf)?;
+        Ok(CheckpointInfo {
+            backfill: native_u32_at(&buf, 0),
+            read_marks: [
+                native_u32_at(&buf, 4),
+                native_u32_at(&buf, 8),
+                native_u32_at(&buf, 12),
+                native_u32_at(&buf, 16),
+                native_u32_at(&buf, 20),
+            ],
+            backfill_attempted: native_u32_at(&buf, 32),
+        })
+    }
+
+    /// Fields are written one at a time, other connections may be changing the rest
+    pub fn set_backfill(&mut self, frames: u32) -> Result<()> {
+        self.write_at(96, &frames.to_ne_bytes())
+    }
+
+    pub fn set_read_mark(&mut self, reader: usize, frame: u32) -> Result<()> {
+        self.write_at(100 + reader as u64 * 4, &frame.to_ne_bytes())
+    }
+
+    pub fn set_backfill_attempted(&mut self, frames: u32) -> Result<()> {
+        self.write_at(128, &frames.to_ne_bytes())
+    }
+
+    /// Records that `frame` (counted from 1) holds `page_number`
+    pub fn append(&mut self, frame: u32, page_number: u32) -> Result<()> {
+        let (block, first) = WalIndex::block(frame);
+        let pages_at = block * BLOCK_SIZE + if block == 0 { HEADER_SIZE } else { 0 };
+        let hash_at = block * BLOCK_SIZE + BLOCK_FRAMES as u64 * 4;
+        if self.file.size()? < (block + 1) * BLOCK_SIZE {
+            self.file.set_size((block + 1) * BLOCK_SIZE)?;
+        }
+        let entry = frame - first;
+        // The block may still hold frames from before the WAL was restarted
+        if entry == 1 {
+            let size = hash_at + HASH_SLOTS as u64 * 2 - pages_at;
+            self.write_at(pages_at, &vec![0; size as usize])?;
+        }
+
+        // Open addressing, the slot holds the entry number
+        let mut hash = vec![0; HASH_SLOTS * 2];
+        self.read_at(hash_at, &mut hash)?;
+        let mut slot = (page_number as usize).wrapping_mul(383) & (HASH_SLOTS - 1);
+        while hash[slot * 2] != 0 || hash[slot * 2 + 1] != 0 {
+            slot = (slot + 1) & (HASH_SLOTS - 1);
+        }
+        self.write_at(
+            pages_at + (entry as u64 - 1) * 4,
+            &page_number.to_ne_bytes(),
+        )?;
+        self.write_at(hash_at + slot as u64 * 2, &(entry as u16).to_ne_bytes())
+    }
+
+    /// Drops entries past `max_frame`, left by a writer that never committed
+    pub fn truncate(&mut self, max_frame: u32) -> Result<()> {
+        let (block, first) = WalIndex::block(max_frame + 1);
+        let last = max_frame - first;
+        // A fresh block is cleared when its first entry goes in
+        if last == 0 || self.file.size()? < (block + 1) * BLOCK_SIZE {
+            return Ok(());
+        }
+        let pages_at = block * BLOCK_SIZE + if block == 0 { HEADER_SIZE } else { 0 };
+        let hash_at = block * BLOCK_SIZE + BLOCK_FRAMES as u64 * 4;
+        let mut hash = vec![0; HASH_SLOTS * 2];
+        self.read_at(hash_at, &mut hash)?;
+        for slot in hash.chunks_exact_mut(2) {
+            if u16::from_ne_bytes([slot[0], slot[1]]) as u32 > last {
+                slot.fill(0);
+            }
+        }
+        self.write_at(hash_at, &hash)?;
+        let size = hash_at - (pages_at + last as u64 * 4);
+        self.write_at(pages_at + last as u64 * 4, &vec![0; size as usize])
+    }
+
+    /// The block indexing a frame and the frame before its first entry
+    fn block(frame: u32) -> (u64, u32) {
+        if frame <= FIRST_BLOCK_FRAMES {
+            return (0, 0);
+        }
+        let block = (frame - FIRST_BLOCK_FRAMES - 1) / BLOCK_FRAMES + 1;
+        (
+            block as u64,
+            FIRST_BLOCK_FRAMES + (block - 1) * BLOCK_FRAMES,
+        )
+    }
+
+    /// Takes a lock without waiting, false if another connection holds it
+    pub fn lock(&mut self, slot: u64, exclusive: bool) -> Result<bool> {
+        let lock = if exclusive {
+            Lock::Exclusive
+        } else {
+            Lock::Shared
+        };
+        self.file.lock_byte(LOCK_OFFSET + slot, lock)
+    }
+
+    pub fn unlock(&mut self, slot: u64) -> Result<()> {
+        self.file.lock_byte(LOCK_OFFSET + slot, Lock::Unlocked)?;
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.file.write_at(offset, buf)
+    }
+}
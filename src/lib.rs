@@ -0,0 +1,23 @@
+//! Reads and writes SQLite database files. [`Connection`] runs SQL on one,
+//! the modules are the file format and the commands built on top of it.
+
+pub mod commands;
+pub mod expr;
+pub mod format;
+// The expression precedence climbing in the grammar expands to closures
+#[allow(clippy::redundant_closure_call)]
+pub mod parser;
+pub mod utils;
+
+mod connection;
+mod mapping;
+#[cfg(test)]
+mod testing;
+
+pub use connection::*;
+pub use mapping::*;
+pub use format::{FromSql, FromSqlError, ToSql, Value, ValueRef};
+
+// For `sql_struct!`
+#[doc(hidden)]
+pub use anyhow;
@@ -1,11 +1,16 @@
-use anyhow::{bail, Result};
-use format::{SqliteFile, Table};
+use anyhow::{anyhow, bail, Result};
 use itertools::Itertools;
-use parser::execute;
 
-mod format;
-mod parser;
-mod utils;
+use sqlite_starter_rust::{
+    commands,
+    format::{HotJournal, OpenOptions, SqliteFile},
+    Connection,
+};
+
+/// Like sqlite3, an interrupted transaction is rolled back before anything else
+const OPEN_OPTIONS: OpenOptions = OpenOptions {
+    hot_journal: HotJournal::Rollback,
+};
 
 fn main() -> Result<()> {
     // Parse arguments
@@ -18,46 +23,63 @@ fn main() -> Result<()> {
 
     // Parse command and act accordingly
     let command = &args[2];
+    let mut words = command.split_whitespace();
 
-    match command.as_str() {
+    match words.next().unwrap_or_default() {
         ".dbinfo" => {
-            let mut file = SqliteFile::open(&args[1])?;
-
-            println!("page size: {}", &file.header.page_size);
-
-            match file.read_page(1) {
-                Ok(page) => match Table::from_page(&page) {
-                    Ok(tables) => println!("number of tables: {}", tables.len()),
-                    Err(e) => println!("failed: {}", e),
-                },
-                Err(e) => println!("failed: {}", e),
-            }
+            let db = SqliteFile::open_with(&args[1], &OPEN_OPTIONS)?;
+            commands::dbinfo(&db, &mut std::io::stdout().lock())?;
         }
         ".tables" => {
-            let mut file = SqliteFile::open(&args[1])?;
-
-            match file.read_page(1) {
-                Ok(page) => match Table::from_page(&page) {
-                    Ok(tables) => println!(
-                        "{}",
-                        tables
-                            .iter()
-                            .map(|it| &it.name)
-                            .filter(|name| *name != "sqlite_sequence")
-                            .join(" ")
-                    ),
-                    // Ok(tables) => println!("{:#?}", tables),
-                    Err(e) => println!("failed: {}", e),
-                },
-                Err(e) => println!("failed: {}", e),
-            }
+            // `open` already read sqlite_schema
+            let db = SqliteFile::open_with(&args[1], &OPEN_OPTIONS)?;
+            println!(
+                "{}",
+                db.tables
+                    .iter()
+                    .map(|it| &it.name)
+                    .filter(|name| *name != "sqlite_sequence")
+                    .join(" ")
+            );
+        }
+        ".dump" => {
+            let mut db = SqliteFile::open_with(&args[1], &OPEN_OPTIONS)?;
+            // `.dump apples` or `.dump` `apples`
+            let table_name = words.next().or(args.get(3).map(String::as_str));
+            commands::dump(&mut db, table_name, &mut std::io::stdout().lock())?;
+        }
+        ".analyze" => {
+            let mut db = SqliteFile::open_with(&args[1], &OPEN_OPTIONS)?;
+            commands::analyze(&mut db, &mut std::io::stdout().lock())?;
+        }
+        ".freelist" => {
+            let mut db = SqliteFile::open_with(&args[1], &OPEN_OPTIONS)?;
+            commands::freelist(&mut db, &mut std::io::stdout().lock())?;
+        }
+        ".pagedump" | ".page" => {
+            let mut db = SqliteFile::open_with(&args[1], &OPEN_OPTIONS)?;
+            let page_number = words
+                .next()
+                .or(args.get(3).map(String::as_str))
+                .ok_or_else(|| anyhow!("Missing <page number>"))?
+                .parse()?;
+            commands::page_dump(&mut db, page_number, &mut std::io::stdout().lock())?;
         }
-        other => {
-            let lenght = other.len();
-            if lenght > 1 {
+        _ => {
+            let other = command.as_str();
+            if other.len() > 1 {
                 // if it's "quoted" -> send to sql parser
-                let mut db = SqliteFile::open(&args[1])?;
-                execute(&other, &mut db)?;
+                let connection = Connection::open_with(&args[1], &OPEN_OPTIONS)?;
+                for mut statement in connection.prepare_batch(other)? {
+                    let rows = statement.query(&[])?;
+                    // Only queries print, even without rows
+                    if !rows.column_names().is_empty() {
+                        println!(
+                            "{}",
+                            rows.map(|row| row.values().iter().join("|")).join("\n")
+                        );
+                    }
+                }
             } else {
                 bail!("Missing or invalid command passed: {}", command);
             }
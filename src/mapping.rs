@@ -0,0 +1,140 @@
+use anyhow::Result;
+
+use crate::{Row, Value};
+
+/// A struct read from a row, its fields matched to columns by name.
+/// `sql_struct!` implements it
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self>;
+}
+
+/// A struct bound to the named parameters `:field`, `@field` or `$field`.
+/// `sql_struct!` implements it
+pub trait ToParams {
+    /// Field names and their values, in declaration order
+    fn to_params(&self) -> Vec<(&'static str, Value)>;
+}
+
+/// Implements `FromRow` and `ToParams` for a struct with named fields, each
+/// with a `FromSql` and `ToSql` type, in place of serde's derives since the
+/// crate doesn't depend on serde. Columns are found by name in any case, so
+/// adding one to the table doesn't break reading:
+///
+/// ```text
+/// struct Apple { id: i64, name: String, color: Option<String> }
+/// sql_struct!(Apple { id, name, color });
+///
+/// connection.insert("apples", &apple)?;
+/// for apple in statement.query(&[])?.deserialize::<Apple>() { ... }
+/// ```
+#[macro_export]
+macro_rules! sql_struct {
+    ($name:ident { $($field:ident),* $(,)? }) => {
+        impl $crate::FromRow for $name {
+            fn from_row(row: &$crate::Row) -> $crate::anyhow::Result<Self> {
+                Ok($name {
+                    $($field: row.get(row.column_index(stringify!($field))?)?,)*
+                })
+            }
+        }
+
+        impl $crate::ToParams for $name {
+            fn to_params(&self) -> Vec<(&'static str, $crate::Value)> {
+                vec![$((stringify!($field), $crate::ToSql::to_sql(&self.$field)),)*]
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Connection;
+
+    #[derive(Debug, PartialEq)]
+    struct Apple {
+        id: i64,
+        name: String,
+        color: Option<String>,
+    }
+    sql_struct!(Apple { id, name, color });
+
+    fn apple(id: i64, name: &str, color: Option<&str>) -> Apple {
+        Apple {
+            id,
+            name: name.to_string(),
+            color: color.map(str::to_string),
+        }
+    }
+
+    fn connection() -> Connection {
+        let connection = Connection::open(":memory:").unwrap();
+        let sql = "CREATE TABLE apples(id INTEGER PRIMARY KEY, name TEXT, color TEXT, weight REAL)";
+        connection.execute(sql, &[]).unwrap();
+        connection
+    }
+
+    fn apples(connection: &Connection, sql: &str) -> Result<Vec<Apple>> {
+        connection.prepare(sql)?.query(&[])?.deserialize().collect()
+    }
+
+    #[test]
+    fn columns_are_read_into_fields_by_name() {
+        let connection = connection();
+        let sql = "INSERT INTO apples VALUES (1, 'Fuji', 'red', 0.2), (2, 'Gala', NULL, NULL)";
+        connection.execute(sql, &[]).unwrap();
+
+        // Other columns and their order don't matter, NULL is None
+        let expected = [apple(1, "Fuji", Some("red")), apple(2, "Gala", None)];
+        assert_eq!(
+            apples(&connection, "SELECT * FROM apples").unwrap(),
+            expected
+        );
+        let sql = "SELECT color, name, id FROM apples";
+        assert_eq!(apples(&connection, sql).unwrap(), expected);
+
+        let error = apples(&connection, "SELECT id, name FROM apples").unwrap_err();
+        assert_eq!(error.to_string(), "no such column: color");
+        connection
+            .execute("UPDATE apples SET name = NULL", &[])
+            .unwrap();
+        let error = apples(&connection, "SELECT * FROM apples").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "column 1 (name): expected text, got null"
+        );
+    }
+
+    #[test]
+    fn fields_are_parameters_in_order() {
+        let params = apple(1, "Fuji", None).to_params();
+        let expected = [
+            ("id", Value::Integer(1)),
+            ("name", Value::Text("Fuji".to_string())),
+            ("color", Value::Null),
+        ];
+        assert_eq!(params, expected);
+    }
+
+    #[test]
+    fn structs_are_inserted_and_bound_by_field_name() {
+        let connection = connection();
+        let inserted = connection
+            .insert("apples", &apple(1, "Fuji", None))
+            .unwrap();
+        assert_eq!(inserted, 1);
+
+        // Any prefix, fields without a parameter are left out and
+        // parameters without a field are NULL
+        let sql = "UPDATE apples SET color = @color, weight = :weight WHERE id = $id";
+        let changed = connection
+            .prepare(sql)
+            .unwrap()
+            .execute_struct(&apple(1, "Gala", Some("red")))
+            .unwrap();
+        assert_eq!(changed, 1);
+        let sql = "SELECT * FROM apples WHERE weight IS NULL";
+        let expected = [apple(1, "Fuji", Some("red"))];
+        assert_eq!(apples(&connection, sql).unwrap(), expected);
+    }
+}
@@ -1,141 +1,790 @@
-use std::vec;
+use std::collections::HashMap;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Error, Result};
 use itertools::Itertools;
 
-use crate::format::{Cell, SqliteFile};
+use crate::{
+    commands::{
+        create_index, create_table, delete, drop_index, drop_table, insert, integrity_check,
+        set_auto_vacuum, update, vacuum, vacuum_into,
+    },
+    expr::{BinaryOp, Expr},
+    format::{unquote, AutoVacuum, CheckpointMode, RecordRef, RecordSerial, SqliteFile},
+    utils::{for_each_entry, for_each_row, incremental_vacuum},
+};
 
 // im not going to implement a full sql parser
-pub struct Where {
-    column_name: String,
-    value: String,
-}
-
+#[derive(Clone)]
 pub enum Command {
     Count {
         table_name: String,
-        conditions: Vec<Where>,
+        condition: Option<Expr>,
     },
     Select {
         table_name: String,
         column_names: Vec<String>,
-        conditions: Vec<Where>,
+        condition: Option<Expr>,
     },
     SelectAll {
         table_name: String,
-        conditions: Vec<Where>,
+        condition: Option<Expr>,
+    },
+    Pragma {
+        name: String,
+        argument: Option<String>,
+    },
+    Insert {
+        table_name: String,
+        column_names: Vec<String>,
+        source: InsertSource,
+    },
+    Update {
+        table_name: String,
+        assignments: Vec<(String, Expr)>,
+        condition: Option<Expr>,
+    },
+    Delete {
+        table_name: String,
+        condition: Option<Expr>,
+    },
+    CreateTable {
+        table_name: String,
+        sql: String,
+        if_not_exists: bool,
+    },
+    CreateIndex {
+        index_name: String,
+        table_name: String,
+        sql: String,
+        if_not_exists: bool,
+    },
+    DropTable {
+        table_name: String,
+        if_exists: bool,
     },
+    DropIndex {
+        index_name: String,
+        if_exists: bool,
+    },
+    Vacuum {
+        into: Option<String>,
+    },
+    Begin,
+    Commit,
+    Rollback,
+}
+
+#[derive(Clone)]
+pub enum InsertSource {
+    /// Literals and placeholders
+    Values(Vec<Vec<Expr>>),
+    Select(Box<Command>),
 }
 
 peg::parser! {
-    grammar sql_command() for str {
+    // `parameters` has the index of the placeholder at each offset
+    grammar sql_command(parameters: &HashMap<usize, usize>) for str {
+        pub rule commands() -> Vec<Command>
+            = _? c:(command() ++ (_? ";" _?)) _? ";"? _? {c}
         pub rule command() -> Command
-            = c:count() / c:select() / c:select_all() {c}
+            = insert() / update() / delete() / count() / select() / select_all() / pragma() / create_table() / create_index() / drop() / vacuum() / transaction()
         pub rule count() -> Command
-            = "SELECT" _ "COUNT(*)" _ "FROM" _ table_name:name() { Command::Count { table_name, conditions: vec![] } }
+            = "SELECT" _ "COUNT(*)" _ "FROM" _ table_name:name() condition:where_clause()? { Command::Count { table_name, condition } }
         pub rule select() -> Command
-            = "SELECT" _ column_names:(name() ** (_* "," _*)) _ "FROM" _ table_name:name() { Command::Select { table_name, column_names, conditions: vec![] } }
+            = "SELECT" _ column_names:(name() ** (_* "," _*)) _ "FROM" _ table_name:name() condition:where_clause()? { Command::Select { table_name, column_names, condition } }
         pub rule select_all() -> Command
-            = "SELECT" _ "*" _ "FROM" _ table_name:name() { Command::SelectAll { table_name, conditions: vec![] } }
-        pub rule create_table() -> Vec<String>
-            = "CREATE" _ "TABLE" _ name() _ "(" _ column_names:((c:name() (_ name())* {c})** (_* "," _*)) _ ")" _* { column_names }
+            = "SELECT" _ "*" _ "FROM" _ table_name:name() condition:where_clause()? { Command::SelectAll { table_name, condition } }
+        pub rule pragma() -> Command
+            = "PRAGMA" _ name:name() argument:(("(" a:pragma_value() ")" { a }) / (_? "=" _? a:pragma_value() { a }))? { Command::Pragma { name, argument } }
+        rule pragma_value() -> String
+            = a:$("-"? ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']+) { a.to_string() }
+        pub rule insert() -> Command
+            = k("INSERT") _ k("INTO") _ table_name:identifier() _?
+              column_names:("(" _? c:(identifier() ** (_? "," _?)) _? ")" _? {c})?
+              source:insert_source()
+            { Command::Insert { table_name, column_names: column_names.unwrap_or_default(), source } }
+        pub rule update() -> Command
+            = k("UPDATE") _ table_name:identifier() _ k("SET") _
+              assignments:((c:identifier() _? "=" _? e:expr() { (c, e) }) ++ (_? "," _?))
+              condition:where_clause()?
+            { Command::Update { table_name, assignments, condition } }
+        pub rule delete() -> Command
+            = k("DELETE") _ k("FROM") _ table_name:identifier() condition:where_clause()?
+            { Command::Delete { table_name, condition } }
+        pub rule transaction() -> Command
+            = k("BEGIN") (_ (k("DEFERRED") / k("IMMEDIATE") / k("EXCLUSIVE")))? (_ k("TRANSACTION"))? { Command::Begin }
+            / (k("COMMIT") / k("END")) (_ k("TRANSACTION"))? { Command::Commit }
+            / k("ROLLBACK") (_ k("TRANSACTION"))? { Command::Rollback }
+        rule insert_source() -> InsertSource
+            = k("VALUES") _? rows:(row() ** (_? "," _?)) { InsertSource::Values(rows) }
+            / query:(select() / select_all()) { InsertSource::Select(Box::new(query)) }
+        rule row() -> Vec<Expr>
+            = "(" _? values:(value() ** (_? "," _?)) _? ")" { values }
+        rule value() -> Expr
+            = value:literal() { Expr::Literal(value) }
+            / index:parameter() { Expr::Parameter(index) }
+        rule parameter() -> usize
+            = offset:position!() placeholder() {? parameters.get(&offset).copied().ok_or("parameter") }
+        rule placeholder() -> &'input str
+            = $("?" ['0'..='9']*)
+            / $([':' | '@' | '$'] ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']+)
+        // Placeholders by offset, with None between statements. Strings and
+        // quoted names are skipped as they can hold anything
+        pub rule placeholders() -> Vec<Option<(usize, &'input str)>>
+            = tokens:(
+                offset:position!() name:placeholder() { vec![Some((offset, name))] }
+                / ";" { vec![None] }
+                / ("'" ([^ '\''] / "''")* "'" / "\"" ([^ '"'] / "\"\"")* "\"" / "`" [^ '`']* "`" / "[" [^ ']']* "]" / [_]) { vec![] }
+              )* { tokens.concat() }
+        pub rule literal() -> RecordSerial
+            = k("NULL") { RecordSerial::Null }
+            / ['x' | 'X'] "'" hex:$(['0'..='9' | 'a'..='f' | 'A'..='F']*) "'" {?
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| hex.get(i..i + 2).and_then(|it| u8::from_str_radix(it, 16).ok()))
+                    .collect::<Option<Vec<_>>>()
+                    .map(RecordSerial::Blob)
+                    .ok_or("hex digits in pairs")
+            }
+            / "'" text:$(([^ '\''] / "''")*) "'" { RecordSerial::String(text.replace("''", "'")) }
+            / number:$(['-' | '+']? (['0'..='9']+ ("." ['0'..='9']*)? / "." ['0'..='9']+) (['e' | 'E'] ['-' | '+']? ['0'..='9']+)?) {?
+                match number.parse::<i64>() {
+                    Ok(i) => Ok(RecordSerial::I64(i)),
+                    // Too large integers become reals too
+                    Err(_) => number.parse().map(RecordSerial::F64).map_err(|_| "number"),
+                }
+            }
+        // sqlite_schema keeps the statement from the name on, without IF NOT EXISTS
+        pub rule create_table() -> Command
+            = k("CREATE") _ k("TABLE") if_not_exists:if_not_exists() _
+              name:$(identifier()) definition:$(_? group() (_? k("WITHOUT") _ k("ROWID"))?)
+            {
+                let sql = format!("CREATE TABLE {name}{definition}");
+                Command::CreateTable { table_name: unquote(name), sql, if_not_exists }
+            }
+        pub rule create_index() -> Command
+            = k("CREATE") unique:(_ k("UNIQUE"))? _ k("INDEX") if_not_exists:if_not_exists() _
+              name:$(identifier()) on:$(_ k("ON") _) table:$(identifier()) columns:$(_? group())
+            {
+                let unique = if unique.is_some() { "UNIQUE " } else { "" };
+                let sql = format!("CREATE {unique}INDEX {name}{on}{table}{columns}");
+                Command::CreateIndex { index_name: unquote(name), table_name: unquote(table), sql, if_not_exists }
+            }
+        pub rule drop() -> Command
+            = k("DROP") _ k("TABLE") if_exists:if_exists() _ table_name:identifier() { Command::DropTable { table_name, if_exists } }
+            / k("DROP") _ k("INDEX") if_exists:if_exists() _ index_name:identifier() { Command::DropIndex { index_name, if_exists } }
+        pub rule vacuum() -> Command
+            = k("VACUUM") into:(_ k("INTO") _ file:literal() {? match file {
+                RecordSerial::String(file) => Ok(file),
+                _ => Err("file name"),
+            } })? { Command::Vacuum { into } }
+        rule if_not_exists() -> bool
+            = e:(_ k("IF") _ k("NOT") _ k("EXISTS"))? { e.is_some() }
+        rule if_exists() -> bool
+            = e:(_ k("IF") _ k("EXISTS"))? { e.is_some() }
+        // Parentheses with anything in them, only strings and names can hold unbalanced ones
+        rule group()
+            = "(" (group() / literal() / identifier() / [^ '(' | ')'])* ")"
 
         rule _()
             = [' ' | '\n' | '\t']+
-        // No condition nesting
-        rule where_condition() -> Where
-            = "WHERE" _ column_name:name() _ "=" _ value:name() { Where { column_name, value }}
+        rule where_clause() -> Expr
+            = _ k("WHERE") _ e:expr() { e }
+        // Loosest binding first
+        pub rule expr() -> Expr = precedence!{
+            a:(@) _ k("OR") _ b:@ { Expr::binary(a, BinaryOp::Or, b) }
+            --
+            a:(@) _ k("AND") _ b:@ { Expr::binary(a, BinaryOp::And, b) }
+            --
+            k("NOT") _ a:@ { Expr::Not(Box::new(a)) }
+            --
+            a:(@) _ k("IS") _ k("NOT") _ k("NULL") { Expr::Not(Box::new(Expr::IsNull(Box::new(a)))) }
+            a:(@) _ k("IS") _ k("NULL") { Expr::IsNull(Box::new(a)) }
+            a:(@) _? "==" _? b:@ { Expr::binary(a, BinaryOp::Equal, b) }
+            a:(@) _? "=" _? b:@ { Expr::binary(a, BinaryOp::Equal, b) }
+            a:(@) _? "!=" _? b:@ { Expr::binary(a, BinaryOp::NotEqual, b) }
+            a:(@) _? "<>" _? b:@ { Expr::binary(a, BinaryOp::NotEqual, b) }
+            --
+            a:(@) _? "<=" _? b:@ { Expr::binary(a, BinaryOp::LessOrEqual, b) }
+            a:(@) _? "<" _? b:@ { Expr::binary(a, BinaryOp::Less, b) }
+            a:(@) _? ">=" _? b:@ { Expr::binary(a, BinaryOp::GreaterOrEqual, b) }
+            a:(@) _? ">" _? b:@ { Expr::binary(a, BinaryOp::Greater, b) }
+            --
+            a:(@) _? "+" _? b:@ { Expr::binary(a, BinaryOp::Add, b) }
+            a:(@) _? "-" _? b:@ { Expr::binary(a, BinaryOp::Subtract, b) }
+            --
+            a:(@) _? "*" _? b:@ { Expr::binary(a, BinaryOp::Multiply, b) }
+            a:(@) _? "/" _? b:@ { Expr::binary(a, BinaryOp::Divide, b) }
+            a:(@) _? "%" _? b:@ { Expr::binary(a, BinaryOp::Remainder, b) }
+            --
+            a:(@) _? "||" _? b:@ { Expr::binary(a, BinaryOp::Concat, b) }
+            --
+            "-" _? a:@ { Expr::Negate(Box::new(a)) }
+            "+" _? a:@ { a }
+            --
+            value:literal() { Expr::Literal(value) }
+            index:parameter() { Expr::Parameter(index) }
+            name:identifier() { Expr::Column(name) }
+            "(" _? e:expr() _? ")" { e }
+        }
         // there's a lot more rule for valid name
         rule name() -> String
             = name:$(['a'..='z' | '_']+) { name.to_string() }
+        rule identifier() -> String
+            = "\"" name:$(([^ '"'] / "\"\"")*) "\"" { name.replace("\"\"", "\"") }
+            / "`" name:$(([^ '`'] / "``")*) "`" { name.replace("``", "`") }
+            / "[" name:$([^ ']']*) "]" { name.to_string() }
+            / name:$(['a'..='z' | 'A'..='Z' | '_'] ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*) { name.to_string() }
+        // Keywords in any case
+        rule k(keyword: &'static str)
+            = input:$([_]*<{keyword.len()}>) {? if input.eq_ignore_ascii_case(keyword) { Ok(()) } else { Err(keyword) } }
+    }
+}
+
+/// A single value like `-1.5`, `'text'` or `X'00'`, as in a DEFAULT clause
+pub fn parse_literal(sql: &str) -> Result<RecordSerial> {
+    Ok(sql_command::literal(sql.trim(), &HashMap::new())?)
+}
+
+/// What a statement returns. Statements that only write have no columns
+#[derive(Debug, Default)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<RecordSerial>>,
+    /// Rows inserted, updated or deleted
+    pub changes: usize,
+}
+
+impl QueryResult {
+    fn single(column: &str, value: RecordSerial) -> Self {
+        QueryResult {
+            columns: vec![column.to_string()],
+            rows: vec![vec![value]],
+            changes: 0,
+        }
+    }
+}
+
+/// Like SQLite's SQLITE_MAX_VARIABLE_NUMBER
+const MAX_PARAMETERS: usize = 32766;
+
+/// The placeholders of a statement, by index from 1. `?` takes the next
+/// index, `?NNN` the given one and a name the same one everywhere
+#[derive(Debug, Default, Clone)]
+pub struct Parameters {
+    /// The names of each index with their prefix, like `:a` and `?1` for
+    /// both, none for `?`
+    pub names: Vec<Vec<String>>,
+}
+
+impl Parameters {
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The index of `:name`, `@name`, `$name` or `?NNN`
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .position(|it| it.iter().any(|it| it == name))
+            .map(|i| i + 1)
+    }
+
+    fn add(&mut self, name: &str) -> Result<usize> {
+        if name == "?" {
+            self.names.push(vec![]);
+            return Ok(self.names.len());
+        }
+        if let Some(index) = self.index(name) {
+            return Ok(index);
+        }
+        if let Some(number) = name.strip_prefix('?') {
+            let index = match number.parse() {
+                Ok(index @ 1..=MAX_PARAMETERS) => index,
+                _ => bail!("variable number must be between ?1 and ?{}", MAX_PARAMETERS),
+            };
+            if index > self.names.len() {
+                self.names.resize(index, vec![]);
+            }
+            // A name already there keeps the index, both find it
+            self.names[index - 1].push(name.to_string());
+            return Ok(index);
+        }
+        if self.names.len() == MAX_PARAMETERS {
+            bail!("too many SQL variables");
+        }
+        self.names.push(vec![name.to_string()]);
+        Ok(self.names.len())
+    }
+}
+
+/// Parses `;` separated statements, with their placeholders
+pub fn parse(sql: &str) -> Result<Vec<(Command, Parameters)>> {
+    let mut indexes = HashMap::new();
+    let mut statements = vec![Parameters::default()];
+    for token in sql_command::placeholders(sql, &indexes)? {
+        match token {
+            Some((offset, name)) => {
+                let parameters = statements.last_mut().expect("one statement at least");
+                indexes.insert(offset, parameters.add(name)?);
+            }
+            None => statements.push(Parameters::default()),
+        }
+    }
+    let commands = sql_command::commands(sql, &indexes)?;
+    Ok(commands.into_iter().zip(statements).collect())
+}
+
+impl Command {
+    /// Only reads the database, even a missing one could run it
+    pub fn is_read(&self) -> bool {
+        match self {
+            Command::Count { .. } | Command::Select { .. } | Command::SelectAll { .. } => true,
+            Command::Pragma { argument, .. } => argument.is_none(),
+            _ => false,
+        }
+    }
+
+    /// A copy with placeholders replaced by their values, by index from 1
+    pub fn bind(&self, values: &[RecordSerial]) -> Command {
+        let mut command = self.clone();
+        let bind = |expr: &mut Option<Expr>| {
+            if let Some(expr) = expr {
+                expr.bind(values);
+            }
+        };
+        match &mut command {
+            Command::Count { condition, .. }
+            | Command::Select { condition, .. }
+            | Command::SelectAll { condition, .. }
+            | Command::Delete { condition, .. } => bind(condition),
+            Command::Update {
+                assignments,
+                condition,
+                ..
+            } => {
+                for (_, expr) in assignments {
+                    expr.bind(values);
+                }
+                bind(condition);
+            }
+            Command::Insert { source, .. } => match source {
+                InsertSource::Values(rows) => {
+                    for value in rows.iter_mut().flatten() {
+                        value.bind(values);
+                    }
+                }
+                InsertSource::Select(query) => **query = query.bind(values),
+            },
+            _ => {}
+        }
+        command
+    }
+}
+
+/// The rows of a table matching `condition`, with the given columns or all of them
+fn select_rows(
+    db: &mut SqliteFile,
+    table_name: &str,
+    column_names: Option<&[String]>,
+    condition: Option<&Expr>,
+) -> Result<QueryResult> {
+    let table = db
+        .tables
+        .iter()
+        .find(|it| it.kind == "table" && it.name.eq_ignore_ascii_case(table_name))
+        .cloned()
+        .ok_or_else(|| anyhow!("no table named {}", table_name))?;
+    let definition = table.definition().map_err(Error::msg)?;
+
+    let column_indexes: Vec<usize> = match column_names {
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                definition
+                    .column_index(name)
+                    .ok_or(anyhow!("column {name} not exists"))
+            })
+            .try_collect()?,
+        None => (0..definition.columns.len()).collect(),
+    };
+    let columns = match column_names {
+        Some(names) => names.to_vec(),
+        None => definition
+            .columns
+            .iter()
+            .map(|it| it.name.clone())
+            .collect(),
+    };
+
+    // Only the columns the query reads are decoded, the others stay NULL
+    let mut needed = column_indexes.clone();
+    if let Some(condition) = condition {
+        // Unknown names fail when the condition is evaluated
+        needed.extend(
+            condition
+                .columns()
+                .into_iter()
+                .filter_map(|name| definition.column_index(name)),
+        );
+    }
+    let needed = needed.into_iter().sorted().dedup().collect_vec();
+
+    let mut selected = vec![];
+    let mut visit = |row_id: Option<i64>, record: &RecordRef| -> Result<()> {
+        let mut values = vec![RecordSerial::Null; definition.columns.len()];
+        for &i in &needed {
+            values[i] = definition
+                .column_value(row_id, record, i)
+                .map_err(Error::msg)?;
+        }
+        if let Some(condition) = condition {
+            if !condition.matches(&definition, &values)? {
+                return Ok(());
+            }
+        }
+        selected.push(column_indexes.iter().map(|i| values[*i].clone()).collect());
+        Ok(())
+    };
+    let root_page = table.root_page as u32;
+    if definition.without_rowid {
+        for_each_entry(db, root_page, &mut |record| visit(None, record))?;
+    } else {
+        for_each_row(db, root_page, &mut |row_id, record| {
+            visit(Some(row_id), record)
+        })?;
     }
+    Ok(QueryResult {
+        columns,
+        rows: selected,
+        changes: 0,
+    })
 }
 
-pub fn execute(command: &str, db: &mut SqliteFile) -> Result<()> {
-    // why did i do this
-    let command = sql_command::command(command)?;
+/// Runs a statement that writes, committing only if all of it succeeded.
+/// Inside a transaction a failed statement is undone alone
+fn autocommit(
+    db: &mut SqliteFile,
+    statement: impl FnOnce(&mut SqliteFile) -> Result<usize>,
+) -> Result<usize> {
+    if db.in_transaction() {
+        let savepoint = db.savepoint();
+        return match statement(db) {
+            Ok(changes) => Ok(changes),
+            Err(e) => {
+                db.restore(savepoint)?;
+                Err(e)
+            }
+        };
+    }
+    match statement(db).and_then(|changes| db.commit().map(|_| changes)) {
+        Ok(changes) => Ok(changes),
+        Err(e) => {
+            db.discard()?;
+            Err(e)
+        }
+    }
+}
+
+/// Runs one parsed statement. Queries and pragmas that read return rows,
+/// statements that write return how many rows they changed. Outside a
+/// transaction each statement reads the latest commit
+pub fn run(command: &Command, db: &mut SqliteFile) -> Result<QueryResult> {
+    db.begin_read()?;
+    let result = run_command(command, db);
+    db.end_read()?;
+    result
+}
+
+fn run_command(command: &Command, db: &mut SqliteFile) -> Result<QueryResult> {
+    let mut changes = 0;
     match command {
         Command::Count {
             table_name,
-            conditions,
+            condition,
         } => {
-            let table = db
-                .tables
-                .iter()
-                .find(|it| it.name == table_name)
-                .ok_or_else(|| anyhow!("no table named {}", table_name))?;
-
-            let page = db.read_page(table.root_page as u64)?;
-            println!("{}", page.cells.len());
+            let rows = select_rows(db, table_name, Some(&[]), condition.as_ref())?.rows;
+            return Ok(QueryResult::single(
+                "COUNT(*)",
+                RecordSerial::I64(rows.len() as i64),
+            ));
         }
         Command::SelectAll {
             table_name,
-            conditions,
-        } => {
-            let table = db
-                .tables
-                .iter()
-                .find(|it| it.name == table_name)
-                .ok_or_else(|| anyhow!("no table named {}", table_name))?;
-            // println!("{}", table.sql);
-            let db_column_names = sql_command::create_table(&table.sql)?;
-
-            let page = db.read_page(table.root_page as u64)?;
-            let output = page
-                .cells
-                .iter()
-                // .filter()
-                .map(|cell| match cell {
-                    Cell::LeafTable { payload, .. } => {
-                        payload.content.iter().map(|it| it.to_string()).join("|")
-                    }
-                    _ => todo!(),
-                })
-                .join("\n");
-            println!("{output}")
-        }
+            condition,
+        } => return select_rows(db, table_name, None, condition.as_ref()),
 
         Command::Select {
             table_name,
             column_names,
-            conditions,
+            condition,
+        } => return select_rows(db, table_name, Some(column_names), condition.as_ref()),
+
+        Command::Insert {
+            table_name,
+            column_names,
+            source,
+        } => {
+            let rows = match source {
+                InsertSource::Values(rows) => rows
+                    .iter()
+                    .map(|row| row.iter().map(Expr::constant).try_collect())
+                    .try_collect()?,
+                InsertSource::Select(query) => match query.as_ref() {
+                    Command::Select {
+                        table_name,
+                        column_names,
+                        condition,
+                    } => select_rows(db, table_name, Some(column_names), condition.as_ref())?.rows,
+                    Command::SelectAll {
+                        table_name,
+                        condition,
+                    } => select_rows(db, table_name, None, condition.as_ref())?.rows,
+                    _ => unreachable!("only SELECT is parsed as an INSERT source"),
+                },
+            };
+            changes = autocommit(db, |db| insert(db, table_name, column_names, rows))?;
+        }
+
+        Command::Update {
+            table_name,
+            assignments,
+            condition,
+        } => {
+            changes = autocommit(db, |db| {
+                update(db, table_name, assignments, condition.as_ref())
+            })?
+        }
+
+        Command::Delete {
+            table_name,
+            condition,
+        } => changes = autocommit(db, |db| delete(db, table_name, condition.as_ref()))?,
+
+        Command::CreateTable {
+            table_name,
+            sql,
+            if_not_exists,
+        } => {
+            autocommit(db, |db| create_table(db, table_name, sql, *if_not_exists))?;
+        }
+
+        Command::CreateIndex {
+            index_name,
+            table_name,
+            sql,
+            if_not_exists,
+        } => {
+            autocommit(db, |db| {
+                create_index(db, index_name, table_name, sql, *if_not_exists)
+            })?;
+        }
+
+        Command::DropTable {
+            table_name,
+            if_exists,
+        } => {
+            autocommit(db, |db| drop_table(db, table_name, *if_exists))?;
+        }
+
+        Command::DropIndex {
+            index_name,
+            if_exists,
         } => {
-            let table = db
-                .tables
-                .iter()
-                .find(|it| it.name == table_name)
-                .ok_or_else(|| anyhow!("no table named {}", table_name))?;
-            // println!("{}", table.sql);
-            let db_column_names = sql_command::create_table(&table.sql)?;
-
-            let column_indexes: Vec<_> = column_names
-                .iter()
-                .map(|name| {
-                    db_column_names
-                        .iter()
-                        .find_position(|it| *it == name)
-                        .map(|(i, _)| i)
-                        .ok_or(anyhow!("column {name} not exists"))
-                })
-                .try_collect()?;
-
-            let page = db.read_page(table.root_page as u64)?;
-            let output = page
-                .cells
-                .iter()
-                // .filter()
-                .map(|cell| match cell {
-                    Cell::LeafTable { payload, .. } => column_indexes
-                        .iter()
-                        .map(|i| payload.content[*i].to_string())
-                        .join("|"),
-                    _ => todo!(),
-                })
-                .join("\n");
-
-            println!("{output}")
-        }
-    }
-
-    Ok(())
+            autocommit(db, |db| drop_index(db, index_name, *if_exists))?;
+        }
+
+        Command::Pragma { name, argument } => return pragma(db, name, argument.as_deref()),
+
+        Command::Vacuum { into: None } => vacuum(db)?,
+        Command::Vacuum { into: Some(path) } => {
+            vacuum_into(db, path)?;
+        }
+
+        Command::Begin => db.begin()?,
+        Command::Commit => {
+            if !db.in_transaction() {
+                bail!("cannot commit - no transaction is active");
+            }
+            db.commit()?
+        }
+        Command::Rollback => {
+            if !db.in_transaction() {
+                bail!("cannot rollback - no transaction is active");
+            }
+            db.discard()?
+        }
+    }
+
+    Ok(QueryResult {
+        changes,
+        ..Default::default()
+    })
+}
+
+fn pragma(db: &mut SqliteFile, name: &str, argument: Option<&str>) -> Result<QueryResult> {
+    match name {
+        "integrity_check" | "quick_check" => {
+            // integrity_check(N) stops after N errors
+            let max_errors = match argument {
+                Some(n) => n.parse()?,
+                None => 100,
+            };
+            let mut errors = integrity_check(db, name == "quick_check", max_errors)?;
+            // Like SQLite, the first error says which database it is about
+            match errors.first_mut() {
+                Some(first) => *first = format!("*** in database main ***\n{first}"),
+                None => errors.push("ok".to_string()),
+            }
+            Ok(QueryResult {
+                columns: vec![name.to_string()],
+                rows: errors
+                    .into_iter()
+                    .map(|it| vec![RecordSerial::String(it)])
+                    .collect(),
+                changes: 0,
+            })
+        }
+        "auto_vacuum" => match argument {
+            Some(mode) => {
+                // Like SQLite, anything unknown means NONE
+                let mode = match mode.to_ascii_uppercase().as_str() {
+                    "FULL" | "1" => AutoVacuum::Full,
+                    "INCREMENTAL" | "2" => AutoVacuum::Incremental,
+                    _ => AutoVacuum::None,
+                };
+                autocommit(db, |db| set_auto_vacuum(db, mode))?;
+                Ok(QueryResult::default())
+            }
+            None => Ok(QueryResult::single(
+                name,
+                RecordSerial::I64(db.header.auto_vacuum() as i64),
+            )),
+        },
+        // incremental_vacuum(N) frees up to N pages, all of them by default
+        "incremental_vacuum" => {
+            let max = match argument {
+                Some(n) => n.parse::<i64>()?,
+                None => 0,
+            };
+            let max = (max > 0).then_some(max as usize);
+            autocommit(db, |db| incremental_vacuum(db, max))?;
+            Ok(QueryResult::default())
+        }
+        // Only for this connection, the header's default_cache_size isn't changed
+        "cache_size" => match argument {
+            Some(size) => {
+                db.set_cache_size(size.parse()?);
+                Ok(QueryResult::default())
+            }
+            None => Ok(QueryResult::single(
+                name,
+                RecordSerial::I64(db.cache_size()),
+            )),
+        },
+        // Like SQLite, setting it returns the new size and a negative one means
+        // the default, no mapping
+        "mmap_size" => {
+            if let Some(size) = argument {
+                db.set_mmap_size(size.parse::<i64>()?.max(0) as u64)?;
+            }
+            Ok(QueryResult::single(
+                name,
+                RecordSerial::I64(db.mmap_size() as i64),
+            ))
+        }
+        // A new page size only applies on the next VACUUM
+        "page_size" => match argument {
+            Some(size) => {
+                let size: u32 = size.parse()?;
+                if size.is_power_of_two() && (512..=65536).contains(&size) {
+                    db.pending_page_size = Some(size);
+                }
+                Ok(QueryResult::default())
+            }
+            None => Ok(QueryResult::single(
+                name,
+                RecordSerial::I64(db.header.page_size as i64),
+            )),
+        },
+        "wal_checkpoint" => {
+            // Unknown modes are PASSIVE too
+            let mode = match argument.map(|it| it.to_ascii_uppercase()).as_deref() {
+                Some("FULL") => CheckpointMode::Full,
+                Some("RESTART") => CheckpointMode::Restart,
+                Some("TRUNCATE") => CheckpointMode::Truncate,
+                _ => CheckpointMode::Passive,
+            };
+            let result = db.checkpoint(mode)?;
+            Ok(QueryResult {
+                columns: vec!["busy".into(), "log".into(), "checkpointed".into()],
+                rows: vec![vec![
+                    RecordSerial::I64(result.busy as i64),
+                    RecordSerial::I64(result.log_frames),
+                    RecordSerial::I64(result.checkpointed),
+                ]],
+                changes: 0,
+            })
+        }
+        other => Err(anyhow!("unsupported pragma {}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The names of each placeholder index of each statement, `?` has none
+    fn placeholders(sql: &str) -> Vec<Vec<String>> {
+        parse(sql)
+            .unwrap()
+            .into_iter()
+            .map(|(_, parameters)| parameters.names.iter().map(|it| it.join(" ")).collect())
+            .collect()
+    }
+
+    #[test]
+    fn quoted_placeholders_are_text() {
+        let sql = r#"SELECT a FROM t WHERE a = '?:x' AND "?" = `:y` AND [@z] = $w"#;
+        assert_eq!(placeholders(sql), [["$w"]]);
+        let sql = "SELECT a FROM t WHERE a = 'it''s ?' AND b = \"\"\"?\" AND c = ?";
+        assert_eq!(placeholders(sql), [[""]]);
+    }
+
+    #[test]
+    fn statements_number_their_own_placeholders() {
+        let sql = "INSERT INTO t VALUES (?, ';'); SELECT a FROM t WHERE a = ? OR a = :a;";
+        assert_eq!(placeholders(sql), [vec![""], vec!["", ":a"]]);
+    }
+
+    #[test]
+    fn numbered_placeholders_leave_gaps() {
+        let sql = "SELECT a FROM t WHERE a = ?3 AND b = ? AND c = ?3";
+        assert_eq!(placeholders(sql), [["", "", "?3", ""]]);
+    }
+
+    #[test]
+    fn names_share_the_index_they_are_given() {
+        let sql = "SELECT a FROM t WHERE a = :a AND a = ?1 AND b = @b AND b = ?2";
+        assert_eq!(placeholders(sql), [[":a ?1", "@b ?2"]]);
+        let (_, parameters) = parse(sql).unwrap().remove(0);
+        assert_eq!(parameters.index(":a"), Some(1));
+        assert_eq!(parameters.index("?1"), Some(1));
+        assert_eq!(parameters.index("?3"), None);
+    }
+
+    #[test]
+    fn numbers_are_from_1_to_the_maximum() {
+        let sql = format!("SELECT a FROM t WHERE a = ?{MAX_PARAMETERS}");
+        assert_eq!(placeholders(&sql)[0].len(), MAX_PARAMETERS);
+        for number in ["0", "32767", "99999999999999999999"] {
+            let sql = format!("SELECT a FROM t WHERE a = ?{number}");
+            let error = parse(&sql).err().unwrap().to_string();
+            assert_eq!(error, "variable number must be between ?1 and ?32766");
+        }
+    }
 }
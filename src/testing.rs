@@ -0,0 +1,54 @@
+//! Databases for tests, kept in a `MemoryVfs` as `test.db`
+
+use std::rc::Rc;
+
+use anyhow::Result;
+
+use crate::{
+    format::{CreateOptions, HotJournal, MemoryVfs, OpenOptions, RecordSerial, SqliteFile, Vfs},
+    parser::{parse, run},
+};
+
+pub const PATH: &str = "test.db";
+
+pub fn create(vfs: &MemoryVfs, options: &CreateOptions) -> SqliteFile {
+    SqliteFile::create_vfs(Rc::new(vfs.clone()), PATH, options).unwrap()
+}
+
+/// Opens the database again, a hot journal is replayed
+pub fn reopen(vfs: &MemoryVfs) -> SqliteFile {
+    reopen_with(vfs, HotJournal::Replay).unwrap()
+}
+
+pub fn reopen_with(vfs: &MemoryVfs, hot_journal: HotJournal) -> Result<SqliteFile> {
+    let options = OpenOptions { hot_journal };
+    SqliteFile::open_vfs(Rc::new(vfs.clone()), PATH, &options)
+}
+
+/// Everything in a file of the VFS
+pub fn content(vfs: &MemoryVfs, path: &str) -> Vec<u8> {
+    let mut file = vfs.open(path, false).unwrap();
+    let mut content = vec![0; file.size().unwrap() as usize];
+    file.read_at(0, &mut content).unwrap();
+    content
+}
+
+/// Runs `;` separated statements without parameters, returns the rows of
+/// the last one
+pub fn execute(db: &mut SqliteFile, sql: &str) -> Result<Vec<Vec<RecordSerial>>> {
+    let mut rows = vec![];
+    for (command, _) in parse(sql)? {
+        rows = run(&command, db)?.rows;
+    }
+    Ok(rows)
+}
+
+/// The first value of the last statement's first row
+pub fn query_one(db: &mut SqliteFile, sql: &str) -> String {
+    execute(db, sql).unwrap()[0][0].to_string()
+}
+
+/// "ok", or the first problem found
+pub fn integrity_check(db: &mut SqliteFile) -> String {
+    query_one(db, "PRAGMA integrity_check")
+}
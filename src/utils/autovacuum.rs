@@ -0,0 +1,179 @@
+use anyhow::{anyhow, bail, Result};
+use itertools::Itertools;
+
+use super::{overflow, Node};
+use crate::format::{
+    is_ptrmap_page, ptrmap_page, u32_at, DatabaseHeader, PtrmapEntry, PtrmapType, SqliteFile,
+};
+
+/// Moves a page of an auto-vacuum database to `to`, a free page, and fixes
+/// the one pointer to it its pointer map entry names
+pub fn relocate_page(db: &mut SqliteFile, from: u32, to: u32) -> Result<()> {
+    let entry = db
+        .ptrmap_entry(from)?
+        .ok_or_else(|| anyhow!("page {} has no pointer map entry", from))?;
+    let buf = db.read_raw_page(from as u64)?;
+    db.write_page(to, buf.clone())?;
+    db.set_ptrmap_entry(to, entry)?;
+
+    match entry.kind {
+        PtrmapType::BTree => {
+            // Rewriting it points its children and overflow chains here
+            Node::read(db, to)?.write(db)?;
+            let mut parent = Node::read(db, entry.parent)?;
+            if parent.right_most == Some(from) {
+                parent.right_most = Some(to);
+            }
+            for cell in &mut parent.cells {
+                if cell[..4] == from.to_be_bytes() {
+                    cell[..4].copy_from_slice(&to.to_be_bytes());
+                }
+            }
+            parent.write(db)?;
+        }
+        PtrmapType::Overflow1 => {
+            let usable_size = db.header.usable_size();
+            let mut parent = Node::read(db, entry.parent)?;
+            for cell in &mut parent.cells {
+                // The overflow page number ends the cell
+                if overflow(parent.kind, cell, usable_size).map(|it| it.0) == Some(from) {
+                    let end = cell.len();
+                    cell[end - 4..].copy_from_slice(&to.to_be_bytes());
+                }
+            }
+            parent.write(db)?;
+        }
+        PtrmapType::Overflow2 => {
+            let mut parent = db.read_raw_page(entry.parent as u64)?;
+            parent[..4].copy_from_slice(&to.to_be_bytes());
+            db.write_page(entry.parent, parent)?;
+        }
+        PtrmapType::RootPage | PtrmapType::FreePage => {
+            bail!("page {} can't be moved, it's a {:?}", from, entry.kind)
+        }
+    }
+
+    if matches!(entry.kind, PtrmapType::Overflow1 | PtrmapType::Overflow2) {
+        let next = u32_at(&buf, 0);
+        if next != 0 {
+            db.set_ptrmap_entry(next, PtrmapEntry::new(PtrmapType::Overflow2, to))?;
+        }
+    }
+    Ok(())
+}
+
+/// Gives up to `max` free pages (all of them by default) back to the file
+/// system: pages past the new end move into free pages before it, then the
+/// file is cut. Returns how many pages went away, not counting pointer maps.
+pub fn incremental_vacuum(db: &mut SqliteFile, max: Option<usize>) -> Result<usize> {
+    if !db.header.is_auto_vacuum() {
+        return Ok(0);
+    }
+    let free = db.freelist()?.pages();
+    let count = max.map_or(free.len(), |it| it.min(free.len()));
+    if count == 0 {
+        return Ok(0);
+    }
+    let page_count = db.page_count()? as u32;
+    let header = db.header.clone();
+    let final_size = final_size(&header, page_count, count as u32);
+
+    let mut targets = free.iter().copied().filter(|it| *it <= final_size).sorted();
+    for page_number in final_size + 1..=page_count {
+        if free.contains(&page_number)
+            || page_number == header.pending_byte_page()
+            || is_ptrmap_page(&header, page_number)
+        {
+            continue;
+        }
+        let to = targets
+            .next()
+            .ok_or_else(|| anyhow!("database disk image is malformed"))?;
+        relocate_page(db, page_number, to)?;
+    }
+    db.set_freelist(&targets.collect_vec())?;
+    db.truncate(final_size)?;
+    Ok(count)
+}
+
+/// The page count once `free` pages are gone, with the pointer map pages
+/// only they needed. Like SQLite's finalDbSize
+fn final_size(header: &DatabaseHeader, page_count: u32, free: u32) -> u32 {
+    let entries = header.usable_size() as u32 / 5;
+    let last_map = ptrmap_page(header, page_count);
+    let ptrmap_pages = (free + last_map + entries - page_count) / entries;
+    let mut size = page_count - free - ptrmap_pages;
+    let pending_byte_page = header.pending_byte_page();
+    if page_count > pending_byte_page && size < pending_byte_page {
+        size -= 1;
+    }
+    while size == pending_byte_page || is_ptrmap_page(header, size) {
+        size -= 1;
+    }
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::{AutoVacuum, CreateOptions, MemoryVfs},
+        testing::{create, execute, integrity_check, reopen},
+    };
+
+    /// 100 rows of 2000 bytes, in 1024 byte pages so each one overflows
+    fn database(vfs: &MemoryVfs) -> SqliteFile {
+        let options = CreateOptions {
+            page_size: 1024,
+            auto_vacuum: AutoVacuum::Incremental,
+            ..CreateOptions::default()
+        };
+        let mut db = create(vfs, &options);
+        execute(&mut db, "CREATE TABLE t(id INTEGER PRIMARY KEY, a TEXT)").unwrap();
+        for id in 0..100 {
+            let sql = format!("INSERT INTO t VALUES ({id}, '{}')", "x".repeat(2000));
+            execute(&mut db, &sql).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn free_pages_at_the_end_are_cut_off() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        execute(&mut db, "DELETE FROM t").unwrap();
+        let free = db.header.free_list_count;
+        let page_count = db.page_count().unwrap();
+        assert!(free >= 200);
+
+        // Nothing has to move, the table's root is before every free page
+        execute(&mut db, "PRAGMA incremental_vacuum(10)").unwrap();
+        let mut db = reopen(&vfs);
+        assert_eq!(db.header.free_list_count, free - 10);
+        assert_eq!(db.page_count().unwrap(), page_count - 10);
+        assert_eq!(integrity_check(&mut db), "ok");
+
+        execute(&mut db, "PRAGMA incremental_vacuum").unwrap();
+        let mut db = reopen(&vfs);
+        assert_eq!(db.header.free_list_count, 0);
+        // Page 1, the pointer map and the empty table
+        assert_eq!(db.page_count().unwrap(), 3);
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+
+    #[test]
+    fn pages_after_the_new_end_are_moved() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        execute(&mut db, "DELETE FROM t WHERE id < 50").unwrap();
+        let free = db.header.free_list_count;
+        assert!(free >= 100);
+
+        execute(&mut db, "PRAGMA incremental_vacuum").unwrap();
+        let mut db = reopen(&vfs);
+        assert_eq!(db.header.free_list_count, 0);
+        assert_eq!(integrity_check(&mut db), "ok");
+        let rows = execute(&mut db, "SELECT COUNT(*) FROM t WHERE a = '' OR id >= 50").unwrap();
+        assert_eq!(rows[0][0].to_string(), "50");
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Error, Result};
+
+use crate::format::{Cell, Page, PageHeader, RawCell, Record, RecordRef, SqliteFile};
+
+#[derive(Debug)]
+pub struct TableRow {
+    pub row_id: i64,
+    pub record: Record,
+}
+
+/// Every row of a table b-tree, in rowid order
+pub fn scan_table(db: &mut SqliteFile, root_page: u32) -> Result<Vec<TableRow>> {
+    let mut rows = vec![];
+    for_each_row(db, root_page, &mut |row_id, record| {
+        rows.push(TableRow {
+            row_id,
+            record: record.to_record().map_err(Error::msg)?,
+        });
+        Ok(())
+    })?;
+    Ok(rows)
+}
+
+/// Every entry of an index b-tree (or a WITHOUT ROWID table), in key order
+pub fn scan_index(db: &mut SqliteFile, root_page: u32) -> Result<Vec<Record>> {
+    let mut records = vec![];
+    for_each_entry(db, root_page, &mut |record| {
+        records.push(record.to_record().map_err(Error::msg)?);
+        Ok(())
+    })?;
+    Ok(records)
+}
+
+/// Visits the rows of a table b-tree in rowid order. Records stay in the
+/// page buffer and only the values `visit` asks for get decoded
+pub fn for_each_row(
+    db: &mut SqliteFile,
+    root_page: u32,
+    visit: &mut dyn FnMut(i64, &RecordRef) -> Result<()>,
+) -> Result<()> {
+    let header = db.header.clone();
+    walk(db, root_page, &mut HashSet::new(), &mut |cell| match (
+        cell.row_id,
+        cell.record(&header).map_err(Error::msg)?,
+    ) {
+        (Some(row_id), Some(record)) => visit(row_id, &record),
+        _ => Ok(()),
+    })
+}
+
+/// Visits the entries of an index b-tree (or a WITHOUT ROWID table) in key
+/// order, like `for_each_row`
+pub fn for_each_entry(
+    db: &mut SqliteFile,
+    root_page: u32,
+    visit: &mut dyn FnMut(&RecordRef) -> Result<()>,
+) -> Result<()> {
+    let header = db.header.clone();
+    walk(db, root_page, &mut HashSet::new(), &mut |cell| match cell
+        .record(&header)
+        .map_err(Error::msg)?
+    {
+        Some(record) if cell.row_id.is_none() => visit(&record),
+        _ => Ok(()),
+    })
+}
+
+// In-order traversal: left child, then the cell itself, then the right-most pointer
+fn walk(
+    db: &mut SqliteFile,
+    page_number: u32,
+    visited: &mut HashSet<u32>,
+    visit: &mut dyn FnMut(RawCell) -> Result<()>,
+) -> Result<()> {
+    if !visited.insert(page_number) {
+        bail!("page {} is referenced more than once", page_number);
+    }
+
+    let db_header = db.header.clone();
+    let page = db.read_page_ref(page_number as u64)?;
+    let padding = if page_number == 1 { 100 } else { 0 };
+    let header =
+        PageHeader::from_bytes(page.get(padding..).unwrap_or_default()).map_err(Error::msg)?;
+    let cell_pointers =
+        Page::parse_cell_pointer_array(&page, &header, padding).map_err(Error::msg)?;
+
+    // A leaf is read in place, mapped or cached, unless one of its cells
+    // spills into overflow pages, which have to be read from `db` too
+    if header.page_number.is_none() {
+        let mut overflows = false;
+        let cells = cell_pointers
+            .iter()
+            .map(|pointer| {
+                Cell::read_raw(
+                    &page,
+                    *pointer as usize,
+                    &header.kind,
+                    &db_header,
+                    &mut |_| {
+                        overflows = true;
+                        Err("overflow page")
+                    },
+                )
+            })
+            .collect::<Result<Vec<_>, _>>();
+        match cells {
+            Ok(cells) => return cells.into_iter().try_for_each(visit),
+            Err(_) if overflows => {}
+            Err(e) => bail!(e),
+        }
+    }
+
+    let buf = page.into_owned();
+    for pointer in cell_pointers {
+        let cell = Cell::read_raw(&buf, pointer as usize, &header.kind, &db_header, &mut |n| {
+            db.read_raw_page(n as u64)
+                .map_err(|_| "Failed to read overflow page")
+        })
+        .map_err(Error::msg)?;
+        if let Some(left_child) = cell.left_child {
+            walk(db, left_child, visited, visit)?;
+        }
+        visit(cell)?;
+    }
+    if let Some(right_most) = header.page_number {
+        walk(db, right_most, visited, visit)?;
+    }
+    Ok(())
+}
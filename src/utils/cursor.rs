@@ -0,0 +1,909 @@
+use std::cmp::Ordering;
+
+use anyhow::{anyhow, bail, Error, Result};
+
+use super::{relocate_page, Varint};
+use crate::format::{
+    is_ptrmap_page, u32_at, Cell, Page, PageHeader, PageType, PtrmapEntry, PtrmapType, Record,
+    RecordSerial, SqliteFile,
+};
+
+/// Deeper than this and the b-tree has a loop in it
+const MAX_DEPTH: usize = 64;
+
+/// A b-tree page as a list of raw cells, written back from scratch so it never
+/// has freeblocks or fragmented bytes
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub page_number: u32,
+    pub kind: PageType,
+    pub cells: Vec<Vec<u8>>,
+    pub right_most: Option<u32>,
+}
+
+impl Node {
+    pub fn read(db: &mut SqliteFile, page_number: u32) -> Result<Self> {
+        let usable_size = db.header.usable_size();
+        let buf = db.read_page_ref(page_number as u64)?;
+        let padding = padding(page_number);
+        let header = PageHeader::from_bytes(&buf[padding..]).map_err(Error::msg)?;
+        let cells = Page::parse_cell_pointer_array(&buf, &header, padding)
+            .map_err(Error::msg)?
+            .iter()
+            .map(|pointer| {
+                let start = *pointer as usize;
+                let size = Cell::size_on_page(&buf, start, &header.kind, usable_size)
+                    .map_err(Error::msg)?;
+                buf.get(start..start + size)
+                    .map(<[u8]>::to_vec)
+                    .ok_or_else(|| Error::msg("Cell out of page bounds"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Node {
+            page_number,
+            kind: header.kind,
+            cells,
+            right_most: header.page_number,
+        })
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        matches!(self.kind, PageType::LeafTable | PageType::LeafIndex)
+    }
+
+    /// Child `i`, the right-most pointer when `i` is the number of cells
+    pub fn child(&self, i: usize) -> Option<u32> {
+        match self.cells.get(i) {
+            Some(cell) if !self.is_leaf() => Some(u32_at(cell, 0)),
+            None => self.right_most,
+            _ => None,
+        }
+    }
+
+    /// The rowid of a table leaf cell or the key of an interior table cell
+    pub fn row_id(&self, i: usize) -> i64 {
+        let cell = &self.cells[i];
+        match self.kind {
+            PageType::InteriorTable => Varint::from_bytes(&cell[4..]).value,
+            _ => {
+                let size = Varint::from_bytes(cell);
+                Varint::from_bytes(&cell[size.size as usize..]).value
+            }
+        }
+    }
+
+    /// The record of an index cell, with its overflow pages read
+    pub fn key(&self, db: &mut SqliteFile, i: usize) -> Result<Record> {
+        let header = PageHeader {
+            kind: self.kind,
+            first_freeblock: 0,
+            number_of_cells: 0,
+            first_cell_content: 0,
+            fragmented_free_bytes: 0,
+            page_number: None,
+        };
+        let db_header = db.header.clone();
+        let cell = Cell::from_bytes(&self.cells[i], 0, &header, &db_header, &mut |n| {
+            db.read_raw_page(n as u64)
+                .map_err(|_| "Failed to read overflow page")
+        })
+        .map_err(Error::msg)?;
+        cell.payload()
+            .cloned()
+            .ok_or_else(|| Error::msg("Not an index cell"))
+    }
+
+    /// Bytes left for cells and their pointers
+    fn capacity(kind: PageType, page_number: u32, usable_size: usize) -> usize {
+        let header_size = match kind {
+            PageType::InteriorIndex | PageType::InteriorTable => 12,
+            _ => 8,
+        };
+        usable_size - padding(page_number) - header_size
+    }
+
+    fn used(&self) -> usize {
+        self.cells.iter().map(|it| it.len() + 2).sum()
+    }
+
+    fn fits(&self, usable_size: usize) -> bool {
+        self.used() <= Node::capacity(self.kind, self.page_number, usable_size)
+    }
+
+    /// Less than a third full, or without cells
+    fn is_underfull(&self, usable_size: usize) -> bool {
+        self.cells.is_empty() || self.used() < Node::capacity(self.kind, 0, usable_size) / 3
+    }
+
+    /// Packs the cells at the end of the page, page 1 keeps its database header
+    pub fn write(&self, db: &mut SqliteFile) -> Result<()> {
+        let page_size = db.header.page_size as usize;
+        let usable_size = db.header.usable_size();
+        if !self.fits(usable_size) {
+            bail!("page {} is too small for its cells", self.page_number);
+        }
+        let padding = padding(self.page_number);
+        let mut buf = vec![0; page_size];
+        if padding > 0 {
+            buf[..padding].copy_from_slice(&db.read_raw_page(self.page_number as u64)?[..padding]);
+        }
+
+        let header_size = if self.is_leaf() { 8 } else { 12 };
+        let mut content = usable_size;
+        for (i, cell) in self.cells.iter().enumerate() {
+            content -= cell.len();
+            buf[content..content + cell.len()].copy_from_slice(cell);
+            let pointer = padding + header_size + i * 2;
+            buf[pointer..pointer + 2].copy_from_slice(&(content as u16).to_be_bytes());
+        }
+        buf[padding] = self.kind as u8;
+        buf[padding + 3..padding + 5].copy_from_slice(&(self.cells.len() as u16).to_be_bytes());
+        // 0 stands for 65536
+        buf[padding + 5..padding + 7].copy_from_slice(&(content as u16).to_be_bytes());
+        if let Some(right_most) = self.right_most.filter(|_| !self.is_leaf()) {
+            buf[padding + 8..padding + 12].copy_from_slice(&right_most.to_be_bytes());
+        }
+        db.write_page(self.page_number, buf)?;
+        self.update_ptrmap(db)
+    }
+
+    /// Children and overflow chains of an auto-vacuum database point back here
+    fn update_ptrmap(&self, db: &mut SqliteFile) -> Result<()> {
+        if !db.header.is_auto_vacuum() {
+            return Ok(());
+        }
+        let usable_size = db.header.usable_size();
+        for (i, cell) in self.cells.iter().enumerate() {
+            if let Some(child) = self.child(i).filter(|_| !self.is_leaf()) {
+                db.set_ptrmap_entry(child, PtrmapEntry::new(PtrmapType::BTree, self.page_number))?;
+            }
+            if let Some((page_number, _)) = overflow(self.kind, cell, usable_size) {
+                let entry = PtrmapEntry::new(PtrmapType::Overflow1, self.page_number);
+                db.set_ptrmap_entry(page_number, entry)?;
+            }
+        }
+        if let Some(right_most) = self.right_most.filter(|_| !self.is_leaf()) {
+            db.set_ptrmap_entry(
+                right_most,
+                PtrmapEntry::new(PtrmapType::BTree, self.page_number),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn padding(page_number: u32) -> usize {
+    if page_number == 1 {
+        100
+    } else {
+        0
+    }
+}
+
+/// A leaf cell for `payload`, what doesn't fit on the page goes to overflow pages
+pub fn leaf_cell(db: &mut SqliteFile, row_id: Option<i64>, payload: &[u8]) -> Result<Vec<u8>> {
+    let kind = match row_id {
+        Some(_) => PageType::LeafTable,
+        None => PageType::LeafIndex,
+    };
+    let mut cell = vec![];
+    Varint::write(payload.len() as i64, &mut cell);
+    if let Some(row_id) = row_id {
+        Varint::write(row_id, &mut cell);
+    }
+
+    let usable_size = db.header.usable_size();
+    let local = Cell::local_payload_size(payload.len(), usable_size, &kind);
+    cell.extend_from_slice(&payload[..local]);
+    if local < payload.len() {
+        let chunks = payload[local..].chunks(usable_size - 4).collect::<Vec<_>>();
+        let pages = chunks
+            .iter()
+            .map(|_| db.allocate_page())
+            .collect::<Result<Vec<_>>>()?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut buf = vec![0; db.header.page_size as usize];
+            let next = pages.get(i + 1).copied().unwrap_or_default();
+            buf[..4].copy_from_slice(&next.to_be_bytes());
+            buf[4..4 + chunk.len()].copy_from_slice(chunk);
+            db.write_page(pages[i], buf)?;
+            if i > 0 {
+                let entry = PtrmapEntry::new(PtrmapType::Overflow2, pages[i - 1]);
+                db.set_ptrmap_entry(pages[i], entry)?;
+            }
+        }
+        cell.extend_from_slice(&pages[0].to_be_bytes());
+    }
+    // Cells can become freeblocks, so they take at least 4 bytes
+    cell.resize(cell.len().max(4), 0);
+    Ok(cell)
+}
+
+/// The largest rowid of a table b-tree, from its right-most leaf
+pub fn last_row_id(db: &mut SqliteFile, root_page: u32) -> Result<Option<i64>> {
+    let mut node = Node::read(db, root_page)?;
+    for _ in 0..MAX_DEPTH {
+        match node.kind {
+            PageType::InteriorTable => {
+                let child = node.child(node.cells.len()).unwrap_or_default();
+                node = Node::read(db, child)?;
+            }
+            PageType::LeafTable => {
+                return Ok(node.cells.len().checked_sub(1).map(|i| node.row_id(i)));
+            }
+            _ => bail!("page {} is not a table b-tree page", node.page_number),
+        }
+    }
+    bail!("b-tree at page {} is too deep", root_page)
+}
+
+/// A new b-tree with a single empty leaf as its root. Auto-vacuum keeps
+/// root pages at the start of the file since they can't be moved, whatever
+/// is on the next one moves out of the way
+pub fn create_btree(db: &mut SqliteFile, kind: PageType) -> Result<u32> {
+    let page_number = if db.header.is_auto_vacuum() {
+        let header = &db.header;
+        let mut root = header.largest_root_btree_page + 1;
+        while root == header.pending_byte_page() || is_ptrmap_page(header, root) {
+            root += 1;
+        }
+        if !db.allocate_exact(root)? {
+            let page_number = db.allocate_page()?;
+            relocate_page(db, root, page_number)?;
+        }
+        db.header.largest_root_btree_page = root;
+        db.set_ptrmap_entry(root, PtrmapEntry::new(PtrmapType::RootPage, 0))?;
+        root
+    } else {
+        db.allocate_page()?
+    };
+    Node {
+        page_number,
+        kind,
+        cells: vec![],
+        right_most: None,
+    }
+    .write(db)?;
+    Ok(page_number)
+}
+
+/// Puts every page of a b-tree on the freelist, overflow pages and the root
+/// included. Auto-vacuum keeps roots packed at the start of the file: the
+/// last root moves into the freed one, and its old page number is returned.
+pub fn free_btree(db: &mut SqliteFile, root_page: u32) -> Result<Option<u32>> {
+    clear_btree(db, root_page)?;
+    if !db.header.is_auto_vacuum() {
+        db.free_page(root_page)?;
+        return Ok(None);
+    }
+    let last = db.header.largest_root_btree_page;
+    let moved = (root_page < last).then_some(last);
+    if moved.is_some() {
+        let buf = db.read_raw_page(last as u64)?;
+        db.write_page(root_page, buf)?;
+        // Points the children's pointer map entries here
+        Node::read(db, root_page)?.write(db)?;
+    }
+    db.free_page(last.max(root_page))?;
+
+    let header = &db.header;
+    let mut largest = last - 1;
+    while largest == header.pending_byte_page() || is_ptrmap_page(header, largest) {
+        largest -= 1;
+    }
+    db.header.largest_root_btree_page = largest;
+    Ok(moved)
+}
+
+/// Frees every page of a b-tree but the root, which becomes an empty leaf
+pub fn clear_btree(db: &mut SqliteFile, root_page: u32) -> Result<()> {
+    let root = Node::read(db, root_page)?;
+    let mut pages = vec![(root_page, 0)];
+    while let Some((page_number, depth)) = pages.pop() {
+        if depth >= MAX_DEPTH {
+            bail!("b-tree at page {} is too deep", root_page);
+        }
+        let node = Node::read(db, page_number)?;
+        for cell in &node.cells {
+            free_overflow(db, node.kind, cell)?;
+        }
+        if !node.is_leaf() {
+            pages.extend(
+                (0..=node.cells.len())
+                    .filter_map(|i| node.child(i))
+                    .map(|it| (it, depth + 1)),
+            );
+        }
+        if page_number != root_page {
+            db.free_page(page_number)?;
+        }
+    }
+    let kind = match root.kind {
+        PageType::InteriorTable | PageType::LeafTable => PageType::LeafTable,
+        _ => PageType::LeafIndex,
+    };
+    Node {
+        page_number: root_page,
+        kind,
+        cells: vec![],
+        right_most: None,
+    }
+    .write(db)
+}
+
+/// A position in a b-tree: every page from the root down to a leaf, with the
+/// cell (or child) taken on each
+pub struct Cursor {
+    path: Vec<(Node, usize)>,
+    /// The seek landed on an entry with an equal key
+    pub found: bool,
+    /// Depth of the highest page changed by the last operation
+    changed_depth: usize,
+    /// Pages lost cells, so they may need merging with a sibling
+    shrunk: bool,
+}
+
+impl Cursor {
+    fn new(path: Vec<(Node, usize)>, found: bool) -> Self {
+        Cursor {
+            changed_depth: path.len() - 1,
+            path,
+            found,
+            shrunk: false,
+        }
+    }
+
+    /// Positions on the row with `row_id`, or where it would be inserted
+    pub fn seek_row_id(db: &mut SqliteFile, root_page: u32, row_id: i64) -> Result<Self> {
+        let mut path = vec![];
+        let mut page_number = root_page;
+        while path.len() < MAX_DEPTH {
+            let node = Node::read(db, page_number)?;
+            let i = (0..node.cells.len())
+                .find(|i| node.row_id(*i) >= row_id)
+                .unwrap_or(node.cells.len());
+            match node.kind {
+                PageType::InteriorTable => {
+                    page_number = node.child(i).unwrap_or_default();
+                    path.push((node, i));
+                }
+                PageType::LeafTable => {
+                    let found = i < node.cells.len() && node.row_id(i) == row_id;
+                    path.push((node, i));
+                    return Ok(Cursor::new(path, found));
+                }
+                _ => bail!("page {} is not a table b-tree page", page_number),
+            }
+        }
+        bail!("b-tree at page {} is too deep", root_page)
+    }
+
+    /// Positions on the first entry of an index b-tree that isn't less than the
+    /// target, `compare` orders the target against an entry's values
+    pub fn seek_key(
+        db: &mut SqliteFile,
+        root_page: u32,
+        compare: &dyn Fn(&[RecordSerial]) -> Ordering,
+    ) -> Result<Self> {
+        let mut path = vec![];
+        let mut page_number = root_page;
+        while path.len() < MAX_DEPTH {
+            let node = Node::read(db, page_number)?;
+            if !matches!(node.kind, PageType::InteriorIndex | PageType::LeafIndex) {
+                bail!("page {} is not an index b-tree page", page_number);
+            }
+            let mut position = (node.cells.len(), Ordering::Greater);
+            for i in 0..node.cells.len() {
+                let ordering = compare(&node.key(db, i)?.content);
+                if ordering.is_le() {
+                    position = (i, ordering);
+                    break;
+                }
+            }
+            let (i, ordering) = position;
+            // Interior cells are entries too
+            if node.is_leaf() || ordering.is_eq() {
+                path.push((node, i));
+                return Ok(Cursor::new(path, ordering.is_eq()));
+            }
+            page_number = node.child(i).unwrap_or_default();
+            path.push((node, i));
+        }
+        bail!("b-tree at page {} is too deep", root_page)
+    }
+
+    /// Puts a cell at the cursor, replacing the row it landed on in a table,
+    /// then splits every page that overflows on the way up
+    pub fn insert(mut self, db: &mut SqliteFile, cell: Vec<u8>) -> Result<()> {
+        let (leaf, i) = self
+            .path
+            .last_mut()
+            .ok_or_else(|| Error::msg("empty cursor"))?;
+        if self.found && leaf.kind != PageType::LeafTable {
+            bail!("entry already exists in index");
+        }
+        if self.found {
+            let old = std::mem::replace(&mut leaf.cells[*i], cell);
+            free_overflow(db, PageType::LeafTable, &old)?;
+        } else {
+            leaf.cells.insert(*i, cell);
+        }
+        self.balance(db)
+    }
+
+    /// Removes the entry the cursor landed on and frees its overflow pages,
+    /// then merges pages left too empty on the way up
+    pub fn delete(mut self, db: &mut SqliteFile) -> Result<()> {
+        if !self.found {
+            bail!("no entry to delete at the cursor");
+        }
+        let depth = self.path.len() - 1;
+        let (node, i) = &mut self.path[depth];
+        let (kind, i) = (node.kind, *i);
+        let removed = node.cells.remove(i);
+        free_overflow(db, kind, &removed)?;
+
+        if kind == PageType::InteriorIndex {
+            // The largest entry of the left subtree takes the place of an interior entry
+            let mut page_number = u32_at(&removed, 0);
+            loop {
+                if self.path.len() >= MAX_DEPTH {
+                    bail!("b-tree is too deep at page {}", page_number);
+                }
+                let mut child = Node::read(db, page_number)?;
+                let last = child.cells.len();
+                if !child.is_leaf() {
+                    page_number = child.child(last).unwrap_or_default();
+                    self.path.push((child, last));
+                    continue;
+                }
+                let predecessor = child
+                    .cells
+                    .pop()
+                    .ok_or_else(|| anyhow!("empty leaf page {}", page_number))?;
+                let mut cell = removed[..4].to_vec();
+                cell.extend_from_slice(&predecessor);
+                self.path[depth].0.cells.insert(i, cell);
+                self.path.push((child, last - 1));
+                break;
+            }
+        }
+        self.changed_depth = depth;
+        self.shrunk = true;
+        self.balance(db)
+    }
+
+    /// Writes the changed pages from the bottom up, splitting the ones that
+    /// overflow and merging the ones that shrank too much
+    fn balance(mut self, db: &mut SqliteFile) -> Result<()> {
+        let usable_size = db.header.usable_size();
+        let mut changed = true;
+        let mut shrunk = self.shrunk;
+        while let Some((mut node, index)) = self.path.pop() {
+            if !changed && self.path.len() < self.changed_depth {
+                return Ok(());
+            }
+
+            let mut moved_down = false;
+            if self.path.is_empty() {
+                if node.fits(usable_size) {
+                    return match node.right_most {
+                        Some(child) if node.cells.is_empty() => collapse_root(db, node, child),
+                        _ => node.write(db),
+                    };
+                }
+                // The root stays where it is, its content moves one level down
+                let child = db.allocate_page()?;
+                let root = Node {
+                    page_number: node.page_number,
+                    kind: match node.kind {
+                        PageType::LeafTable | PageType::InteriorTable => PageType::InteriorTable,
+                        _ => PageType::InteriorIndex,
+                    },
+                    cells: vec![],
+                    right_most: Some(child),
+                };
+                self.path.push((root, 0));
+                node.page_number = child;
+                moved_down = true;
+            }
+
+            let (parent, parent_index) = self
+                .path
+                .last_mut()
+                .ok_or_else(|| Error::msg("empty cursor"))?;
+            if !node.fits(usable_size) || moved_down {
+                let append = node.kind == PageType::LeafTable && index == node.cells.len() - 1;
+                let dividers = split(db, &mut node, append)?;
+                let count = dividers.len();
+                parent.cells.splice(*parent_index..*parent_index, dividers);
+                *parent_index += count;
+                node.write(db)?;
+                (changed, shrunk) = (true, false);
+            } else if shrunk && node.is_underfull(usable_size) {
+                merge(db, parent, *parent_index, node)?;
+                changed = true;
+            } else {
+                node.write(db)?;
+                (changed, shrunk) = (false, false);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An interior root left without cells takes in its only child when it fits
+fn collapse_root(db: &mut SqliteFile, mut root: Node, child: u32) -> Result<()> {
+    let child = Node::read(db, child)?;
+    let capacity = Node::capacity(child.kind, root.page_number, db.header.usable_size());
+    if child.used() > capacity {
+        return root.write(db);
+    }
+    db.free_page(child.page_number)?;
+    root.kind = child.kind;
+    root.cells = child.cells;
+    root.right_most = child.right_most;
+    root.write(db)
+}
+
+/// Moves cells between an underfull page and a sibling, or merges the two
+/// into the right one when everything fits
+fn merge(db: &mut SqliteFile, parent: &mut Node, index: usize, node: Node) -> Result<()> {
+    if parent.cells.is_empty() {
+        return node.write(db);
+    }
+    let (divider_index, left, right) = if index > 0 {
+        let left = Node::read(db, parent.child(index - 1).unwrap_or_default())?;
+        (index - 1, left, node)
+    } else {
+        let right = Node::read(db, parent.child(1).unwrap_or_default())?;
+        (0, node, right)
+    };
+    if left.kind != right.kind {
+        bail!(
+            "pages {} and {} are siblings of different kinds",
+            left.page_number,
+            right.page_number
+        );
+    }
+
+    // The divider comes down between the two pages, table leaves don't need it
+    let divider = parent.cells[divider_index].clone();
+    let mut cells = left.cells.clone();
+    match left.kind {
+        PageType::LeafTable => {}
+        PageType::LeafIndex => {
+            let mut cell = divider[4..].to_vec();
+            cell.resize(cell.len().max(4), 0);
+            cells.push(cell);
+        }
+        _ => {
+            let mut cell = divider;
+            cell[..4].copy_from_slice(&left.right_most.unwrap_or_default().to_be_bytes());
+            cells.push(cell);
+        }
+    }
+    cells.extend(right.cells.iter().cloned());
+    let merged = Node {
+        page_number: right.page_number,
+        kind: right.kind,
+        cells,
+        right_most: right.right_most,
+    };
+
+    let usable_size = db.header.usable_size();
+    if merged.fits(usable_size) {
+        parent.cells.remove(divider_index);
+        db.free_page(left.page_number)?;
+        return merged.write(db);
+    }
+    let capacity = Node::capacity(merged.kind, 0, usable_size);
+    let promote = merged.kind != PageType::LeafTable;
+    match chunk(&merged.cells, capacity, 2, promote).as_deref() {
+        Some([first, second]) => {
+            parent.cells[divider_index] =
+                write_left(db, merged.kind, left.page_number, first.clone())?;
+            Node {
+                cells: second.clone(),
+                ..merged
+            }
+            .write(db)
+        }
+        // Too uneven to share, both stay as they were
+        _ => {
+            left.write(db)?;
+            right.write(db)
+        }
+    }
+}
+
+/// Frees the overflow chain of a cell that is going away
+fn free_overflow(db: &mut SqliteFile, kind: PageType, cell: &[u8]) -> Result<()> {
+    let Some((mut page_number, pages)) = overflow(kind, cell, db.header.usable_size()) else {
+        return Ok(());
+    };
+    for _ in 0..pages {
+        let buf = db.read_page_ref(page_number as u64)?;
+        let next = u32_at(&buf, 0);
+        db.free_page(page_number)?;
+        page_number = next;
+    }
+    Ok(())
+}
+
+/// The first overflow page of a cell and how many there are, if it spills
+pub fn overflow(kind: PageType, cell: &[u8], usable_size: usize) -> Option<(u32, usize)> {
+    let mut offset = match kind {
+        PageType::InteriorTable => return None,
+        PageType::InteriorIndex => 4,
+        _ => 0,
+    };
+    let payload_size = Varint::from_bytes(&cell[offset..]);
+    offset += payload_size.size as usize;
+    if kind == PageType::LeafTable {
+        offset += Varint::from_bytes(&cell[offset..]).size as usize;
+    }
+    let payload_size = payload_size.value as usize;
+    let local = Cell::local_payload_size(payload_size, usable_size, &kind);
+    if local == payload_size {
+        return None;
+    }
+    let at = offset + local;
+    let page_number = u32_at(cell, at);
+    Some((
+        page_number,
+        (payload_size - local).div_ceil(usable_size - 4),
+    ))
+}
+
+/// Moves cells from the front of an overflowing page to new pages on its
+/// left, returning the cells that point to them in the parent
+fn split(db: &mut SqliteFile, node: &mut Node, append: bool) -> Result<Vec<Vec<u8>>> {
+    let promote = node.kind != PageType::LeafTable;
+    let capacity = Node::capacity(node.kind, 0, db.header.usable_size());
+    let cells = std::mem::take(&mut node.cells);
+
+    let chunks = if append
+        && cells[..cells.len() - 1]
+            .iter()
+            .map(|it| it.len() + 2)
+            .sum::<usize>()
+            <= capacity
+    {
+        // Rows added at the end leave full pages behind, like SQLite's balance_quick
+        let (last, rest) = cells.split_last().unwrap_or_else(|| unreachable!());
+        vec![rest.to_vec(), vec![last.clone()]]
+    } else {
+        let total: usize = cells.iter().map(|it| it.len() + 2).sum();
+        (total.div_ceil(capacity).max(2)..=cells.len())
+            .find_map(|pages| chunk(&cells, capacity, pages, promote))
+            .ok_or_else(|| Error::msg("cells are too large to split"))?
+    };
+
+    let (last, rest) = chunks.split_last().unwrap_or_else(|| unreachable!());
+    let dividers = rest
+        .iter()
+        .map(|cells| {
+            let page_number = db.allocate_page()?;
+            write_left(db, node.kind, page_number, cells.clone())
+        })
+        .collect::<Result<_>>()?;
+    node.cells = last.clone();
+    Ok(dividers)
+}
+
+/// Writes the left one of two sibling pages and returns the cell pointing to
+/// it in the parent. Interior and index pages give up their last cell for it.
+fn write_left(
+    db: &mut SqliteFile,
+    kind: PageType,
+    page_number: u32,
+    mut cells: Vec<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let mut divider = page_number.to_be_bytes().to_vec();
+    let right_most = match kind {
+        PageType::LeafTable => {
+            let key = Node {
+                page_number,
+                kind,
+                cells: cells.clone(),
+                right_most: None,
+            }
+            .row_id(cells.len() - 1);
+            Varint::write(key, &mut divider);
+            None
+        }
+        PageType::LeafIndex => {
+            divider.extend_from_slice(&cells.pop().unwrap_or_default());
+            None
+        }
+        _ => {
+            let promoted = cells.pop().unwrap_or_default();
+            divider.extend_from_slice(&promoted[4..]);
+            Some(u32_at(&promoted, 0))
+        }
+    };
+    Node {
+        page_number,
+        kind,
+        cells,
+        right_most,
+    }
+    .write(db)?;
+    Ok(divider)
+}
+
+/// Splits cells into `pages` chunks of about the same size, each chunk but the
+/// last ends with the cell to promote when `promote` is set. None when a page
+/// would overflow or be empty.
+fn chunk(
+    cells: &[Vec<u8>],
+    capacity: usize,
+    pages: usize,
+    promote: bool,
+) -> Option<Vec<Vec<Vec<u8>>>> {
+    let target = cells.iter().map(|it| it.len() + 2).sum::<usize>() / pages;
+    let mut chunks = vec![vec![]];
+    let mut used = 0;
+    for cell in cells {
+        let size = cell.len() + 2;
+        if used > 0 && used + size > target && chunks.len() < pages {
+            if promote {
+                chunks.last_mut()?.push(cell.clone());
+                chunks.push(vec![]);
+                used = 0;
+                continue;
+            }
+            chunks.push(vec![]);
+            used = 0;
+        }
+        if used + size > capacity {
+            return None;
+        }
+        chunks.last_mut()?.push(cell.clone());
+        used += size;
+    }
+    let valid = chunks.iter().enumerate().all(|(i, it)| {
+        let promoted = usize::from(promote && i < chunks.len() - 1);
+        it.len() > promoted
+    });
+    valid.then_some(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::{CreateOptions, MemoryVfs},
+        testing::{create, execute, integrity_check, reopen},
+    };
+
+    const ROWS: i64 = 1000;
+
+    /// `t` with an index on `a`, in the smallest pages so the trees get deep
+    /// quickly. Rows go in out of order, so pages split in the middle too
+    fn database(vfs: &MemoryVfs) -> SqliteFile {
+        let options = CreateOptions {
+            page_size: 512,
+            ..CreateOptions::default()
+        };
+        let mut db = create(vfs, &options);
+        execute(&mut db, "CREATE TABLE t(id INTEGER PRIMARY KEY, a TEXT)").unwrap();
+        execute(&mut db, "CREATE INDEX t_a ON t(a)").unwrap();
+        execute(&mut db, "BEGIN").unwrap();
+        for i in 0..ROWS {
+            let id = i * 37 % ROWS;
+            let sql = format!("INSERT INTO t VALUES ({id}, '{}')", value(id));
+            execute(&mut db, &sql).unwrap();
+        }
+        execute(&mut db, "COMMIT").unwrap();
+        db
+    }
+
+    /// Every fifth value overflows, in the table and in the index
+    fn value(id: i64) -> String {
+        let len = if id % 5 == 0 { 1500 } else { 20 };
+        format!("{id:04}{}", "x".repeat(len))
+    }
+
+    /// Levels of a b-tree, down its left edge
+    fn depth(db: &mut SqliteFile, name: &str) -> usize {
+        let table = db.tables.iter().find(|it| it.name == name).unwrap();
+        let mut node = Node::read(db, table.root_page as u32).unwrap();
+        let mut depth = 1;
+        while !node.is_leaf() {
+            node = Node::read(db, node.child(0).unwrap()).unwrap();
+            depth += 1;
+        }
+        depth
+    }
+
+    /// Checks every row left reads back whole and in order
+    fn check_rows(db: &mut SqliteFile, ids: impl Iterator<Item = i64>) {
+        let rows = execute(db, "SELECT id, a FROM t").unwrap();
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|row| (row[0].to_string(), row[1].to_string()))
+            .collect();
+        let expected: Vec<_> = ids.map(|id| (id.to_string(), value(id))).collect();
+        assert_eq!(rows, expected);
+        assert_eq!(integrity_check(db), "ok");
+    }
+
+    #[test]
+    fn inserts_split_interior_pages() {
+        let vfs = MemoryVfs::default();
+        database(&vfs);
+        let mut db = reopen(&vfs);
+        assert!(depth(&mut db, "t") >= 3);
+        assert!(depth(&mut db, "t_a") >= 3);
+        assert_eq!(db.header.free_list_count, 0);
+        check_rows(&mut db, 0..ROWS);
+    }
+
+    #[test]
+    fn deletes_merge_pages_and_free_overflow() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        let in_use = |db: &SqliteFile| db.page_count().unwrap() - db.header.free_list_count as u64;
+
+        // What's left are 50 short rows, in a handful of pages
+        execute(&mut db, "DELETE FROM t WHERE id % 10 <> 1").unwrap();
+        let deleted = |id: i64| (id / 10) % 2 == 0;
+        for i in 0..ROWS / 10 {
+            let id = i * 37 % (ROWS / 10) * 10 + 1;
+            if deleted(id) {
+                execute(&mut db, &format!("DELETE FROM t WHERE id = {id}")).unwrap();
+            }
+        }
+        let mut db = reopen(&vfs);
+        check_rows(&mut db, (1..ROWS).step_by(10).filter(|id| !deleted(*id)));
+        assert!(in_use(&db) < 20);
+        assert_eq!(depth(&mut db, "t"), 2);
+
+        // Page 1 and the two roots are all that's left
+        execute(&mut db, "DELETE FROM t").unwrap();
+        let mut db = reopen(&vfs);
+        assert_eq!(in_use(&db), 3);
+        assert_eq!((depth(&mut db, "t"), depth(&mut db, "t_a")), (1, 1));
+        check_rows(&mut db, 0..0);
+    }
+
+    #[test]
+    fn updates_grow_and_shrink_overflow_chains() {
+        let vfs = MemoryVfs::default();
+        let mut db = database(&vfs);
+        let before = db.page_count().unwrap();
+        let long = "y".repeat(2000);
+        let sql = format!("UPDATE t SET a = a || '{long}' WHERE id % 3 = 0");
+        execute(&mut db, &sql).unwrap();
+        let rows = execute(&mut db, "SELECT id, a FROM t WHERE id % 3 = 0").unwrap();
+        assert_eq!(rows.len() as i64, (ROWS + 2) / 3);
+        for row in rows {
+            let id: i64 = row[0].to_string().parse().unwrap();
+            assert_eq!(row[1].to_string(), value(id) + &long);
+        }
+
+        // Overflow pages of values that got short are free, and used again
+        execute(&mut db, "UPDATE t SET a = 'short' WHERE id % 3 = 0").unwrap();
+        let mut db = reopen(&vfs);
+        assert!(db.header.free_list_count as u64 > before / 2);
+        let rows = execute(&mut db, "SELECT id, a FROM t").unwrap();
+        assert_eq!(rows.len() as i64, ROWS);
+        for (id, row) in (0..ROWS).zip(&rows) {
+            let expected = if id % 3 == 0 {
+                "short".to_string()
+            } else {
+                value(id)
+            };
+            assert_eq!(
+                (row[0].to_string(), row[1].to_string()),
+                (id.to_string(), expected)
+            );
+        }
+        let page_count = db.page_count().unwrap();
+        execute(&mut db, &sql).unwrap();
+        assert_eq!(db.page_count().unwrap(), page_count);
+        assert_eq!(integrity_check(&mut db), "ok");
+    }
+}
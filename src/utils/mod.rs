@@ -1,5 +1,10 @@
 mod varint;
 mod btree;
+mod cursor;
+mod autovacuum;
+mod random;
 pub use varint::*; 
 pub use btree::*; 
-
+pub use cursor::*;
+pub use autovacuum::*;
+pub use random::*;
@@ -0,0 +1,9 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+/// Good enough for journal nonces and WAL salts, every call is seeded anew
+pub fn random_u32() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
@@ -1,13 +1,9 @@
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Varint {
     pub value: i64,
     pub size: u8,
 }
 
-
-// from https://sqlite.org/src4/doc/trunk/www/varint.wiki
-// Wrong one mf
-
 impl Varint {
     pub fn from_bytes(buf: &[u8]) -> Self {
         let mut value: i64 = 0;
@@ -29,7 +25,101 @@ impl Varint {
             size: bytes_read as u8,
         }
     }
+    pub fn from_value(value: i64) -> Self {
+        let bits = 64 - (value as u64).leading_zeros();
+        let size = match bits {
+            0..=56 => bits.div_ceil(7).max(1),
+            _ => 9,
+        };
+        Varint {
+            value,
+            size: size as u8,
+        }
+    }
+
+    /// Only the first `size` bytes are used
     pub fn to_bytes(&self) -> [u8; 9] {
-        unimplemented!()
+        let mut buf = [0; 9];
+        let mut value = self.value as u64;
+        let size = self.size as usize;
+        if size == 9 {
+            // the last byte keeps all 8 bits
+            buf[8] = value as u8;
+            value >>= 8;
+            for byte in buf[..8].iter_mut().rev() {
+                *byte = (value & 0x7f) as u8 | 0x80;
+                value >>= 7;
+            }
+            return buf;
+        }
+        for (i, byte) in buf[..size].iter_mut().enumerate().rev() {
+            *byte = (value & 0x7f) as u8 | if i == size - 1 { 0 } else { 0x80 };
+            value >>= 7;
+        }
+        buf
+    }
+
+    /// Appends the encoded value
+    pub fn write(value: i64, out: &mut Vec<u8>) {
+        let varint = Varint::from_value(value);
+        out.extend_from_slice(&varint.to_bytes()[..varint.size as usize]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: i64, size: u8) {
+        let varint = Varint::from_value(value);
+        assert_eq!(varint.size, size, "size of {value}");
+        let mut buf = vec![];
+        Varint::write(value, &mut buf);
+        assert_eq!(buf.len(), size as usize);
+        let read = Varint::from_bytes(&buf);
+        assert_eq!((read.value, read.size), (value, size), "{value} read back");
+    }
+
+    #[test]
+    fn one_byte() {
+        round_trip(0, 1);
+        round_trip(1, 1);
+        round_trip(0x7f, 1);
+        assert_eq!(Varint::from_bytes(&[0x7f]).value, 0x7f);
+    }
+
+    #[test]
+    fn two_bytes() {
+        round_trip(0x80, 2);
+        round_trip(0x3fff, 2);
+        round_trip(0x4000, 3);
+        let mut buf = vec![];
+        Varint::write(0x80, &mut buf);
+        assert_eq!(buf, [0x81, 0x00]);
+    }
+
+    #[test]
+    fn eight_and_nine_bytes() {
+        round_trip((1 << 56) - 1, 8);
+        round_trip(1 << 56, 9);
+        round_trip(i64::MAX, 9);
+        let mut buf = vec![];
+        Varint::write((1 << 56) - 1, &mut buf);
+        assert_eq!(buf, [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn negative_numbers_take_nine_bytes() {
+        round_trip(-1, 9);
+        round_trip(i64::MIN, 9);
+        let mut buf = vec![];
+        Varint::write(-1, &mut buf);
+        assert_eq!(buf, [0xff; 9]);
+    }
+
+    #[test]
+    fn reading_stops_after_nine_bytes() {
+        let varint = Varint::from_bytes(&[0xff; 12]);
+        assert_eq!((varint.value, varint.size), (-1, 9));
     }
 }